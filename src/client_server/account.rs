@@ -1,5 +1,9 @@
 use super::{State, DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
-use crate::{pdu::PduBuilder, utils, ConduitResult, Database, Error, Ruma};
+use crate::{
+    database::users::ThreepidSession, pdu::PduBuilder, utils, AuthenticatedUser, ConduitResult,
+    Database, Error, Result, Ruma,
+};
+use rocket::response::content::Json;
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -8,18 +12,23 @@ use ruma::{
                 change_password, deactivate, get_username_availability, register, whoami,
                 ThirdPartyIdRemovalStatus,
             },
-            uiaa::{AuthFlow, UiaaInfo},
+            uiaa::{AuthData, AuthFlow, UiaaInfo},
         },
     },
     events::{room::member, EventType},
     UserId,
 };
+use serde::Deserialize;
+use serde_json::json;
 
 use register::RegistrationKind;
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
 const GUEST_NAME_LENGTH: usize = 10;
+const THREEPID_SID_LENGTH: usize = 32;
+const THREEPID_TOKEN_LENGTH: usize = 32;
+const THREEPID_SESSION_LIFETIME: u64 = 24 * 60 * 60 * 1000; // One day in milliseconds
 
 /// # `GET /_matrix/client/r0/register/available`
 ///
@@ -377,3 +386,389 @@ pub fn deactivate_route(
     }
     .into())
 }
+
+#[derive(Deserialize)]
+struct RequestEmailToken {
+    client_secret: String,
+    email: String,
+    send_attempt: u64,
+}
+
+#[derive(Deserialize)]
+struct SubmitToken {
+    sid: String,
+    client_secret: String,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct AddThreepid {
+    sid: String,
+    client_secret: String,
+    auth: Option<AuthData>,
+}
+
+#[derive(Deserialize)]
+struct DeleteThreepid {
+    medium: String,
+    address: String,
+}
+
+/// Client secrets are chosen by the client and have to match `[0-9a-zA-Z.=_-]{1,255}`.
+fn check_client_secret(client_secret: &str) -> Result<()> {
+    if client_secret.is_empty()
+        || client_secret.len() > 255
+        || !client_secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '=' | '_' | '-'))
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid client_secret.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the validation session if it exists and did not expire.
+fn active_threepid_session(
+    db: &Database<'_>,
+    client_secret: &str,
+    sid: &str,
+) -> Result<Option<ThreepidSession>> {
+    Ok(db
+        .users
+        .threepid_session(client_secret, sid)?
+        .filter(|session| {
+            session.created_ts + THREEPID_SESSION_LIFETIME > utils::millis_since_unix_epoch()
+        }))
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/email/requestToken`
+///
+/// Mails a token to an email address, which proves that the user owns it once it is submitted.
+///
+/// - Retries with the same client_secret, email and send_attempt don't send another email
+/// - Only works if email is configured on this server
+///
+/// The ruma branch we depend on has no types for the 3PID endpoints yet, so the bodies are parsed
+/// as json, like the notifications route does.
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/email/requestToken", data = "<body>")
+)]
+pub async fn request_3pid_email_token_route(
+    db: State<'_, Database<'_>>,
+    user: AuthenticatedUser,
+    body: String,
+) -> Result<Json<String>> {
+    let request = serde_json::from_str::<RequestEmailToken>(&body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+    check_client_secret(&request.client_secret)?;
+
+    let config = db.globals.email().cloned().ok_or(Error::BadRequest(
+        ErrorKind::ThreepidDenied,
+        "Email is not enabled on this server.",
+    ))?;
+
+    let address = request.email.to_lowercase();
+    lettre::EmailAddress::new(address.clone())
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address."))?;
+
+    if db
+        .users
+        .threepid_user("email", &address)?
+        .map_or(false, |user_id| user_id != user.sender_id)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "Email is already in use.",
+        ));
+    }
+
+    let existing = db
+        .users
+        .find_threepid_session(&request.client_secret, "email", &address)?
+        .filter(|(_, session)| {
+            session.created_ts + THREEPID_SESSION_LIFETIME > utils::millis_since_unix_epoch()
+        });
+
+    let submit_url = format!(
+        "https://{}/_matrix/client/r0/account/3pid/email/submit_token",
+        db.globals.server_name()
+    );
+
+    let (sid, session) = match existing {
+        Some((sid, session)) if request.send_attempt <= session.send_attempt => {
+            return Ok(Json(
+                json!({ "sid": sid, "submit_url": submit_url }).to_string(),
+            ));
+        }
+        Some((sid, session)) => (
+            sid,
+            ThreepidSession {
+                send_attempt: request.send_attempt,
+                ..session
+            },
+        ),
+        None => (
+            utils::random_string(THREEPID_SID_LENGTH),
+            ThreepidSession {
+                medium: "email".to_owned(),
+                address,
+                token: utils::random_string(THREEPID_TOKEN_LENGTH),
+                send_attempt: request.send_attempt,
+                created_ts: utils::millis_since_unix_epoch(),
+                validated_ts: None,
+            },
+        ),
+    };
+
+    let subject = format!("[{}] Validate your email address", db.globals.server_name());
+    let body = format!(
+        "Hi {},
+
+Open this link to add your email address to your account:
+{}?sid={}&client_secret={}&token={}
+
+Or enter this code in your client: {}
+
+If you didn't ask for this, you can ignore this email.
+",
+        user.sender_id, submit_url, sid, request.client_secret, session.token, session.token
+    );
+    let to = session.address.clone();
+    rocket::tokio::task::spawn_blocking(move || config.send(&to, subject, body))
+        .await
+        .expect("sending an email does not panic")?;
+
+    db.users
+        .set_threepid_session(&request.client_secret, &sid, &session)?;
+
+    Ok(Json(
+        json!({ "sid": sid, "submit_url": submit_url }).to_string(),
+    ))
+}
+
+/// Marks a validation session as validated if the token matches.
+fn submit_3pid_token(
+    db: &Database<'_>,
+    client_secret: &str,
+    sid: &str,
+    token: &str,
+) -> Result<bool> {
+    let mut session = match active_threepid_session(db, client_secret, sid)? {
+        Some(session) if session.token == token => session,
+        _ => return Ok(false),
+    };
+
+    if session.validated_ts.is_none() {
+        session.validated_ts = Some(utils::millis_since_unix_epoch());
+        db.users
+            .set_threepid_session(client_secret, sid, &session)?;
+    }
+
+    Ok(true)
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/email/submit_token`
+///
+/// Submits the token of an email validation session. This is the `submit_url` of the sessions
+/// this server creates.
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/email/submit_token", data = "<body>")
+)]
+pub fn submit_3pid_email_token_route(
+    db: State<'_, Database<'_>>,
+    body: String,
+) -> Result<Json<String>> {
+    let request = serde_json::from_str::<SubmitToken>(&body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
+    let success = submit_3pid_token(&db, &request.client_secret, &request.sid, &request.token)?;
+
+    Ok(Json(json!({ "success": success }).to_string()))
+}
+
+/// # `GET /_matrix/client/r0/account/3pid/email/submit_token`
+///
+/// Like the `POST` version, but for the link in the validation email.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/account/3pid/email/submit_token?<sid>&<client_secret>&<token>")
+)]
+pub fn submit_3pid_email_token_link_route(
+    db: State<'_, Database<'_>>,
+    sid: String,
+    client_secret: String,
+    token: String,
+) -> Result<&'static str> {
+    if submit_3pid_token(&db, &client_secret, &sid, &token)? {
+        Ok("Your email address was validated. You can return to your client now.")
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The link is invalid or expired.",
+        ))
+    }
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/add`
+///
+/// Adds a validated third party id to the account.
+///
+/// - Needs user interactive authentication
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/add", data = "<body>")
+)]
+pub fn add_3pid_route(
+    db: State<'_, Database<'_>>,
+    user: AuthenticatedUser,
+    body: String,
+) -> Result<Json<String>> {
+    let sender_id = &user.sender_id;
+    let device_id = &user.device_id;
+
+    let request = serde_json::from_str::<AddThreepid>(&body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec!["m.login.password".to_owned()],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &request.auth {
+        let (worked, uiaainfo) = db.uiaa.try_auth(
+            &sender_id,
+            &device_id,
+            auth,
+            &uiaainfo,
+            &db.users,
+            &db.globals,
+        )?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        db.uiaa.create(&sender_id, &device_id, &uiaainfo)?;
+        return Err(Error::Uiaa(uiaainfo));
+    }
+
+    let session = active_threepid_session(&db, &request.client_secret, &request.sid)?
+        .filter(|session| session.validated_ts.is_some())
+        .ok_or(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The third party id was not validated.",
+        ))?;
+
+    if db
+        .users
+        .threepid_user(&session.medium, &session.address)?
+        .map_or(false, |user_id| &user_id != sender_id)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "The third party id is already in use.",
+        ));
+    }
+
+    db.users
+        .add_threepid(sender_id, &session.medium, &session.address)?;
+    db.users
+        .remove_threepid_session(&request.client_secret, &request.sid)?;
+
+    Ok(Json("{}".to_owned()))
+}
+
+/// # `GET /_matrix/client/r0/account/3pid`
+///
+/// Lists the third party ids of the account.
+#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/account/3pid"))]
+pub fn get_3pids_route(
+    db: State<'_, Database<'_>>,
+    user: AuthenticatedUser,
+) -> Result<Json<String>> {
+    let threepids = db
+        .users
+        .threepids(&user.sender_id)
+        .map(|r| {
+            let (medium, address, added_at) = r?;
+            // Third party ids are bound right after they were validated
+            Ok(json!({
+                "medium": medium,
+                "address": address,
+                "validated_at": added_at,
+                "added_at": added_at,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Json(json!({ "threepids": threepids }).to_string()))
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/delete`
+///
+/// Removes a third party id from the account.
+///
+/// - Also removes the email pushers of the address
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/account/3pid/delete", data = "<body>")
+)]
+pub fn delete_3pid_route(
+    db: State<'_, Database<'_>>,
+    user: AuthenticatedUser,
+    body: String,
+) -> Result<Json<String>> {
+    let sender_id = &user.sender_id;
+
+    let request = serde_json::from_str::<DeleteThreepid>(&body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+    let address = request.address.to_lowercase();
+
+    if !db
+        .users
+        .remove_threepid(sender_id, &request.medium, &address)?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidNotFound,
+            "The third party id is not bound to this account.",
+        ));
+    }
+
+    if request.medium == "email" {
+        let email_pushers = db
+            .users
+            .pushers(sender_id)
+            .filter_map(|r| r.ok())
+            .filter(|pusher| pusher.get("kind").and_then(|k| k.as_str()) == Some("email"))
+            .filter_map(|pusher| {
+                let app_id = pusher.get("app_id")?.as_str()?.to_owned();
+                let pushkey = pusher.get("pushkey")?.as_str()?.to_owned();
+                Some((app_id, pushkey))
+            })
+            .filter(|(_, pushkey)| pushkey.to_lowercase() == address)
+            .collect::<Vec<_>>();
+
+        for (app_id, pushkey) in email_pushers {
+            db.users.remove_pusher(sender_id, &app_id, &pushkey)?;
+        }
+    }
+
+    Ok(Json(
+        json!({
+            "id_server_unbind_result": "no-support",
+        })
+        .to_string(),
+    ))
+}
@@ -1,5 +1,5 @@
-use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
+use super::{EventFilter, State};
+use crate::{ConduitResult, Database, Error, Result, Ruma};
 use ruma::api::client::{error::ErrorKind, r0::context::get_context};
 use std::convert::TryFrom;
 
@@ -15,65 +15,86 @@ pub fn get_context_route(
     body: Ruma<get_context::Request>,
 ) -> ConduitResult<get_context::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
-        ));
-    }
-
-    let base_event = db
-        .rooms
-        .get_pdu(&body.event_id)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "Base event not found.",
-        ))?
-        .to_room_event();
+    // The history visibility decides, so world readable rooms work without joining
+    let base_pdu = match db.rooms.get_pdu(&body.event_id)? {
+        Some(pdu)
+            if pdu.room_id == body.room_id && db.rooms.user_can_see_event(sender_id, &pdu)? =>
+        {
+            pdu
+        }
+        _ => {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Base event not found.",
+            ))
+        }
+    };
+    let base_event = base_pdu.to_room_event(
+        db.transaction_ids
+            .event_txnid(&base_pdu, sender_id, device_id)?
+            .as_deref(),
+    );
 
     let base_token = db
         .rooms
         .get_pdu_count(&body.event_id)?
         .expect("event still exists");
 
+    let filter = EventFilter::from_json(serde_json::to_value(&body.filter).ok().as_ref());
+
     let events_before = db
         .rooms
         .pdus_until(&sender_id, &body.room_id, base_token)
+        .filter_map(|r| r.ok()) // Remove buggy events
+        .filter(|(_, pdu)| filter.matches_pdu(pdu))
         .take(
             u32::try_from(body.limit).map_err(|_| {
                 Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
             })? as usize
                 / 2,
         )
-        .filter_map(|r| r.ok()) // Remove buggy events
         .collect::<Vec<_>>();
 
     let start_token = events_before.last().map(|(count, _)| count.to_string());
 
     let events_before = events_before
         .into_iter()
-        .map(|(_, pdu)| pdu.to_room_event())
-        .collect::<Vec<_>>();
+        .map(|(_, pdu)| {
+            Ok(pdu.to_room_event(
+                db.transaction_ids
+                    .event_txnid(&pdu, sender_id, device_id)?
+                    .as_deref(),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
     let events_after = db
         .rooms
         .pdus_after(&sender_id, &body.room_id, base_token)
+        .filter_map(|r| r.ok()) // Remove buggy events
+        .filter(|(_, pdu)| filter.matches_pdu(pdu))
         .take(
             u32::try_from(body.limit).map_err(|_| {
                 Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
             })? as usize
                 / 2,
         )
-        .filter_map(|r| r.ok()) // Remove buggy events
         .collect::<Vec<_>>();
 
     let end_token = events_after.last().map(|(count, _)| count.to_string());
 
     let events_after = events_after
         .into_iter()
-        .map(|(_, pdu)| pdu.to_room_event())
-        .collect::<Vec<_>>();
+        .map(|(_, pdu)| {
+            Ok(pdu.to_room_event(
+                db.transaction_ids
+                    .event_txnid(&pdu, sender_id, device_id)?
+                    .as_deref(),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
 
     Ok(get_context::Response {
         start: start_token,
@@ -85,6 +106,7 @@ pub fn get_context_route(
             .rooms
             .room_state_full(&body.room_id)?
             .values()
+            .filter(|pdu| filter.matches_pdu(pdu))
             .map(|pdu| pdu.to_state_event())
             .collect(),
     }
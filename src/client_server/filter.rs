@@ -1,29 +1,217 @@
-use crate::{utils, ConduitResult};
-use ruma::api::client::r0::filter::{self, create_filter, get_filter};
+use super::State;
+use crate::{ConduitResult, Database, Error, PduEvent, Ruma};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        r0::filter::{create_filter, get_filter},
+    },
+    RoomId, UserId,
+};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/user/<_>/filter/<_>"))]
-pub fn get_filter_route() -> ConduitResult<get_filter::Response> {
-    // TODO
-    Ok(get_filter::Response {
-        filter: filter::FilterDefinition {
-            event_fields: None,
-            event_format: None,
-            account_data: None,
-            room: None,
-            presence: None,
-        },
-    }
-    .into())
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/user/<_>/filter/<_>", data = "<body>")
+)]
+pub fn get_filter_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_filter::Request>,
+) -> ConduitResult<get_filter::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let filter = db
+        .users
+        .get_filter(sender_id, &body.filter_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Filter not found."))?;
+
+    Ok(get_filter::Response { filter }.into())
 }
 
-#[cfg_attr(feature = "conduit_bin", post("/_matrix/client/r0/user/<_>/filter"))]
-pub fn create_filter_route() -> ConduitResult<create_filter::Response> {
-    // TODO
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/user/<_>/filter", data = "<body>")
+)]
+pub fn create_filter_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_filter::Request>,
+) -> ConduitResult<create_filter::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if &body.user_id != sender_id {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You can't create filters for other users.",
+        ));
+    }
+
     Ok(create_filter::Response {
-        filter_id: utils::random_string(10),
+        filter_id: db.users.create_filter(sender_id, &body.filter)?,
     }
     .into())
 }
+
+/// Filters events by type, sender and room. It is read from the json of any filter kind, so
+/// the same logic works for sync, /messages and /context.
+#[derive(Default)]
+pub struct EventFilter {
+    pub limit: Option<usize>,
+    types: Option<Vec<String>>,
+    not_types: Vec<String>,
+    senders: Option<Vec<String>>,
+    not_senders: Vec<String>,
+    rooms: Option<Vec<String>>,
+    not_rooms: Vec<String>,
+    contains_url: Option<bool>,
+    pub lazy_load_members: bool,
+    pub include_redundant_members: bool,
+}
+
+impl EventFilter {
+    pub fn from_json(json: Option<&serde_json::Value>) -> Self {
+        let json = match json {
+            Some(json) => json,
+            None => return Self::default(),
+        };
+
+        EventFilter {
+            limit: json
+                .get("limit")
+                .and_then(|limit| limit.as_u64())
+                .map(|limit| limit as usize),
+            types: strings(json, "types"),
+            not_types: strings(json, "not_types").unwrap_or_default(),
+            senders: strings(json, "senders"),
+            not_senders: strings(json, "not_senders").unwrap_or_default(),
+            rooms: strings(json, "rooms"),
+            not_rooms: strings(json, "not_rooms").unwrap_or_default(),
+            contains_url: json.get("contains_url").and_then(|c| c.as_bool()),
+            lazy_load_members: json
+                .get("lazy_load_members")
+                .and_then(|l| l.as_bool())
+                .unwrap_or(false),
+            include_redundant_members: json
+                .get("include_redundant_members")
+                .and_then(|i| i.as_bool())
+                .unwrap_or(false),
+        }
+    }
+
+    /// Types can contain `*` as a wildcard.
+    pub fn matches_type(&self, kind: &str) -> bool {
+        !self
+            .not_types
+            .iter()
+            .any(|pattern| glob_matches(pattern, kind))
+            && self.types.as_ref().map_or(true, |types| {
+                types.iter().any(|pattern| glob_matches(pattern, kind))
+            })
+    }
+
+    pub fn matches_sender(&self, sender: &UserId) -> bool {
+        !self.not_senders.iter().any(|s| s == sender.as_str())
+            && self
+                .senders
+                .as_ref()
+                .map_or(true, |senders| senders.iter().any(|s| s == sender.as_str()))
+    }
+
+    pub fn matches_room(&self, room_id: &RoomId) -> bool {
+        !self.not_rooms.iter().any(|r| r == room_id.as_str())
+            && self
+                .rooms
+                .as_ref()
+                .map_or(true, |rooms| rooms.iter().any(|r| r == room_id.as_str()))
+    }
+
+    pub fn matches_pdu(&self, pdu: &PduEvent) -> bool {
+        self.matches_type(&pdu.kind.to_string())
+            && self.matches_sender(&pdu.sender)
+            && self.matches_room(&pdu.room_id)
+            && self.contains_url.map_or(true, |contains_url| {
+                pdu.content.get("url").map_or(false, |url| url.is_string()) == contains_url
+            })
+    }
+}
+
+/// All parts of a filter that apply to /sync.
+#[derive(Default)]
+pub struct SyncFilter {
+    pub include_leave: bool,
+    rooms: Option<Vec<String>>,
+    not_rooms: Vec<String>,
+    pub timeline: EventFilter,
+    pub state: EventFilter,
+    pub ephemeral: EventFilter,
+    pub room_account_data: EventFilter,
+    pub account_data: EventFilter,
+    pub presence: EventFilter,
+}
+
+impl SyncFilter {
+    pub fn from_json(json: &serde_json::Value) -> Self {
+        let room = json.get("room");
+        let room_part = |key| room.and_then(|room| room.get(key));
+
+        SyncFilter {
+            include_leave: room_part("include_leave")
+                .and_then(|i| i.as_bool())
+                .unwrap_or(false),
+            rooms: room.and_then(|room| strings(room, "rooms")),
+            not_rooms: room
+                .and_then(|room| strings(room, "not_rooms"))
+                .unwrap_or_default(),
+            timeline: EventFilter::from_json(room_part("timeline")),
+            state: EventFilter::from_json(room_part("state")),
+            ephemeral: EventFilter::from_json(room_part("ephemeral")),
+            room_account_data: EventFilter::from_json(room_part("account_data")),
+            account_data: EventFilter::from_json(json.get("account_data")),
+            presence: EventFilter::from_json(json.get("presence")),
+        }
+    }
+
+    /// Checks the `rooms` and `not_rooms` fields of the room filter.
+    pub fn matches_room(&self, room_id: &RoomId) -> bool {
+        !self.not_rooms.iter().any(|r| r == room_id.as_str())
+            && self
+                .rooms
+                .as_ref()
+                .map_or(true, |rooms| rooms.iter().any(|r| r == room_id.as_str()))
+    }
+}
+
+fn strings(json: &serde_json::Value, key: &str) -> Option<Vec<String>> {
+    json.get(key)?.as_array().map(|array| {
+        array
+            .iter()
+            .filter_map(|s| s.as_str())
+            .map(str::to_owned)
+            .collect()
+    })
+}
+
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().expect("split always returns one part");
+    if !s.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &s[first.len()..];
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // There was no wildcard
+        None => return rest.is_empty(),
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
@@ -1,9 +1,12 @@
 use super::State;
-use crate::{database::media::FileMeta, utils, ConduitResult, Database, Error, Ruma};
+use crate::{
+    database::media::FileMeta, server_server, utils, ConduitResult, Database, Error, Result, Ruma,
+};
 use ruma::api::client::{
     error::ErrorKind,
     r0::media::{create_content, get_content, get_content_thumbnail, get_media_config},
 };
+use ruma::ServerName;
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
@@ -25,7 +28,7 @@ pub fn get_media_config_route(
     feature = "conduit_bin",
     post("/_matrix/media/r0/upload", data = "<body>")
 )]
-pub fn create_content_route(
+pub async fn create_content_route(
     db: State<'_, Database<'_>>,
     body: Ruma<create_content::Request>,
 ) -> ConduitResult<create_content::Response> {
@@ -34,12 +37,14 @@ pub fn create_content_route(
         db.globals.server_name(),
         utils::random_string(MXC_LENGTH)
     );
-    db.media.create(
-        mxc.clone(),
-        body.filename.as_ref(),
-        &body.content_type,
-        &body.file,
-    )?;
+    db.media
+        .create(
+            mxc.clone(),
+            body.filename.as_ref(),
+            &body.content_type,
+            &body.file,
+        )
+        .await?;
 
     Ok(create_content::Response { content_uri: mxc }.into())
 }
@@ -51,19 +56,19 @@ pub fn create_content_route(
         data = "<body>"
     )
 )]
-pub fn get_content_route(
+pub async fn get_content_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_content::Request>,
     _server_name: String,
     _media_id: String,
 ) -> ConduitResult<get_content::Response> {
+    let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
+
     if let Some(FileMeta {
         filename,
         content_type,
         file,
-    }) = db
-        .media
-        .get(format!("mxc://{}/{}", body.server_name, body.media_id))?
+    }) = db.media.get(mxc.clone()).await?
     {
         Ok(get_content::Response {
             file,
@@ -71,6 +76,14 @@ pub fn get_content_route(
             content_disposition: filename.unwrap_or_default(), // TODO: Spec says this should be optional
         }
         .into())
+    } else if body.server_name.as_str() != db.globals.server_name().as_str()
+        && body.allow_remote.unwrap_or(true)
+    {
+        Ok(
+            fetch_remote_content(&db, body.server_name.clone(), &body.media_id, mxc)
+                .await?
+                .into(),
+        )
     } else {
         Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
     }
@@ -83,25 +96,94 @@ pub fn get_content_route(
         data = "<body>"
     )
 )]
-pub fn get_content_thumbnail_route(
+pub async fn get_content_thumbnail_route(
     db: State<'_, Database<'_>>,
     body: Ruma<get_content_thumbnail::Request>,
     _server_name: String,
     _media_id: String,
 ) -> ConduitResult<get_content_thumbnail::Response> {
+    let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
+    let width = body
+        .width
+        .try_into()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?;
+    let height = body
+        .height
+        .try_into()
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Height is invalid."))?;
+
     if let Some(FileMeta {
         content_type, file, ..
-    }) = db.media.get_thumbnail(
-        format!("mxc://{}/{}", body.server_name, body.media_id),
-        body.width
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
-        body.height
-            .try_into()
-            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Width is invalid."))?,
-    )? {
-        Ok(get_content_thumbnail::Response { file, content_type }.into())
+    }) = db.media.get_thumbnail(mxc.clone(), width, height).await?
+    {
+        return Ok(get_content_thumbnail::Response { file, content_type }.into());
+    }
+
+    if body.server_name.as_str() != db.globals.server_name().as_str()
+        && body.allow_remote.unwrap_or(true)
+    {
+        // Fetch the original instead of the remote thumbnail, so thumbnails of remote media are
+        // generated and cached the same way as those of local media
+        fetch_remote_content(&db, body.server_name.clone(), &body.media_id, mxc.clone()).await?;
+
+        if let Some(FileMeta {
+            content_type, file, ..
+        }) = db.media.get_thumbnail(mxc, width, height).await?
+        {
+            return Ok(get_content_thumbnail::Response { file, content_type }.into());
+        }
+    }
+
+    Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
+}
+
+/// Downloads media from the server that owns it and caches it, so it only has to be fetched once.
+async fn fetch_remote_content(
+    db: &Database<'static>,
+    server_name: Box<ServerName>,
+    media_id: &str,
+    mxc: String,
+) -> Result<get_content::Response> {
+    let get_content_response = server_server::send_limited_request(
+        db,
+        server_name.as_str().to_owned(),
+        get_content::Request {
+            allow_remote: Some(false),
+            server_name,
+            media_id: media_id.to_owned(),
+        },
+        Some(db.globals.max_request_size().into()),
+    )
+    .await?;
+
+    db.media
+        .create(
+            mxc,
+            filename_from_content_disposition(&get_content_response.content_disposition).as_ref(),
+            &get_content_response.content_type,
+            &get_content_response.file,
+        )
+        .await?;
+
+    Ok(get_content_response)
+}
+
+/// Extracts the filename from a Content-Disposition header like `inline; filename=image.png`.
+/// Conduit sends the bare filename, which is used as it is.
+fn filename_from_content_disposition(content_disposition: &str) -> Option<String> {
+    let filename = if content_disposition.contains('=') {
+        content_disposition
+            .split(';')
+            .map(str::trim)
+            .find_map(|part| part.strip_prefix("filename="))?
+            .trim_matches('"')
     } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
+        content_disposition.trim()
+    };
+
+    if filename.is_empty() {
+        None
+    } else {
+        Some(filename.to_owned())
     }
 }
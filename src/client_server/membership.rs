@@ -1,6 +1,7 @@
 use super::State;
 use crate::{
-    client_server, pdu::PduBuilder, server_server, utils, ConduitResult, Database, Error, Ruma,
+    client_server, pdu::PduBuilder, room_version::RoomVersion, server_server, utils, ConduitResult,
+    Database, Error, Result, Ruma,
 };
 use ruma::{
     api::{
@@ -11,15 +12,16 @@ use ruma::{
                 membership::{
                     ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
                     join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
-                    unban_user,
+                    unban_user, Invite3pid,
                 },
             },
         },
         federation,
     },
     events::{room::member, EventType},
-    EventId, Raw, RoomId, RoomVersionId,
+    EventId, Raw, RoomId, UserId,
 };
+use serde_json::json;
 use std::{collections::BTreeMap, convert::TryFrom};
 
 #[cfg(feature = "conduit_bin")]
@@ -43,7 +45,9 @@ pub async fn join_room_by_id_route(
             federation::membership::create_join_event_template::v1::Request {
                 room_id: body.room_id.clone(),
                 user_id: sender_id.clone(),
-                ver: vec![RoomVersionId::Version5, RoomVersionId::Version6],
+                ver: RoomVersion::supported()
+                    .map(|version| version.id.clone())
+                    .collect(),
             },
         )
         .await?;
@@ -105,6 +109,29 @@ pub async fn join_room_by_id_route(
         todo!("Take send_join_response and 'create' the room using that data");
     }
 
+    // A signed third party invite has to be turned into a real invite before we can join
+    if let Some(third_party_signed) = &body.third_party_signed {
+        if &third_party_signed.mxid != sender_id {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Third party invite was signed for another user.",
+            ));
+        }
+
+        if !db.rooms.is_invited(&sender_id, &body.room_id)?
+            && !db.rooms.is_joined(&sender_id, &body.room_id)?
+        {
+            db.rooms.exchange_third_party_invite(
+                &body.room_id,
+                &third_party_signed.sender,
+                serde_json::to_value(third_party_signed)
+                    .expect("ThirdPartySigned::to_value always works"),
+                &db.globals,
+                &db.account_data,
+            )?;
+        }
+    }
+
     let event = member::MemberEventContent {
         membership: member::MembershipState::Join,
         displayname: db.users.displayname(&sender_id)?,
@@ -226,37 +253,170 @@ pub fn leave_room_route(
     feature = "conduit_bin",
     post("/_matrix/client/r0/rooms/<_>/invite", data = "<body>")
 )]
-pub fn invite_user_route(
+pub async fn invite_user_route(
     db: State<'_, Database<'_>>,
     body: Ruma<invite_user::Request>,
 ) -> ConduitResult<invite_user::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if let invite_user::InvitationRecipient::UserId { user_id } = &body.recipient {
-        db.rooms.append_pdu(
-            PduBuilder {
-                room_id: body.room_id.clone(),
-                sender: sender_id.clone(),
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(member::MemberEventContent {
-                    membership: member::MembershipState::Invite,
-                    displayname: db.users.displayname(&user_id)?,
-                    avatar_url: db.users.avatar_url(&user_id)?,
-                    is_direct: None,
-                    third_party_invite: None,
+    match &body.recipient {
+        invite_user::InvitationRecipient::UserId { user_id } => {
+            invite_helper(&db, &sender_id, &body.room_id, &user_id, None)?;
+        }
+        invite_user::InvitationRecipient::ThirdPartyId(invite_3pid) => {
+            invite_3pid_helper(&db, &sender_id, &body.room_id, invite_3pid, None).await?;
+        }
+    }
+
+    Ok(invite_user::Response.into())
+}
+
+/// Sends an invite event for `user_id` into the room.
+pub(super) fn invite_helper(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    user_id: &UserId,
+    is_direct: Option<bool>,
+) -> Result<EventId> {
+    db.rooms.append_pdu(
+        PduBuilder {
+            room_id: room_id.clone(),
+            sender: sender_id.clone(),
+            event_type: EventType::RoomMember,
+            content: serde_json::to_value(member::MemberEventContent {
+                membership: member::MembershipState::Invite,
+                displayname: db.users.displayname(&user_id)?,
+                avatar_url: db.users.avatar_url(&user_id)?,
+                is_direct,
+                third_party_invite: None,
+            })
+            .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user_id.to_string()),
+            redacts: None,
+        },
+        &db.globals,
+        &db.account_data,
+    )
+}
+
+/// Invites someone who is only known by a third party id (e.g. an email address).
+///
+/// - If the identity server knows a Matrix user for the third party id, that user is invited
+/// - Otherwise the identity server stores the invite and we send an `m.room.third_party_invite`
+/// event with the public keys the identity server will later sign the invite with
+pub(super) async fn invite_3pid_helper(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    invite_3pid: &Invite3pid,
+    is_direct: Option<bool>,
+) -> Result<()> {
+    let id_server = identity_server_url(&invite_3pid.id_server);
+    let medium = serde_json::to_value(&invite_3pid.medium)
+        .expect("Medium::to_value always works")
+        .as_str()
+        .expect("Medium is always a string")
+        .to_owned();
+
+    // Maybe the third party id is already bound to a user
+    let lookup_response = serde_json::from_str::<serde_json::Value>(
+        &db.globals
+            .reqwest_client()
+            .get(&format!("{}/_matrix/identity/api/v1/lookup", id_server))
+            .query(&[("medium", &medium), ("address", &invite_3pid.address)])
+            .send()
+            .await?
+            .text()
+            .await?,
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid lookup response from identity server."))?;
+
+    if let Some(user_id) = lookup_response
+        .get("mxid")
+        .and_then(|mxid| mxid.as_str())
+        .and_then(|mxid| UserId::try_from(mxid).ok())
+    {
+        invite_helper(db, sender_id, room_id, &user_id, is_direct)?;
+        return Ok(());
+    }
+
+    let room_name = db
+        .rooms
+        .room_state_get(room_id, &EventType::RoomName, "")?
+        .and_then(|pdu| pdu.content.get("name").cloned());
+
+    let store_invite_response = serde_json::from_str::<serde_json::Value>(
+        &db.globals
+            .reqwest_client()
+            .post(&format!(
+                "{}/_matrix/identity/api/v1/store-invite",
+                id_server
+            ))
+            .header("Content-Type", "application/json")
+            .body(
+                json!({
+                    "medium": medium,
+                    "address": invite_3pid.address,
+                    "room_id": room_id,
+                    "sender": sender_id,
+                    "room_name": room_name,
+                    "sender_display_name": db.users.displayname(&sender_id)?,
+                    "sender_avatar_url": db.users.avatar_url(&sender_id)?,
                 })
-                .expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(user_id.to_string()),
-                redacts: None,
-            },
-            &db.globals,
-            &db.account_data,
-        )?;
+                .to_string(),
+            )
+            .send()
+            .await?
+            .text()
+            .await?,
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid store-invite response from identity server."))?;
+
+    let token = store_invite_response
+        .get("token")
+        .and_then(|token| token.as_str())
+        .ok_or(Error::BadServerResponse(
+            "Identity server did not return an invite token.",
+        ))?;
+
+    let public_keys = store_invite_response
+        .get("public_keys")
+        .and_then(|keys| keys.as_array())
+        .filter(|keys| !keys.is_empty())
+        .ok_or(Error::BadServerResponse(
+            "Identity server did not return public keys.",
+        ))?;
+
+    db.rooms.append_pdu(
+        PduBuilder {
+            room_id: room_id.clone(),
+            sender: sender_id.clone(),
+            event_type: EventType::RoomThirdPartyInvite,
+            content: json!({
+                "display_name": store_invite_response.get("display_name"),
+                "key_validity_url": public_keys[0].get("key_validity_url"),
+                "public_key": public_keys[0].get("public_key"),
+                "public_keys": public_keys,
+            }),
+            unsigned: None,
+            state_key: Some(token.to_owned()),
+            redacts: None,
+        },
+        &db.globals,
+        &db.account_data,
+    )?;
+
+    Ok(())
+}
 
-        Ok(invite_user::Response.into())
+/// Identity servers are usually given as a server name, but a full url is accepted too.
+fn identity_server_url(id_server: &str) -> String {
+    if id_server.starts_with("https://") || id_server.starts_with("http://") {
+        id_server.trim_end_matches('/').to_owned()
     } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+        format!("https://{}", id_server)
     }
 }
 
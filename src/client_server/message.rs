@@ -1,13 +1,17 @@
-use super::State;
-use crate::{pdu::PduBuilder, utils, ConduitResult, Database, Error, Ruma};
+use super::{EventFilter, State, SyncToken};
+use crate::{pdu::PduBuilder, utils, ConduitResult, Database, Error, PduEvent, Result, Ruma};
 use ruma::{
     api::client::{
         error::ErrorKind,
         r0::message::{get_message_events, send_message_event},
     },
-    EventId,
+    events::{AnyStateEvent, EventType},
+    DeviceId, EventId, Raw, RoomId, UserId,
+};
+use std::{
+    collections::HashSet,
+    convert::{TryFrom, TryInto},
 };
-use std::convert::{TryFrom, TryInto};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, put};
@@ -45,9 +49,6 @@ pub fn send_message_event_route(
         return Ok(send_message_event::Response { event_id }.into());
     }
 
-    let mut unsigned = serde_json::Map::new();
-    unsigned.insert("transaction_id".to_owned(), body.txn_id.clone().into());
-
     let event_id = db.rooms.append_pdu(
         PduBuilder {
             room_id: body.room_id.clone(),
@@ -60,7 +61,7 @@ pub fn send_message_event_route(
                     .get(),
             )
             .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?,
-            unsigned: Some(unsigned),
+            unsigned: None,
             state_key: None,
             redacts: None,
         },
@@ -70,6 +71,9 @@ pub fn send_message_event_route(
 
     db.transaction_ids
         .add_txnid(sender_id, device_id, &body.txn_id, event_id.as_bytes())?;
+    // Only the sending device gets the transaction id back, see `PduEvent::unsigned_for`
+    db.transaction_ids
+        .add_event_txnid(&event_id, device_id, &body.txn_id)?;
     Ok(send_message_event::Response { event_id }.into())
 }
 
@@ -82,21 +86,29 @@ pub fn get_message_events_route(
     body: Ruma<get_message_events::Request>,
 ) -> ConduitResult<get_message_events::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    // Users who left can still read the history until they left
+    let last_visible = if db.rooms.is_joined(sender_id, &body.room_id)? {
+        u64::MAX
+    } else if let Some(left_count) = db.rooms.left_count(sender_id, &body.room_id)? {
+        left_count
+    } else {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
         ));
-    }
+    };
 
-    let from = body
-        .from
-        .clone()
-        .parse()
-        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from` value."))?;
+    // Clients can also paginate from a sync token
+    let from = body.from.parse::<SyncToken>()?.pdus;
 
-    let to = body.to.as_ref().map(|t| t.parse());
+    let to = body
+        .to
+        .as_ref()
+        .map(|t| t.parse::<SyncToken>())
+        .transpose()?
+        .map(|t| t.pdus);
 
     // Use limit or else 10
     let limit = body
@@ -104,54 +116,135 @@ pub fn get_message_events_route(
         .try_into()
         .map_or(Ok::<_, Error>(10_usize), |l: u32| Ok(l as usize))?;
 
+    let filter = EventFilter::from_json(serde_json::to_value(&body.filter).ok().as_ref());
+
     match body.dir {
         get_message_events::Direction::Forward => {
             let events_after = db
                 .rooms
                 .pdus_after(&sender_id, &body.room_id, from)
-                .take(limit)
                 .filter_map(|r| r.ok()) // Filter out buggy events
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
+                .take_while(|&(k, _)| k <= last_visible)
+                .filter(|(_, pdu)| filter.matches_pdu(pdu))
+                .take(limit)
+                .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
                 .collect::<Vec<_>>();
 
             let end_token = events_after.last().map(|(count, _)| count.to_string());
 
+            let state = lazy_loaded_members(
+                &db,
+                sender_id,
+                device_id,
+                &body.room_id,
+                &filter,
+                events_after.iter().map(|(_, pdu)| pdu),
+            )?;
+
             let events_after = events_after
                 .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect::<Vec<_>>();
+                .map(|(_, pdu)| {
+                    Ok(pdu.to_room_event(
+                        db.transaction_ids
+                            .event_txnid(&pdu, sender_id, device_id)?
+                            .as_deref(),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
 
             Ok(get_message_events::Response {
                 start: Some(body.from.clone()),
                 end: end_token,
                 chunk: events_after,
-                state: Vec::new(),
+                state,
             }
             .into())
         }
         get_message_events::Direction::Backward => {
             let events_before = db
                 .rooms
-                .pdus_until(&sender_id, &body.room_id, from)
-                .take(limit)
+                .pdus_until(
+                    &sender_id,
+                    &body.room_id,
+                    from.min(last_visible.saturating_add(1)),
+                )
                 .filter_map(|r| r.ok()) // Filter out buggy events
-                .take_while(|&(k, _)| Some(Ok(k)) != to) // Stop at `to`
+                .filter(|(_, pdu)| filter.matches_pdu(pdu))
+                .take(limit)
+                .take_while(|&(k, _)| Some(k) != to) // Stop at `to`
                 .collect::<Vec<_>>();
 
             let start_token = events_before.last().map(|(count, _)| count.to_string());
 
+            let state = lazy_loaded_members(
+                &db,
+                sender_id,
+                device_id,
+                &body.room_id,
+                &filter,
+                events_before.iter().map(|(_, pdu)| pdu),
+            )?;
+
             let events_before = events_before
                 .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect::<Vec<_>>();
+                .map(|(_, pdu)| {
+                    Ok(pdu.to_room_event(
+                        db.transaction_ids
+                            .event_txnid(&pdu, sender_id, device_id)?
+                            .as_deref(),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
 
             Ok(get_message_events::Response {
                 start: Some(body.from.clone()),
                 end: start_token,
                 chunk: events_before,
-                state: Vec::new(),
+                state,
             }
             .into())
         }
     }
 }
+
+/// Returns the member events of the senders of these events if the filter enables lazy loading.
+/// Member events this device already has are skipped unless the filter asks for redundant members.
+fn lazy_loaded_members<'a>(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    device_id: &DeviceId,
+    room_id: &RoomId,
+    filter: &EventFilter,
+    pdus: impl Iterator<Item = &'a PduEvent>,
+) -> Result<Vec<Raw<AnyStateEvent>>> {
+    if !filter.lazy_load_members {
+        return Ok(Vec::new());
+    }
+
+    let mut members = Vec::new();
+    for user_id in pdus.map(|pdu| &pdu.sender).collect::<HashSet<_>>() {
+        if !filter.include_redundant_members
+            && db
+                .rooms
+                .lazy_load_was_sent_before(sender_id, device_id, room_id, user_id)?
+        {
+            continue;
+        }
+
+        if let Some(pdu) =
+            db.rooms
+                .room_state_get(room_id, &EventType::RoomMember, user_id.as_str())?
+        {
+            members.push((user_id.clone(), pdu.to_state_event()));
+        }
+    }
+
+    db.rooms.lazy_load_mark_sent(
+        sender_id,
+        device_id,
+        room_id,
+        members.iter().map(|(user_id, _)| user_id.clone()),
+    )?;
+
+    Ok(members.into_iter().map(|(_, event)| event).collect())
+}
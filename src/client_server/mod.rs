@@ -19,6 +19,7 @@ mod redact;
 mod room;
 mod search;
 mod session;
+mod sliding_sync;
 mod state;
 mod sync;
 mod tag;
@@ -50,6 +51,7 @@ pub use redact::*;
 pub use room::*;
 pub use search::*;
 pub use session::*;
+pub use sliding_sync::*;
 pub use state::*;
 pub use sync::*;
 pub use tag::*;
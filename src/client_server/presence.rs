@@ -1,6 +1,6 @@
 use super::State;
-use crate::{utils, ConduitResult, Database, Ruma};
-use ruma::api::client::r0::presence::set_presence;
+use crate::{utils, ConduitResult, Database, Result, Ruma};
+use ruma::{api::client::r0::presence::set_presence, presence::PresenceState, UserId};
 use std::convert::TryInto;
 
 #[cfg(feature = "conduit_bin")]
@@ -16,24 +16,36 @@ pub fn set_presence_route(
 ) -> ConduitResult<set_presence::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    for room_id in db.rooms.rooms_joined(&sender_id) {
+    update_presence(&db, sender_id, body.presence, body.status_msg.clone())?;
+
+    Ok(set_presence::Response.into())
+}
+
+/// Sends a new presence event to all rooms of the user.
+pub fn update_presence(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    presence: PresenceState,
+    status_msg: Option<String>,
+) -> Result<()> {
+    for room_id in db.rooms.rooms_joined(sender_id) {
         let room_id = room_id?;
 
         db.rooms.edus.update_presence(
-            &sender_id,
+            sender_id,
             &room_id,
             ruma::events::presence::PresenceEvent {
                 content: ruma::events::presence::PresenceEventContent {
-                    avatar_url: db.users.avatar_url(&sender_id)?,
+                    avatar_url: db.users.avatar_url(sender_id)?,
                     currently_active: None,
-                    displayname: db.users.displayname(&sender_id)?,
+                    displayname: db.users.displayname(sender_id)?,
                     last_active_ago: Some(
                         utils::millis_since_unix_epoch()
                             .try_into()
                             .expect("time is valid"),
                     ),
-                    presence: body.presence,
-                    status_msg: body.status_msg.clone(),
+                    presence,
+                    status_msg: status_msg.clone(),
                 },
                 sender: sender_id.clone(),
             },
@@ -41,5 +53,5 @@ pub fn set_presence_route(
         )?;
     }
 
-    Ok(set_presence::Response.into())
+    Ok(())
 }
@@ -1,16 +1,29 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
-use log::warn;
+use crate::{
+    database::rooms::PushQueue, push_rules::default_pushrules, AuthenticatedUser, ConduitResult,
+    Database, Error, Result, Ruma,
+};
+use rocket::response::content::Json;
 use ruma::{
     api::client::{
         error::ErrorKind,
-        r0::push::{get_pushers, get_pushrules_all, set_pushrule, set_pushrule_enabled},
+        r0::push::{
+            delete_pushrule, get_pushers, get_pushrule, get_pushrule_actions, get_pushrule_enabled,
+            get_pushrules_all, set_pusher, set_pushrule, set_pushrule_actions,
+            set_pushrule_enabled, RuleKind,
+        },
+    },
+    events::{
+        push_rules::{PushRulesEvent, PushRulesEventContent},
+        EventType,
     },
-    events::EventType,
+    push::Ruleset,
+    UserId,
 };
+use serde_json::json;
 
 #[cfg(feature = "conduit_bin")]
-use rocket::{get, post, put};
+use rocket::{delete, get, post, put};
 
 #[cfg_attr(
     feature = "conduit_bin",
@@ -36,40 +49,547 @@ pub fn get_pushrules_all_route(
     .into())
 }
 
-#[cfg_attr(feature = "conduit_bin", put(
-    "/_matrix/client/r0/pushrules/<_>/<_>/<_>",
-    //data = "<body>"
-))]
-pub fn set_pushrule_route(//db: State<'_, Database<'_>>,
-    //body: Ruma<set_pushrule::Request>,
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/pushrules/<_>/<_>/<_>", data = "<body>")
+)]
+pub fn get_pushrule_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_pushrule::Request>,
+) -> ConduitResult<get_pushrule::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let mut global = global_rules(&db, sender_id)?;
+    let rules = rules_of_kind(&mut global, &body.scope, &body.kind)?;
+
+    let rule = rules
+        .iter()
+        .find(|rule| rule_id(rule) == Some(body.rule_id.as_str()))
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Push rule not found.",
+        ))?;
+
+    Ok(get_pushrule::Response {
+        rule: serde_json::from_value(rule.clone())
+            .map_err(|_| Error::bad_database("Invalid push rule in database."))?,
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/client/r0/pushrules/<_>/<_>/<_>", data = "<body>")
+)]
+pub fn set_pushrule_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<set_pushrule::Request>,
 ) -> ConduitResult<set_pushrule::Response> {
-    // TODO
-    warn!("TODO: set_pushrule_route");
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let kind = kind_key(&body.kind);
+    if body.rule_id.starts_with('.') || is_default_rule(sender_id, &kind, &body.rule_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Server-default push rules can't be replaced.",
+        ));
+    }
+
+    let mut rule = json!({
+        "rule_id": body.rule_id,
+        "actions": body.actions,
+        "default": false,
+        "enabled": true,
+    });
+    match kind.as_str() {
+        "content" => {
+            let pattern = body.pattern.as_ref().ok_or(Error::BadRequest(
+                ErrorKind::MissingParam,
+                "Content push rules need a pattern.",
+            ))?;
+            rule["pattern"] = json!(pattern);
+        }
+        "override" | "underride" => rule["conditions"] = json!(body.conditions),
+        _ => {}
+    }
+
+    let mut global = global_rules(&db, sender_id)?;
+    let rules = rules_of_kind(&mut global, &body.scope, &body.kind)?;
+
+    let existing = rules
+        .iter()
+        .position(|r| rule_id(r) == Some(body.rule_id.as_str()));
+
+    match (existing, &body.before, &body.after) {
+        // Updating a rule without moving it keeps its priority
+        (Some(index), None, None) => rules[index] = rule,
+        (existing, before, after) => {
+            if let Some(index) = existing {
+                rules.remove(index);
+            }
+
+            let index = match (before, after) {
+                (Some(before), _) => neighbour_index(sender_id, &kind, rules, before)?,
+                (None, Some(after)) => neighbour_index(sender_id, &kind, rules, after)? + 1,
+                // New rules have the highest priority of their kind, which comes before the
+                // server-default rules, except for .m.rule.master which always stays first
+                (None, None) => rules
+                    .iter()
+                    .position(|rule| rule_id(rule) == Some(".m.rule.master"))
+                    .map_or(0, |index| index + 1),
+            };
+            rules.insert(index, rule);
+        }
+    }
+
+    save_global_rules(&db, sender_id, global)?;
+
     Ok(set_pushrule::Response.into())
 }
 
 #[cfg_attr(
     feature = "conduit_bin",
-    put("/_matrix/client/r0/pushrules/<_>/<_>/<_>/enabled")
+    delete("/_matrix/client/r0/pushrules/<_>/<_>/<_>", data = "<body>")
+)]
+pub fn delete_pushrule_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<delete_pushrule::Request>,
+) -> ConduitResult<delete_pushrule::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    if is_default_rule(sender_id, &kind_key(&body.kind), &body.rule_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Server-default push rules can't be deleted.",
+        ));
+    }
+
+    let mut global = global_rules(&db, sender_id)?;
+    let rules = rules_of_kind(&mut global, &body.scope, &body.kind)?;
+
+    let index = rules
+        .iter()
+        .position(|rule| rule_id(rule) == Some(body.rule_id.as_str()))
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Push rule not found.",
+        ))?;
+    rules.remove(index);
+
+    save_global_rules(&db, sender_id, global)?;
+
+    Ok(delete_pushrule::Response.into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/pushrules/<_>/<_>/<_>/enabled", data = "<body>")
+)]
+pub fn get_pushrule_enabled_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_pushrule_enabled::Request>,
+) -> ConduitResult<get_pushrule_enabled::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let mut global = global_rules(&db, sender_id)?;
+    let rule = find_rule(&mut global, &body.scope, &body.kind, &body.rule_id)?;
+
+    Ok(get_pushrule_enabled::Response {
+        enabled: rule["enabled"]
+            .as_bool()
+            .ok_or_else(|| Error::bad_database("Invalid push rule in database."))?,
+    }
+    .into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/client/r0/pushrules/<_>/<_>/<_>/enabled", data = "<body>")
 )]
-pub fn set_pushrule_enabled_route() -> ConduitResult<set_pushrule_enabled::Response> {
-    // TODO
-    warn!("TODO: set_pushrule_enabled_route");
+pub fn set_pushrule_enabled_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<set_pushrule_enabled::Request>,
+) -> ConduitResult<set_pushrule_enabled::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let mut global = global_rules(&db, sender_id)?;
+    find_rule(&mut global, &body.scope, &body.kind, &body.rule_id)?["enabled"] =
+        body.enabled.into();
+
+    save_global_rules(&db, sender_id, global)?;
+
     Ok(set_pushrule_enabled::Response.into())
 }
 
-#[cfg_attr(feature = "conduit_bin", get("/_matrix/client/r0/pushers"))]
-pub fn get_pushers_route() -> ConduitResult<get_pushers::Response> {
-    Ok(get_pushers::Response {
-        pushers: Vec::new(),
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/pushrules/<_>/<_>/<_>/actions", data = "<body>")
+)]
+pub fn get_pushrule_actions_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_pushrule_actions::Request>,
+) -> ConduitResult<get_pushrule_actions::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let mut global = global_rules(&db, sender_id)?;
+    let rule = find_rule(&mut global, &body.scope, &body.kind, &body.rule_id)?;
+
+    Ok(get_pushrule_actions::Response {
+        actions: serde_json::from_value(rule["actions"].take())
+            .map_err(|_| Error::bad_database("Invalid push rule in database."))?,
     }
     .into())
 }
 
-#[cfg_attr(feature = "conduit_bin", post("/_matrix/client/r0/pushers/set"))]
-pub fn set_pushers_route() -> ConduitResult<get_pushers::Response> {
+#[cfg_attr(
+    feature = "conduit_bin",
+    put("/_matrix/client/r0/pushrules/<_>/<_>/<_>/actions", data = "<body>")
+)]
+pub fn set_pushrule_actions_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<set_pushrule_actions::Request>,
+) -> ConduitResult<set_pushrule_actions::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    let mut global = global_rules(&db, sender_id)?;
+    find_rule(&mut global, &body.scope, &body.kind, &body.rule_id)?["actions"] =
+        json!(body.actions);
+
+    save_global_rules(&db, sender_id, global)?;
+
+    Ok(set_pushrule_actions::Response.into())
+}
+
+/// # `GET /_matrix/client/r0/notifications`
+///
+/// Lists the events that notified the user, newest first.
+///
+/// - `from` is the `next_token` of the previous page
+/// - `only=highlight` only returns highlights
+///
+/// The ruma branch we depend on has no types for this endpoint yet, so the query is parsed by
+/// rocket and the response is built as json, like the sliding sync route does.
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/notifications?<from>&<limit>&<only>")
+)]
+pub fn get_notifications_route(
+    db: State<'_, Database<'_>>,
+    user: AuthenticatedUser,
+    from: Option<String>,
+    limit: Option<usize>,
+    only: Option<String>,
+) -> Result<Json<String>> {
+    let sender_id = &user.sender_id;
+    let device_id = &user.device_id;
+
+    let from = from
+        .map(|from| {
+            from.parse::<u64>()
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid from token."))
+        })
+        .transpose()?
+        .unwrap_or(u64::MAX);
+    let limit = limit.unwrap_or(20).min(100);
+    let only_highlight = only.as_deref() == Some("highlight");
+
+    let mut notifications = Vec::new();
+    let mut last_count = None;
+    let mut next_token = None;
+    for r in db.rooms.notifications(sender_id, from) {
+        let (count, notification) = r?;
+        if only_highlight && !notification.actions.highlight {
+            continue;
+        }
+
+        let pdu = match db.rooms.get_pdu(&notification.event_id)? {
+            Some(pdu) => pdu,
+            None => continue,
+        };
+
+        if notifications.len() == limit {
+            next_token = last_count.map(|count: u64| count.to_string());
+            break;
+        }
+
+        let read = db
+            .rooms
+            .edus
+            .private_read_get(&notification.room_id, sender_id)?
+            .map_or(false, |last_read| count <= last_read);
+
+        notifications.push(json!({
+            "actions": notification.actions.to_actions(),
+            "event": pdu.to_room_event(
+                db.transaction_ids
+                    .event_txnid(&pdu, sender_id, device_id)?
+                    .as_deref(),
+            ),
+            "profile_tag": null,
+            "read": read,
+            "room_id": notification.room_id,
+            "ts": pdu.origin_server_ts,
+        }));
+        last_count = Some(count);
+    }
+
+    Ok(Json(
+        json!({
+            "next_token": next_token,
+            "notifications": notifications,
+        })
+        .to_string(),
+    ))
+}
+
+/// Loads the global ruleset of the user as json, so rules of every kind can be edited the same
+/// way.
+fn global_rules(db: &Database<'_>, sender_id: &UserId) -> Result<serde_json::Value> {
+    let event = db
+        .account_data
+        .get::<PushRulesEvent>(None, sender_id, EventType::PushRules)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "PushRules event not found.",
+        ))?;
+
+    Ok(serde_json::to_value(event.content.global).expect("rulesets can be serialized"))
+}
+
+/// Stores an edited ruleset. Edits that don't result in a valid ruleset are rejected.
+fn save_global_rules(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    global: serde_json::Value,
+) -> Result<()> {
+    let global = serde_json::from_value::<Ruleset>(global)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid push rule."))?;
+
+    db.account_data.update(
+        None,
+        sender_id,
+        EventType::PushRules,
+        &PushRulesEvent {
+            content: PushRulesEventContent { global },
+        },
+        &db.globals,
+    )
+}
+
+/// The key of a rule kind in a serialized ruleset.
+fn kind_key(kind: &RuleKind) -> String {
+    kind.to_string()
+}
+
+fn rules_of_kind<'a>(
+    global: &'a mut serde_json::Value,
+    scope: &str,
+    kind: &RuleKind,
+) -> Result<&'a mut Vec<serde_json::Value>> {
+    if scope != "global" {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Only the global push rule scope is supported.",
+        ));
+    }
+
+    let global = global
+        .as_object_mut()
+        .expect("rulesets are serialized as objects");
+
+    global
+        .entry(kind_key(kind))
+        .or_insert_with(|| json!([]))
+        .as_array_mut()
+        .ok_or_else(|| Error::bad_database("Invalid push rules in database."))
+}
+
+fn find_rule<'a>(
+    global: &'a mut serde_json::Value,
+    scope: &str,
+    kind: &RuleKind,
+    rule_id: &str,
+) -> Result<&'a mut serde_json::Value> {
+    rules_of_kind(global, scope, kind)?
+        .iter_mut()
+        .find(|rule| self::rule_id(rule) == Some(rule_id))
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Push rule not found.",
+        ))
+}
+
+fn rule_id(rule: &serde_json::Value) -> Option<&str> {
+    rule.get("rule_id")?.as_str()
+}
+
+/// Checks if the rule is one of the rules every user starts with.
+fn is_default_rule(sender_id: &UserId, kind: &str, rule_id: &str) -> bool {
+    serde_json::to_value(default_pushrules(sender_id))
+        .expect("rulesets can be serialized")
+        .get(kind)
+        .and_then(|rules| rules.as_array())
+        .map_or(false, |rules| {
+            rules
+                .iter()
+                .any(|rule| self::rule_id(rule) == Some(rule_id))
+        })
+}
+
+/// Returns the index of the rule a new rule is placed `before` or `after`. Rules can only be placed
+/// relative to user-defined rules.
+fn neighbour_index(
+    sender_id: &UserId,
+    kind: &str,
+    rules: &[serde_json::Value],
+    neighbour: &str,
+) -> Result<usize> {
+    if is_default_rule(sender_id, kind, neighbour) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Push rules can't be placed relative to server-default rules.",
+        ));
+    }
+
+    rules
+        .iter()
+        .position(|rule| rule_id(rule) == Some(neighbour))
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "The rule to place the push rule next to was not found.",
+        ))
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/client/r0/pushers", data = "<body>")
+)]
+pub fn get_pushers_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<get_pushers::Request>,
+) -> ConduitResult<get_pushers::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
     Ok(get_pushers::Response {
-        pushers: Vec::new(),
+        pushers: db
+            .users
+            .pushers(sender_id)
+            .map(|pusher| {
+                serde_json::from_value(pusher?)
+                    .map_err(|_| Error::bad_database("Invalid pusher in database."))
+            })
+            .collect::<Result<_>>()?,
     }
     .into())
 }
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/pushers/set", data = "<body>")
+)]
+pub fn set_pushers_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<set_pusher::Request>,
+) -> ConduitResult<set_pusher::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
+
+    let mut pusher = serde_json::from_str::<serde_json::Value>(
+        body.json_body
+            .as_ref()
+            .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?
+            .get(),
+    )
+    .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
+    let pusher = pusher
+        .as_object_mut()
+        .ok_or(Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
+    let append = pusher
+        .remove("append")
+        .and_then(|append| append.as_bool())
+        .unwrap_or(false);
+
+    let field = |name: &str| pusher.get(name).and_then(|v| v.as_str()).map(str::to_owned);
+
+    let app_id = field("app_id").ok_or(Error::BadRequest(
+        ErrorKind::MissingParam,
+        "Missing app_id.",
+    ))?;
+    let pushkey = field("pushkey").ok_or(Error::BadRequest(
+        ErrorKind::MissingParam,
+        "Missing pushkey.",
+    ))?;
+    if app_id.len() > 64 || pushkey.len() > 512 {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "app_id or pushkey is too long.",
+        ));
+    }
+
+    // A kind of null deletes the pusher
+    let kind = match field("kind") {
+        Some(kind) => kind,
+        None => {
+            db.users.remove_pusher(sender_id, &app_id, &pushkey)?;
+            return Ok(set_pusher::Response.into());
+        }
+    };
+
+    if kind == "http"
+        && !pusher
+            .get("data")
+            .and_then(|data| data.get("url"))
+            .and_then(|url| url.as_str())
+            .map_or(false, PushQueue::is_valid_gateway_url)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "HTTP pushers need a data.url with the path /_matrix/push/v1/notify.",
+        ));
+    }
+
+    if kind == "email" {
+        if db.globals.email().is_none() {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Email notifications are not enabled on this server.",
+            ));
+        }
+
+        // The pushkey has to be an email address bound to the account, otherwise anyone could
+        // send our notification emails to any address
+        if db
+            .users
+            .threepid_user("email", &pushkey.to_lowercase())?
+            .as_ref()
+            != Some(sender_id)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Email pushers need an email address bound to the account.",
+            ));
+        }
+    }
+
+    // Without append, the pushkey moves to this user
+    if !append {
+        for user_id in db.users.pushkey_users(&app_id, &pushkey) {
+            let user_id = user_id?;
+            if &user_id != sender_id {
+                db.users.remove_pusher(&user_id, &app_id, &pushkey)?;
+            }
+        }
+    }
+
+    db.users.add_pusher(
+        sender_id,
+        device_id,
+        &app_id,
+        &pushkey,
+        &serde_json::Value::Object(pusher.clone()),
+    )?;
+
+    Ok(set_pusher::Response.into())
+}
@@ -1,8 +1,12 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
+use crate::{ConduitResult, Database, Error, Result, Ruma};
 use ruma::{
-    api::client::{error::ErrorKind, r0::read_marker::set_read_marker},
+    api::client::{
+        error::ErrorKind,
+        r0::{read_marker::set_read_marker, receipt::create_receipt},
+    },
     events::{AnyEphemeralRoomEvent, AnyEvent, EventType},
+    EventId, RoomId, UserId,
 };
 
 #[cfg(feature = "conduit_bin")]
@@ -34,42 +38,69 @@ pub fn set_read_marker_route(
     )?;
 
     if let Some(event) = &body.read_receipt {
-        db.rooms.edus.private_read_set(
-            &body.room_id,
-            &sender_id,
-            db.rooms.get_pdu_count(event)?.ok_or(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Event does not exist.",
-            ))?,
-            &db.globals,
-        )?;
-
-        let mut user_receipts = BTreeMap::new();
-        user_receipts.insert(
-            sender_id.clone(),
-            ruma::events::receipt::Receipt {
-                ts: Some(SystemTime::now()),
-            },
-        );
-        let mut receipt_content = BTreeMap::new();
-        receipt_content.insert(
-            event.clone(),
-            ruma::events::receipt::Receipts {
-                read: Some(user_receipts),
-            },
-        );
-
-        db.rooms.edus.readreceipt_update(
-            &sender_id,
-            &body.room_id,
-            AnyEvent::Ephemeral(AnyEphemeralRoomEvent::Receipt(
-                ruma::events::receipt::ReceiptEvent {
-                    content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-                    room_id: body.room_id.clone(),
-                },
-            )),
-            &db.globals,
-        )?;
+        set_read_receipt(&db, &sender_id, &body.room_id, event)?;
     }
     Ok(set_read_marker::Response.into())
 }
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    post("/_matrix/client/r0/rooms/<_>/receipt/<_>/<_>", data = "<body>")
+)]
+pub fn create_receipt_route(
+    db: State<'_, Database<'_>>,
+    body: Ruma<create_receipt::Request>,
+) -> ConduitResult<create_receipt::Response> {
+    let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+
+    set_read_receipt(&db, &sender_id, &body.room_id, &body.event_id)?;
+
+    Ok(create_receipt::Response.into())
+}
+
+/// Moves the read marker of the user to the event and tells the room about it. Notifications up
+/// to the event count as read.
+fn set_read_receipt(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    event: &EventId,
+) -> Result<()> {
+    db.rooms.edus.private_read_set(
+        room_id,
+        sender_id,
+        db.rooms.get_pdu_count(event)?.ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event does not exist.",
+        ))?,
+        &db.globals,
+    )?;
+    db.rooms.reset_notification_counts(sender_id, room_id)?;
+
+    let mut user_receipts = BTreeMap::new();
+    user_receipts.insert(
+        sender_id.clone(),
+        ruma::events::receipt::Receipt {
+            ts: Some(SystemTime::now()),
+        },
+    );
+    let mut receipt_content = BTreeMap::new();
+    receipt_content.insert(
+        event.clone(),
+        ruma::events::receipt::Receipts {
+            read: Some(user_receipts),
+        },
+    );
+
+    db.rooms.edus.readreceipt_update(
+        sender_id,
+        room_id,
+        AnyEvent::Ephemeral(AnyEphemeralRoomEvent::Receipt(
+            ruma::events::receipt::ReceiptEvent {
+                content: ruma::events::receipt::ReceiptEventContent(receipt_content),
+                room_id: room_id.clone(),
+            },
+        )),
+        &db.globals,
+    )
+}
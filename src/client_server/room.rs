@@ -1,5 +1,9 @@
 use super::State;
-use crate::{pdu::PduBuilder, ConduitResult, Database, Error, Ruma};
+use crate::{
+    pdu::PduBuilder,
+    room_version::{self, RoomVersion},
+    ConduitResult, Database, Error, Ruma,
+};
 use ruma::{
     api::client::{
         error::ErrorKind,
@@ -20,7 +24,7 @@ use rocket::{get, post};
     feature = "conduit_bin",
     post("/_matrix/client/r0/createRoom", data = "<body>")
 )]
-pub fn create_room_route(
+pub async fn create_room_route(
     db: State<'_, Database<'_>>,
     body: Ruma<create_room::Request>,
 ) -> ConduitResult<create_room::Response> {
@@ -53,7 +57,13 @@ pub fn create_room_route(
         .creation_content
         .as_ref()
         .and_then(|c| c.predecessor.clone());
-    content.room_version = RoomVersionId::Version6;
+    content.room_version = RoomVersion::get_supported(
+        body.room_version
+            .as_ref()
+            .unwrap_or(&room_version::DEFAULT_ROOM_VERSION),
+    )?
+    .id
+    .clone();
 
     // 1. The room create event
     db.rooms.append_pdu(
@@ -282,28 +292,20 @@ pub fn create_room_route(
         )?;
     }
 
-    // 7. Events implied by invite (and TODO: invite_3pid)
+    // 7. Events implied by invite and invite_3pid
     for user in &body.invite {
-        db.rooms.append_pdu(
-            PduBuilder {
-                room_id: room_id.clone(),
-                sender: sender_id.clone(),
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(member::MemberEventContent {
-                    membership: member::MembershipState::Invite,
-                    displayname: db.users.displayname(&user)?,
-                    avatar_url: db.users.avatar_url(&user)?,
-                    is_direct: body.is_direct,
-                    third_party_invite: None,
-                })
-                .expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(user.to_string()),
-                redacts: None,
-            },
-            &db.globals,
-            &db.account_data,
-        )?;
+        super::membership::invite_helper(&db, &sender_id, &room_id, &user, body.is_direct)?;
+    }
+
+    for invite_3pid in &body.invite_3pid {
+        super::membership::invite_3pid_helper(
+            &db,
+            &sender_id,
+            &room_id,
+            invite_3pid,
+            body.is_direct,
+        )
+        .await?;
     }
 
     // Homeserver specific stuff
@@ -327,22 +329,24 @@ pub fn get_room_event_route(
     body: Ruma<get_room_event::Request>,
 ) -> ConduitResult<get_room_event::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
-
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
-        ));
-    }
-
-    Ok(get_room_event::Response {
-        event: db
-            .rooms
-            .get_pdu(&body.event_id)?
-            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?
-            .to_room_event(),
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
+
+    // The history visibility decides, so world readable rooms work without joining
+    match db.rooms.get_pdu(&body.event_id)? {
+        Some(pdu)
+            if pdu.room_id == body.room_id && db.rooms.user_can_see_event(sender_id, &pdu)? =>
+        {
+            Ok(get_room_event::Response {
+                event: pdu.to_room_event(
+                    db.transaction_ids
+                        .event_txnid(&pdu, sender_id, device_id)?
+                        .as_deref(),
+                ),
+            }
+            .into())
+        }
+        _ => Err(Error::BadRequest(ErrorKind::NotFound, "Event not found.")),
     }
-    .into())
 }
 
 #[cfg_attr(
@@ -357,18 +361,14 @@ pub fn upgrade_room_route(
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
     // Validate the room version requested
-    let new_version =
-        RoomVersionId::try_from(body.new_version.clone()).expect("invalid room version id");
-
-    if !matches!(
-        new_version,
-        RoomVersionId::Version5 | RoomVersionId::Version6
-    ) {
-        return Err(Error::BadRequest(
-            ErrorKind::UnsupportedRoomVersion,
-            "This server does not support that room version.",
-        ));
-    }
+    let new_version = RoomVersion::get_supported(
+        &RoomVersionId::try_from(body.new_version.clone()).map_err(|_| {
+            Error::BadRequest(
+                ErrorKind::UnsupportedRoomVersion,
+                "This server does not support that room version.",
+            )
+        })?,
+    )?;
 
     // Create a replacement room
     let replacement_room = RoomId::new(db.globals.server_name());
@@ -415,7 +415,7 @@ pub fn upgrade_room_route(
     let mut create_event_content =
         ruma::events::room::create::CreateEventContent::new(sender_id.clone());
     create_event_content.federate = federate;
-    create_event_content.room_version = new_version;
+    create_event_content.room_version = new_version.id.clone();
     create_event_content.predecessor = predecessor;
 
     db.rooms.append_pdu(
@@ -1,5 +1,5 @@
 use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
+use crate::{database::rooms::VisibilityCache, ConduitResult, Database, Error, Result, Ruma};
 use js_int::uint;
 use ruma::api::client::{error::ErrorKind, r0::search::search_events};
 
@@ -17,6 +17,7 @@ pub fn search_events_route(
     body: Ruma<search_events::Request>,
 ) -> ConduitResult<search_events::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
+    let device_id = body.device_id.as_ref().expect("user is authenticated");
 
     let search_criteria = body.search_categories.room_events.as_ref().unwrap();
     let filter = search_criteria.filter.as_ref().unwrap();
@@ -25,13 +26,6 @@ pub fn search_events_route(
 
     let limit = filter.limit.map_or(10, |l| u64::from(l) as usize);
 
-    if !db.rooms.is_joined(sender_id, &room_id)? {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
-        ));
-    }
-
     let skip = match body.next_batch.as_ref().map(|s| s.parse()) {
         Some(Ok(s)) => s,
         Some(Err(_)) => {
@@ -47,22 +41,42 @@ pub fn search_events_route(
         .rooms
         .search_pdus(&room_id, &search_criteria.search_term)?;
 
-    let results = search
+    // The history visibility decides, so world readable rooms can be searched without joining
+    let mut visibility_cache = VisibilityCache::default();
+    let mut visible_pdus = Vec::new();
+    for pdu in search
         .0
-        .map(|result| {
-            Ok::<_, Error>(SearchResult {
+        .map(|result| db.rooms.get_pdu_from_id(&result))
+        .filter_map(|r| r.ok().flatten())
+    {
+        if visible_pdus.len() == skip + limit {
+            break;
+        }
+        if db
+            .rooms
+            .user_can_see_event_cached(sender_id, &pdu, &mut visibility_cache)?
+        {
+            visible_pdus.push(pdu);
+        }
+    }
+
+    let results = visible_pdus
+        .into_iter()
+        .skip(skip)
+        .map(|pdu| {
+            Ok(SearchResult {
                 context: None,
                 rank: None,
-                result: db
-                    .rooms
-                    .get_pdu_from_id(&result)?
-                    .map(|pdu| pdu.to_room_event()),
+                result: Some(
+                    pdu.to_room_event(
+                        db.transaction_ids
+                            .event_txnid(&pdu, sender_id, device_id)?
+                            .as_deref(),
+                    ),
+                ),
             })
         })
-        .filter_map(|r| r.ok())
-        .skip(skip)
-        .take(limit)
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>>>()?;
 
     let next_batch = if results.len() < limit as usize {
         None
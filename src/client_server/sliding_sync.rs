@@ -0,0 +1,588 @@
+use super::{sync::load_timeline, EventFilter, State, SyncToken};
+use crate::{database::globals::Stream, AuthenticatedUser, Database, Error, PduEvent, Result};
+use rocket::response::content::Json;
+use ruma::{api::client::error::ErrorKind, events::EventType, DeviceId, RoomId, UserId};
+use serde_json::json;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    time::Duration,
+};
+
+#[cfg(feature = "conduit_bin")]
+use rocket::{post, tokio};
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc3575/sync`
+///
+/// Sliding window sync: clients ask for ranges of sorted room lists instead of all rooms.
+///
+/// - Rooms are only sent when they are in one of the requested ranges or explicitly subscribed
+/// - Rooms that are new to the connection are sent in full, other rooms only if they have new
+///   events
+/// - Extensions add to-device events, e2ee info, account data and read receipts
+#[cfg_attr(
+    feature = "conduit_bin",
+    post(
+        "/_matrix/client/unstable/org.matrix.msc3575/sync?<pos>&<timeout>",
+        data = "<body>"
+    )
+)]
+pub async fn sliding_sync_route(
+    db: State<'_, Database<'_>>,
+    user: AuthenticatedUser,
+    pos: Option<String>,
+    timeout: Option<u64>,
+    body: String,
+) -> Result<Json<String>> {
+    let sender_id = &user.sender_id;
+    let device_id = &user.device_id;
+
+    let request = serde_json::from_str::<serde_json::Value>(&body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+
+    // Setup watchers, so if there's no response, we can wait for them
+    let watcher = db.watch(sender_id);
+
+    let next_pos = SyncToken::current(&db.globals)?;
+    let since = pos
+        .as_ref()
+        .map(|pos| pos.parse::<SyncToken>())
+        .transpose()?;
+    let since_pdus = since.as_ref().map_or(0, |since| since.pdus);
+
+    // Rooms that enter a window or subscription are sent like in an initial sync
+    let conn_id = request
+        .get("conn_id")
+        .and_then(|conn_id| conn_id.as_str())
+        .unwrap_or_default();
+    let known_rooms = match &pos {
+        Some(pos) => db
+            .rooms
+            .sliding_sync_rooms(sender_id, device_id, conn_id, pos)?,
+        None => HashSet::new(),
+    };
+
+    let mut rooms = Vec::new();
+    for (room_id, invite) in db
+        .rooms
+        .rooms_joined(sender_id)
+        .map(|room_id| (room_id, false))
+        .chain(
+            db.rooms
+                .rooms_invited(sender_id)
+                .map(|room_id| (room_id, true)),
+        )
+    {
+        let room_id = room_id?;
+        rooms.push(RoomListEntry {
+            recency: db.rooms.last_pdu_count(&room_id)?,
+            room_id,
+            invite,
+        });
+    }
+
+    // Names and encryption are only loaded for rooms that are filtered or sorted by them, or sent
+    rooms.sort_by_key(|entry| Reverse(entry.recency));
+    let mut details = HashMap::new();
+
+    // Which rooms to send and how much of each of them
+    let mut room_configs = BTreeMap::<RoomId, RoomConfig>::new();
+
+    let mut lists = serde_json::Map::new();
+    if let Some(requested_lists) = request.get("lists").and_then(|l| l.as_object()) {
+        for (name, list) in requested_lists {
+            let mut entries = Vec::new();
+            for entry in &rooms {
+                if entry.matches(&db, list.get("filters"), &mut details)? {
+                    entries.push(entry);
+                }
+            }
+
+            let by_name = list
+                .get("sort")
+                .and_then(|sort| sort.as_array())
+                .and_then(|sort| sort.first())
+                .and_then(|sort| sort.as_str())
+                == Some("by_name");
+
+            if by_name {
+                for entry in &entries {
+                    RoomDetails::get(&db, &mut details, &entry.room_id)?;
+                }
+                entries.sort_by(|a, b| details[&a.room_id].name.cmp(&details[&b.room_id].name));
+            }
+
+            let config = RoomConfig::from_json(list);
+
+            let mut ops = Vec::new();
+            for range in list
+                .get("ranges")
+                .and_then(|ranges| ranges.as_array())
+                .into_iter()
+                .flatten()
+            {
+                let (start, end) = match (
+                    range.get(0).and_then(|s| s.as_u64()),
+                    range.get(1).and_then(|e| e.as_u64()),
+                ) {
+                    (Some(start), Some(end)) if start <= end => (start as usize, end as usize),
+                    _ => {
+                        return Err(Error::BadRequest(
+                            ErrorKind::InvalidParam,
+                            "Invalid list range.",
+                        ))
+                    }
+                };
+
+                // The range comes from the client, so it may be larger than the list
+                let room_ids = entries
+                    .iter()
+                    .skip(start)
+                    .take((end - start).saturating_add(1))
+                    .map(|entry| entry.room_id.clone())
+                    .collect::<Vec<_>>();
+
+                for room_id in &room_ids {
+                    room_configs
+                        .entry(room_id.clone())
+                        .or_default()
+                        .merge(&config);
+                }
+
+                ops.push(json!({
+                    "op": "SYNC",
+                    "range": [start, end],
+                    "room_ids": room_ids,
+                }));
+            }
+
+            lists.insert(
+                name.clone(),
+                json!({
+                    "count": entries.len(),
+                    "ops": ops,
+                }),
+            );
+        }
+    }
+
+    if let Some(subscriptions) = request
+        .get("room_subscriptions")
+        .and_then(|s| s.as_object())
+    {
+        for (room_id, subscription) in subscriptions {
+            let room_id = RoomId::try_from(room_id.as_str())
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id."))?;
+
+            // Users can only subscribe to rooms they are in
+            if rooms.iter().any(|entry| entry.room_id == room_id) {
+                room_configs
+                    .entry(room_id)
+                    .or_default()
+                    .merge(&RoomConfig::from_json(subscription));
+            }
+        }
+    }
+
+    let mut response_rooms = serde_json::Map::new();
+    for (room_id, config) in &room_configs {
+        let entry = rooms
+            .iter()
+            .find(|entry| &entry.room_id == room_id)
+            .expect("room configs only contain rooms from the list");
+
+        let initial = !known_rooms.contains(room_id);
+        let name = &RoomDetails::get(&db, &mut details, room_id)?.name;
+
+        if entry.invite {
+            // Invites are only sent once
+            if !initial {
+                continue;
+            }
+
+            response_rooms.insert(
+                room_id.to_string(),
+                json!({
+                    "name": name,
+                    "initial": true,
+                    "invite_state": db
+                        .rooms
+                        .room_state_full(room_id)?
+                        .values()
+                        .map(|pdu| pdu.to_stripped_state_event())
+                        .collect::<Vec<_>>(),
+                }),
+            );
+            continue;
+        }
+
+        let (timeline_pdus, limited) = load_timeline(
+            &db,
+            sender_id,
+            room_id,
+            if initial { 0 } else { since_pdus },
+            u64::MAX,
+            config.timeline_limit.min(db.globals.max_timeline_limit()),
+            &EventFilter::default(),
+        )?;
+
+        if !initial && timeline_pdus.is_empty() {
+            continue;
+        }
+
+        let mut room = json!({
+            "name": name,
+            "initial": initial,
+            "limited": limited,
+            "prev_batch": timeline_pdus.first().map(|(count, _)| count.to_string()),
+            "timeline": timeline_pdus
+                .iter()
+                .map(|(_, pdu)| {
+                    Ok(pdu.to_sync_room_event(
+                        db.transaction_ids
+                            .event_txnid(pdu, sender_id, device_id)?
+                            .as_deref(),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            "joined_count": db.rooms.room_members(room_id).count(),
+            "invited_count": db.rooms.room_members_invited(room_id).count(),
+            "notification_count": db.rooms.notification_count(sender_id, room_id)?,
+            "highlight_count": db.rooms.highlight_count(sender_id, room_id)?,
+        });
+
+        // Later state changes are part of the timeline
+        if initial {
+            room["required_state"] = json!(db
+                .rooms
+                .room_state_full(room_id)?
+                .values()
+                .filter(|pdu| config.requires(pdu, sender_id))
+                .map(|pdu| pdu.to_sync_state_event())
+                .collect::<Vec<_>>());
+        }
+
+        response_rooms.insert(room_id.to_string(), room);
+    }
+
+    let extensions = load_extensions(
+        &db,
+        sender_id,
+        device_id,
+        &request,
+        since.as_ref(),
+        room_configs.keys(),
+    )?;
+
+    // Rooms that left all windows are sent in full again when they come back
+    db.rooms.sliding_sync_update(
+        sender_id,
+        device_id,
+        conn_id,
+        pos.as_deref(),
+        &next_pos.to_string(),
+        room_configs.keys(),
+    )?;
+
+    if since.is_some() && response_rooms.is_empty() && extensions.is_empty() {
+        // Hang a few seconds so requests are not spammed
+        // Stop hanging if new info arrives
+        let duration = Duration::from_millis(timeout.unwrap_or(0).min(30_000));
+        let mut delay = tokio::time::delay_for(duration);
+        tokio::select! {
+            _ = &mut delay => {}
+            _ = watcher.changed() => {}
+        }
+    }
+
+    Ok(Json(
+        json!({
+            "pos": next_pos.to_string(),
+            "lists": lists,
+            "rooms": response_rooms,
+            "extensions": extensions,
+        })
+        .to_string(),
+    ))
+}
+
+/// Everything needed to sort a room in the room lists by recency.
+struct RoomListEntry {
+    room_id: RoomId,
+    /// Token of the newest event in the room
+    recency: u64,
+    invite: bool,
+}
+
+impl RoomListEntry {
+    fn matches(
+        &self,
+        db: &Database<'_>,
+        filters: Option<&serde_json::Value>,
+        details: &mut HashMap<RoomId, RoomDetails>,
+    ) -> Result<bool> {
+        let filters = match filters {
+            Some(filters) => filters,
+            None => return Ok(true),
+        };
+
+        if let Some(is_invite) = filters.get("is_invite").and_then(|i| i.as_bool()) {
+            if is_invite != self.invite {
+                return Ok(false);
+            }
+        }
+
+        let is_encrypted = filters.get("is_encrypted").and_then(|e| e.as_bool());
+        let name_like = filters.get("room_name_like").and_then(|n| n.as_str());
+        if is_encrypted.is_none() && name_like.is_none() {
+            return Ok(true);
+        }
+
+        let details = RoomDetails::get(db, details, &self.room_id)?;
+        Ok(
+            is_encrypted.map_or(true, |is_encrypted| is_encrypted == details.encrypted)
+                && name_like.map_or(true, |name| {
+                    details.name.to_lowercase().contains(&name.to_lowercase())
+                }),
+        )
+    }
+}
+
+/// The parts of a room that need its state, so they are only loaded when needed.
+struct RoomDetails {
+    name: String,
+    encrypted: bool,
+}
+
+impl RoomDetails {
+    /// Returns the details of a room, loading them at most once per request.
+    fn get<'a>(
+        db: &Database<'_>,
+        details: &'a mut HashMap<RoomId, RoomDetails>,
+        room_id: &RoomId,
+    ) -> Result<&'a Self> {
+        if !details.contains_key(room_id) {
+            let name = db
+                .rooms
+                .room_state_get(room_id, &EventType::RoomName, "")?
+                .and_then(|pdu| {
+                    pdu.content
+                        .get("name")
+                        .and_then(|name| name.as_str())
+                        .map(str::to_owned)
+                })
+                .unwrap_or_else(|| room_id.to_string());
+
+            let encrypted = db
+                .rooms
+                .room_state_get(room_id, &EventType::RoomEncryption, "")?
+                .is_some();
+
+            details.insert(room_id.clone(), RoomDetails { name, encrypted });
+        }
+
+        Ok(&details[room_id])
+    }
+}
+
+/// How much of a room the client wants. Rooms in multiple lists get the union of everything.
+#[derive(Default)]
+struct RoomConfig {
+    timeline_limit: usize,
+    /// Pairs of event type and state key, both may be `*`
+    required_state: HashSet<(String, String)>,
+}
+
+impl RoomConfig {
+    fn from_json(json: &serde_json::Value) -> Self {
+        RoomConfig {
+            timeline_limit: json
+                .get("timeline_limit")
+                .and_then(|limit| limit.as_u64())
+                .unwrap_or(0) as usize,
+            required_state: json
+                .get("required_state")
+                .and_then(|state| state.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|pair| {
+                    Some((
+                        pair.get(0)?.as_str()?.to_owned(),
+                        pair.get(1)?.as_str()?.to_owned(),
+                    ))
+                })
+                .collect(),
+        }
+    }
+
+    fn merge(&mut self, other: &RoomConfig) {
+        self.timeline_limit = self.timeline_limit.max(other.timeline_limit);
+        self.required_state
+            .extend(other.required_state.iter().cloned());
+    }
+
+    fn requires(&self, pdu: &PduEvent, sender_id: &UserId) -> bool {
+        let kind = pdu.kind.to_string();
+        let state_key = pdu.state_key.as_deref().unwrap_or_default();
+
+        self.required_state
+            .iter()
+            .any(|(required_kind, required_key)| {
+                (required_kind == "*" || required_kind == &kind)
+                    && (required_key == "*"
+                        || required_key == state_key
+                        || (required_key == "$ME" && state_key == sender_id.as_str()))
+            })
+    }
+}
+
+fn load_extensions<'a>(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    device_id: &DeviceId,
+    request: &serde_json::Value,
+    since: Option<&SyncToken>,
+    room_ids: impl Iterator<Item = &'a RoomId> + Clone,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let enabled = |extension: &str| {
+        request
+            .get("extensions")
+            .and_then(|extensions| extensions.get(extension))
+            .and_then(|extension| extension.get("enabled"))
+            .and_then(|enabled| enabled.as_bool())
+            .unwrap_or(false)
+    };
+
+    let mut extensions = serde_json::Map::new();
+
+    if enabled("to_device") {
+        // Remove all to-device events the device received *last time*
+        if let Some(to_device_since) = request["extensions"]["to_device"]
+            .get("since")
+            .and_then(|since| since.as_str())
+            .and_then(|since| since.parse().ok())
+        {
+            db.users
+                .remove_to_device_events(sender_id, device_id, to_device_since)?;
+        }
+
+        let events = db.users.get_to_device_events(sender_id, device_id)?;
+        if !events.is_empty() {
+            extensions.insert(
+                "to_device".to_owned(),
+                json!({
+                    "next_batch": db
+                        .globals
+                        .current_stream_count(Stream::ToDevice)?
+                        .to_string(),
+                    "events": events,
+                }),
+            );
+        }
+    }
+
+    if enabled("e2ee") {
+        let device_lists_since = since.map_or(0, |since| since.device_lists);
+
+        let mut changed = db
+            .users
+            .keys_changed(&sender_id.to_string(), device_lists_since, None)
+            .filter_map(|r| r.ok())
+            .collect::<HashSet<_>>();
+        for room_id in db.rooms.rooms_joined(sender_id).filter_map(|r| r.ok()) {
+            changed.extend(
+                db.users
+                    .keys_changed(&room_id.to_string(), device_lists_since, None)
+                    .filter_map(|r| r.ok()),
+            );
+        }
+
+        let one_time_keys_count = if since.is_none()
+            || db.users.last_one_time_keys_update(sender_id)? > device_lists_since
+        {
+            Some(db.users.count_one_time_keys(sender_id, device_id)?)
+        } else {
+            None
+        };
+
+        if !changed.is_empty() || one_time_keys_count.is_some() {
+            let mut e2ee = json!({
+                "device_lists": {
+                    "changed": changed,
+                    "left": [],
+                },
+            });
+            if let Some(count) = one_time_keys_count {
+                e2ee["device_one_time_keys_count"] = json!(count);
+            }
+            extensions.insert("e2ee".to_owned(), e2ee);
+        }
+    }
+
+    if enabled("account_data") {
+        let account_data_since = since.map_or(0, |since| since.account_data);
+
+        let global = db
+            .account_data
+            .changes_since(None, sender_id, account_data_since)?
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect::<Vec<_>>();
+
+        let mut rooms = serde_json::Map::new();
+        for room_id in room_ids.clone() {
+            let events = db
+                .account_data
+                .changes_since(Some(room_id), sender_id, account_data_since)?
+                .into_iter()
+                .map(|(_, event)| event)
+                .collect::<Vec<_>>();
+            if !events.is_empty() {
+                rooms.insert(room_id.to_string(), json!(events));
+            }
+        }
+
+        if !global.is_empty() || !rooms.is_empty() {
+            extensions.insert(
+                "account_data".to_owned(),
+                json!({
+                    "global": global,
+                    "rooms": rooms,
+                }),
+            );
+        }
+    }
+
+    if enabled("receipts") {
+        let receipts_since = since.map_or(0, |since| since.receipts);
+
+        let mut rooms = serde_json::Map::new();
+        for room_id in room_ids {
+            // Merge all receipt events of the room into one
+            let mut content = serde_json::Map::new();
+            for receipt in db.rooms.edus.readreceipts_since(room_id, receipts_since)? {
+                let receipt = serde_json::to_value(receipt?).expect("Raw can be serialized");
+                if let Some(receipt_content) = receipt.get("content").and_then(|c| c.as_object()) {
+                    content.extend(receipt_content.clone());
+                }
+            }
+
+            if !content.is_empty() {
+                rooms.insert(
+                    room_id.to_string(),
+                    json!({
+                        "type": "m.receipt",
+                        "content": content,
+                    }),
+                );
+            }
+        }
+
+        if !rooms.is_empty() {
+            extensions.insert("receipts".to_owned(), json!({ "rooms": rooms }));
+        }
+    }
+
+    Ok(extensions)
+}
@@ -99,6 +99,7 @@ pub fn send_state_event_for_empty_key_route(
             },
         sender_id,
         device_id,
+        origin,
         json_body,
     } = body;
 
@@ -114,6 +115,7 @@ pub fn send_state_event_for_empty_key_route(
                 },
                 sender_id,
                 device_id,
+                origin,
                 json_body,
             },
         )?
@@ -1,8 +1,12 @@
-use super::State;
-use crate::{ConduitResult, Database, Error, Ruma};
+use super::{update_presence, EventFilter, State, SyncFilter};
+use crate::{
+    database::globals::{Globals, Stream},
+    ConduitResult, Database, Error, PduEvent, Result, Ruma,
+};
 use ruma::{
-    api::client::r0::sync::sync_events,
+    api::client::{error::ErrorKind, r0::sync::sync_events},
     events::{room::member::MembershipState, AnySyncEphemeralRoomEvent, EventType},
+    presence::PresenceState,
     Raw, RoomId, UserId,
 };
 
@@ -11,6 +15,8 @@ use rocket::{get, tokio};
 use std::{
     collections::{hash_map, BTreeMap, HashMap, HashSet},
     convert::TryFrom,
+    fmt,
+    str::FromStr,
     time::Duration,
 };
 
@@ -36,20 +42,53 @@ pub async fn sync_events_route(
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
     let device_id = body.device_id.as_ref().expect("user is authenticated");
 
-    // TODO: match body.set_presence {
-    db.rooms.edus.ping_presence(&sender_id)?;
+    let filter = SyncFilter::from_json(&match &body.filter {
+        Some(sync_events::Filter::FilterDefinition(filter)) => {
+            serde_json::to_value(filter).expect("filter can be serialized")
+        }
+        Some(sync_events::Filter::FilterId(filter_id)) => serde_json::to_value(
+            db.users
+                .get_filter(sender_id, filter_id)?
+                .ok_or(Error::BadRequest(ErrorKind::NotFound, "Filter not found."))?,
+        )
+        .expect("filter can be serialized"),
+        None => serde_json::Value::Null,
+    });
+
+    // Clients that sync with set_presence=offline don't change the presence of the user
+    if body.set_presence != PresenceState::Offline {
+        db.rooms.edus.ping_presence(&sender_id)?;
+
+        if db.rooms.edus.presence_state(&sender_id)? != Some(body.set_presence) {
+            update_presence(&db, sender_id, body.set_presence, None)?;
+        }
+    }
 
     // Setup watchers, so if there's no response, we can wait for them
-    let watcher = db.watch(sender_id, device_id);
+    let watcher = db.watch(sender_id);
 
-    let next_batch = db.globals.current_count()?.to_string();
+    let next_batch = SyncToken::current(&db.globals)?.to_string();
 
     let mut joined_rooms = BTreeMap::new();
-    let since = body
+    let since_token = body
         .since
-        .clone()
-        .and_then(|string| string.parse().ok())
-        .unwrap_or(0);
+        .as_ref()
+        .map(|since| since.parse())
+        .transpose()?
+        .unwrap_or_default();
+    let since = since_token.pdus;
+
+    // The client received the previous response, including its lazy loaded members
+    if let Some(since) = &body.since {
+        db.rooms
+            .lazy_load_confirm_delivery(sender_id, device_id, since)?;
+    }
+
+    let timeline_limit = filter
+        .timeline
+        .limit
+        .unwrap_or(10)
+        .min(db.globals.max_timeline_limit());
 
     let mut presence_updates = HashMap::new();
     let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
@@ -59,46 +98,29 @@ pub async fn sync_events_route(
     // Look for device list updates of this account
     device_list_updates.extend(
         db.users
-            .keys_changed(&sender_id.to_string(), since, None)
+            .keys_changed(&sender_id.to_string(), since_token.device_lists, None)
             .filter_map(|r| r.ok()),
     );
 
     for room_id in db.rooms.rooms_joined(&sender_id) {
         let room_id = room_id?;
 
-        let mut non_timeline_pdus = db
-            .rooms
-            .pdus_since(&sender_id, &room_id, since)?
-            .filter_map(|r| r.ok()); // Filter out buggy events
-
-        // Take the last 10 events for the timeline
-        let timeline_pdus = non_timeline_pdus
-            .by_ref()
-            .rev()
-            .take(10)
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .collect::<Vec<_>>();
+        let (timeline_pdus, limited) = load_timeline(
+            &db,
+            &sender_id,
+            &room_id,
+            since,
+            u64::MAX,
+            timeline_limit,
+            &filter.timeline,
+        )?;
 
         let send_notification_counts = !timeline_pdus.is_empty()
             || db
                 .rooms
                 .edus
                 .last_privateread_update(&sender_id, &room_id)?
-                > since;
-
-        // They /sync response doesn't always return all messages, so we say the output is
-        // limited unless there are events in non_timeline_pdus
-        let mut limited = false;
-
-        let mut state_pdus = Vec::new();
-        for pdu in non_timeline_pdus {
-            if pdu.state_key.is_some() {
-                state_pdus.push(pdu);
-            }
-            limited = true;
-        }
+                > since_token.receipts;
 
         let encrypted_room = db
             .rooms
@@ -177,7 +199,7 @@ pub async fn sync_events_route(
         // Look for device list updates in this room
         device_list_updates.extend(
             db.users
-                .keys_changed(&room_id.to_string(), since, None)
+                .keys_changed(&room_id.to_string(), since_token.device_lists, None)
                 .filter_map(|r| r.ok()),
         );
 
@@ -246,50 +268,103 @@ pub async fn sync_events_route(
             (None, None, Vec::new())
         };
 
-        let notification_count = if send_notification_counts {
-            if let Some(last_read) = db.rooms.edus.private_read_get(&room_id, &sender_id)? {
-                Some(
-                    (db.rooms
-                        .pdus_since(&sender_id, &room_id, last_read)?
-                        .filter_map(|pdu| pdu.ok()) // Filter out buggy events
-                        .filter(|pdu| {
-                            matches!(
-                                pdu.kind.clone(),
-                                EventType::RoomMessage | EventType::RoomEncrypted
-                            )
-                        })
-                        .count() as u32)
-                        .into(),
-                )
-            } else {
-                None
+        let (notification_count, highlight_count) = if send_notification_counts {
+            (
+                Some((db.rooms.notification_count(&sender_id, &room_id)? as u32).into()),
+                Some((db.rooms.highlight_count(&sender_id, &room_id)? as u32).into()),
+            )
+        } else {
+            (None, None)
+        };
+
+        // Paginating backwards from the first timeline event continues exactly where it begins
+        let prev_batch = timeline_pdus.first().map(|(count, _)| count.to_string());
+
+        // With lazy loading, clients only get the member events of users that sent timeline events
+        let timeline_senders = timeline_pdus
+            .iter()
+            .map(|(_, pdu)| pdu.sender.clone())
+            .collect::<HashSet<_>>();
+
+        let state_events = if joined_since_last_sync || body.full_state {
+            if filter.state.lazy_load_members {
+                db.rooms.lazy_load_reset(sender_id, device_id, &room_id)?;
             }
+
+            db.rooms
+                .room_state_full(&room_id)?
+                .into_iter()
+                .map(|(_, pdu)| pdu)
+                .filter(|pdu| filter.state.matches_pdu(pdu))
+                .filter(|pdu| {
+                    !filter.state.lazy_load_members
+                        || pdu.kind != EventType::RoomMember
+                        || timeline_senders
+                            .iter()
+                            .any(|user_id| pdu.state_key.as_deref() == Some(user_id.as_str()))
+                })
+                .collect::<Vec<_>>()
+        } else if filter.state.lazy_load_members {
+            let mut member_events = Vec::new();
+            for user_id in &timeline_senders {
+                if filter.state.include_redundant_members
+                    || !db
+                        .rooms
+                        .lazy_load_was_sent_before(sender_id, device_id, &room_id, user_id)?
+                {
+                    if let Some(pdu) = db.rooms.room_state_get(
+                        &room_id,
+                        &EventType::RoomMember,
+                        user_id.as_str(),
+                    )? {
+                        if filter.state.matches_pdu(&pdu) {
+                            member_events.push(pdu);
+                        }
+                    }
+                }
+            }
+            member_events
         } else {
-            None
+            Vec::new()
         };
 
-        let prev_batch = timeline_pdus.first().map_or(Ok::<_, Error>(None), |e| {
-            Ok(Some(
-                db.rooms
-                    .get_pdu_count(&e.event_id)?
-                    .ok_or_else(|| Error::bad_database("Can't find count from event in db."))?
-                    .to_string(),
-            ))
-        })?;
+        if filter.state.lazy_load_members {
+            db.rooms.lazy_load_mark_pending(
+                sender_id,
+                device_id,
+                &room_id,
+                state_events
+                    .iter()
+                    .filter(|pdu| pdu.kind == EventType::RoomMember)
+                    .filter_map(|pdu| UserId::try_from(pdu.state_key.as_deref()?).ok()),
+                &next_batch,
+            )?;
+        }
 
         let room_events = timeline_pdus
             .into_iter()
-            .map(|pdu| pdu.to_sync_room_event())
-            .collect::<Vec<_>>();
+            .map(|(_, pdu)| {
+                Ok(pdu.to_sync_room_event(
+                    db.transaction_ids
+                        .event_txnid(&pdu, sender_id, device_id)?
+                        .as_deref(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        let mut edus = db
-            .rooms
-            .edus
-            .readreceipts_since(&room_id, since)?
-            .filter_map(|r| r.ok()) // Filter out buggy events
-            .collect::<Vec<_>>();
+        let mut edus = if filter.ephemeral.matches_type("m.receipt") {
+            db.rooms
+                .edus
+                .readreceipts_since(&room_id, since_token.receipts)?
+                .filter_map(|r| r.ok()) // Filter out buggy events
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
 
-        if db.rooms.edus.last_typing_update(&room_id, &db.globals)? > since {
+        if filter.ephemeral.matches_type("m.typing")
+            && db.rooms.edus.last_typing_update(&room_id, &db.globals)? > since_token.typing
+        {
             edus.push(
                 serde_json::from_str(
                     &serde_json::to_string(&AnySyncEphemeralRoomEvent::Typing(
@@ -305,8 +380,9 @@ pub async fn sync_events_route(
             account_data: sync_events::AccountData {
                 events: db
                     .account_data
-                    .changes_since(Some(&room_id), &sender_id, since)?
+                    .changes_since(Some(&room_id), &sender_id, since_token.account_data)?
                     .into_iter()
+                    .filter(|(kind, _)| filter.room_account_data.matches_type(&kind.to_string()))
                     .filter_map(|(_, v)| {
                         serde_json::from_str(v.json().get())
                             .map_err(|_| Error::bad_database("Invalid account event in database."))
@@ -320,7 +396,7 @@ pub async fn sync_events_route(
                 invited_member_count: invited_member_count.map(|n| (n as u32).into()),
             },
             unread_notifications: sync_events::UnreadNotificationsCount {
-                highlight_count: None,
+                highlight_count,
                 notification_count,
             },
             timeline: sync_events::Timeline {
@@ -330,20 +406,15 @@ pub async fn sync_events_route(
             },
             // TODO: state before timeline
             state: sync_events::State {
-                events: if joined_since_last_sync {
-                    db.rooms
-                        .room_state_full(&room_id)?
-                        .into_iter()
-                        .map(|(_, pdu)| pdu.to_sync_state_event())
-                        .collect()
-                } else {
-                    Vec::new()
-                },
+                events: state_events
+                    .iter()
+                    .map(|pdu| pdu.to_sync_state_event())
+                    .collect(),
             },
             ephemeral: sync_events::Ephemeral { events: edus },
         };
 
-        if !joined_room.is_empty() {
+        if !joined_room.is_empty() && filter.matches_room(&room_id) {
             joined_rooms.insert(room_id.clone(), joined_room);
         }
 
@@ -351,8 +422,14 @@ pub async fn sync_events_route(
         for (user_id, presence) in
             db.rooms
                 .edus
-                .presence_since(&room_id, since, &db.rooms, &db.globals)?
+                .presence_since(&room_id, since_token.presence, &db.rooms, &db.globals)?
         {
+            if !filter.presence.matches_type("m.presence")
+                || !filter.presence.matches_sender(&user_id)
+            {
+                continue;
+            }
+
             match presence_updates.entry(user_id) {
                 hash_map::Entry::Vacant(v) => {
                     v.insert(presence);
@@ -385,39 +462,12 @@ pub async fn sync_events_route(
     let mut left_rooms = BTreeMap::new();
     for room_id in db.rooms.rooms_left(&sender_id) {
         let room_id = room_id?;
-        let pdus = db.rooms.pdus_since(&sender_id, &room_id, since)?;
-        let room_events = pdus
-            .filter_map(|pdu| pdu.ok()) // Filter out buggy events
-            .map(|pdu| pdu.to_sync_room_event())
-            .collect();
 
-        let left_room = sync_events::LeftRoom {
-            account_data: sync_events::AccountData { events: Vec::new() },
-            timeline: sync_events::Timeline {
-                limited: false,
-                prev_batch: Some(next_batch.clone()),
-                events: room_events,
-            },
-            state: sync_events::State { events: Vec::new() },
+        let left_count = match db.rooms.left_count(&sender_id, &room_id)? {
+            Some(left_count) => left_count,
+            None => continue,
         };
-
-        let mut left_since_last_sync = false;
-        for pdu in db.rooms.pdus_since(&sender_id, &room_id, since)? {
-            let pdu = pdu?;
-            if pdu.kind == EventType::RoomMember && pdu.state_key == Some(sender_id.to_string()) {
-                let content = serde_json::from_value::<
-                    Raw<ruma::events::room::member::MemberEventContent>,
-                >(pdu.content.clone())
-                .expect("Raw::from_value always works")
-                .deserialize()
-                .map_err(|_| Error::bad_database("Invalid PDU in database."))?;
-
-                if content.membership == MembershipState::Leave {
-                    left_since_last_sync = true;
-                    break;
-                }
-            }
-        }
+        let left_since_last_sync = left_count > since;
 
         if left_since_last_sync {
             device_list_left.extend(
@@ -444,9 +494,95 @@ pub async fn sync_events_route(
             );
         }
 
-        if !left_room.is_empty() {
-            left_rooms.insert(room_id.clone(), left_room);
+        // Rooms left before the last sync are archived and only part of initial syncs that ask
+        // for them
+        let include = if since == 0 {
+            filter.include_leave
+        } else {
+            left_since_last_sync
+        };
+
+        if !include || !filter.matches_room(&room_id) {
+            continue;
         }
+
+        // The user can't see anything after they left
+        let (timeline_pdus, limited) = load_timeline(
+            &db,
+            &sender_id,
+            &room_id,
+            since,
+            left_count + 1,
+            timeline_limit,
+            &filter.timeline,
+        )?;
+
+        let prev_batch = timeline_pdus.first().map(|(count, _)| count.to_string());
+
+        let state_events = if since == 0 || limited {
+            let leave_event = db
+                .rooms
+                .room_state_get(&room_id, &EventType::RoomMember, sender_id.as_str())?
+                .ok_or_else(|| Error::bad_database("Left room has no member event."))?;
+
+            let mut state_events = Vec::new();
+            for event_id in db
+                .rooms
+                .state_at_event(&leave_event.event_id, &db.globals)?
+                .values()
+                .filter(|event_id| {
+                    !timeline_pdus
+                        .iter()
+                        .any(|(_, pdu)| &&pdu.event_id == event_id)
+                })
+            {
+                if let Some(pdu) = db.rooms.get_pdu(event_id)? {
+                    if filter.state.matches_pdu(&pdu) {
+                        state_events.push(pdu.to_sync_state_event());
+                    }
+                }
+            }
+            state_events
+        } else {
+            Vec::new()
+        };
+
+        let room_events = timeline_pdus
+            .into_iter()
+            .map(|(_, pdu)| {
+                Ok(pdu.to_sync_room_event(
+                    db.transaction_ids
+                        .event_txnid(&pdu, sender_id, device_id)?
+                        .as_deref(),
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        let left_room = sync_events::LeftRoom {
+            account_data: sync_events::AccountData {
+                events: db
+                    .account_data
+                    .changes_since(Some(&room_id), &sender_id, since_token.account_data)?
+                    .into_iter()
+                    .filter(|(kind, _)| filter.room_account_data.matches_type(&kind.to_string()))
+                    .filter_map(|(_, v)| {
+                        serde_json::from_str(v.json().get())
+                            .map_err(|_| Error::bad_database("Invalid account event in database."))
+                            .ok()
+                    })
+                    .collect::<Vec<_>>(),
+            },
+            timeline: sync_events::Timeline {
+                limited,
+                prev_batch,
+                events: room_events,
+            },
+            state: sync_events::State {
+                events: state_events,
+            },
+        };
+
+        left_rooms.insert(room_id.clone(), left_room);
     }
 
     let mut invited_rooms = BTreeMap::new();
@@ -513,7 +649,7 @@ pub async fn sync_events_route(
 
     // Remove all to-device events the device received *last time*
     db.users
-        .remove_to_device_events(sender_id, device_id, since)?;
+        .remove_to_device_events(sender_id, device_id, since_token.to_device)?;
 
     let response = sync_events::Response {
         next_batch,
@@ -526,25 +662,30 @@ pub async fn sync_events_route(
             events: presence_updates
                 .into_iter()
                 .map(|(_, v)| Raw::from(v))
+                .take(filter.presence.limit.unwrap_or(usize::MAX))
                 .collect(),
         },
         account_data: sync_events::AccountData {
             events: db
                 .account_data
-                .changes_since(None, &sender_id, since)?
+                .changes_since(None, &sender_id, since_token.account_data)?
                 .into_iter()
+                .filter(|(kind, _)| filter.account_data.matches_type(&kind.to_string()))
                 .filter_map(|(_, v)| {
                     serde_json::from_str(v.json().get())
                         .map_err(|_| Error::bad_database("Invalid account event in database."))
                         .ok()
                 })
+                .take(filter.account_data.limit.unwrap_or(usize::MAX))
                 .collect::<Vec<_>>(),
         },
         device_lists: sync_events::DeviceLists {
             changed: device_list_updates.into_iter().collect(),
             left: device_list_left.into_iter().collect(),
         },
-        device_one_time_keys_count: if db.users.last_one_time_keys_update(sender_id)? > since {
+        device_one_time_keys_count: if db.users.last_one_time_keys_update(sender_id)?
+            > since_token.device_lists
+        {
             db.users.count_one_time_keys(sender_id, device_id)?
         } else {
             BTreeMap::new()
@@ -572,13 +713,130 @@ pub async fn sync_events_route(
         let mut delay = tokio::time::delay_for(duration);
         tokio::select! {
             _ = &mut delay => {}
-            _ = watcher => {}
+            _ = watcher.changed() => {}
         }
     }
 
     Ok(response.into())
 }
 
+/// A position in every stream a client follows with /sync.
+///
+/// Tokens look like `s{pdus}_{receipts}_{typing}_{presence}_{to_device}_{account_data}_{device_lists}`.
+/// Older numeric tokens are positions in one global counter and apply to all streams.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncToken {
+    pub pdus: u64,
+    pub receipts: u64,
+    pub typing: u64,
+    pub presence: u64,
+    pub to_device: u64,
+    pub account_data: u64,
+    pub device_lists: u64,
+}
+
+impl SyncToken {
+    /// Returns the current position of all streams.
+    pub fn current(globals: &Globals<'_>) -> Result<Self> {
+        Ok(SyncToken {
+            pdus: globals.current_stream_count(Stream::Pdus)?,
+            receipts: globals.current_stream_count(Stream::Receipts)?,
+            typing: globals.current_stream_count(Stream::Typing)?,
+            presence: globals.current_stream_count(Stream::Presence)?,
+            to_device: globals.current_stream_count(Stream::ToDevice)?,
+            account_data: globals.current_stream_count(Stream::AccountData)?,
+            device_lists: globals.current_stream_count(Stream::DeviceLists)?,
+        })
+    }
+}
+
+impl FromStr for SyncToken {
+    type Err = Error;
+
+    fn from_str(token: &str) -> Result<Self> {
+        let invalid = || Error::BadRequest(ErrorKind::InvalidParam, "Invalid sync token.");
+
+        if let Ok(count) = token.parse::<u64>() {
+            return Ok(SyncToken {
+                pdus: count,
+                receipts: count,
+                typing: count,
+                presence: count,
+                to_device: count,
+                account_data: count,
+                device_lists: count,
+            });
+        }
+
+        let positions = token
+            .strip_prefix('s')
+            .ok_or_else(invalid)?
+            .split('_')
+            .map(|position| position.parse::<u64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+
+        match positions[..] {
+            [pdus, receipts, typing, presence, to_device, account_data, device_lists] => {
+                Ok(SyncToken {
+                    pdus,
+                    receipts,
+                    typing,
+                    presence,
+                    to_device,
+                    account_data,
+                    device_lists,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for SyncToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "s{}_{}_{}_{}_{}_{}_{}",
+            self.pdus,
+            self.receipts,
+            self.typing,
+            self.presence,
+            self.to_device,
+            self.account_data,
+            self.device_lists
+        )
+    }
+}
+
+/// Returns the last `limit` timeline events between `since` and `until` (exclusive) that match
+/// the filter, oldest first, together with their tokens. The bool is true if there were more
+/// matching events.
+pub(super) fn load_timeline(
+    db: &Database<'_>,
+    sender_id: &UserId,
+    room_id: &RoomId,
+    since: u64,
+    until: u64,
+    limit: usize,
+    filter: &EventFilter,
+) -> Result<(Vec<(u64, PduEvent)>, bool)> {
+    // Walk backwards from the newest event, so we only load what ends up in the timeline
+    let mut timeline_pdus = db
+        .rooms
+        .pdus_until(sender_id, room_id, until)
+        .filter_map(|r| r.ok()) // Filter out buggy events
+        .take_while(|(count, _)| *count > since)
+        .filter(|(_, pdu)| filter.matches_pdu(pdu))
+        .take(limit + 1)
+        .collect::<Vec<_>>();
+
+    let limited = timeline_pdus.len() > limit;
+    timeline_pdus.truncate(limit);
+    timeline_pdus.reverse();
+
+    Ok((timeline_pdus, limited))
+}
+
 fn share_encrypted_room(
     db: &Database<'_>,
     sender_id: &UserId,
@@ -599,3 +857,62 @@ fn share_encrypted_room(
         })
         .any(|encrypted| encrypted)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SyncToken;
+
+    #[test]
+    fn token_round_trips() {
+        let token = SyncToken {
+            pdus: 1,
+            receipts: 2,
+            typing: 3,
+            presence: 4,
+            to_device: 5,
+            account_data: 6,
+            device_lists: 7,
+        };
+
+        assert_eq!(token.to_string(), "s1_2_3_4_5_6_7");
+        assert_eq!(token.to_string().parse::<SyncToken>().unwrap(), token);
+    }
+
+    #[test]
+    fn legacy_token_applies_to_all_streams() {
+        let token = "42".parse::<SyncToken>().unwrap();
+
+        assert_eq!(
+            token,
+            SyncToken {
+                pdus: 42,
+                receipts: 42,
+                typing: 42,
+                presence: 42,
+                to_device: 42,
+                account_data: 42,
+                device_lists: 42,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_tokens_are_rejected() {
+        for token in &[
+            "",
+            "s",
+            "x1_2_3_4_5_6_7",
+            "s1_2_3_4_5_6",
+            "s1_2_3_4_5_6_7_8",
+            "s1_2_3_a_5_6_7",
+            "s1__3_4_5_6_7",
+            "-1",
+        ] {
+            assert!(
+                token.parse::<SyncToken>().is_err(),
+                "{} was accepted",
+                token
+            );
+        }
+    }
+}
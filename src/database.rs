@@ -2,6 +2,7 @@ pub mod account_data;
 pub mod globals;
 pub mod key_backups;
 pub mod media;
+pub mod notifier;
 pub mod rooms;
 pub mod transaction_ids;
 pub mod uiaa;
@@ -10,11 +11,11 @@ pub mod users;
 use crate::{Error, Result};
 use directories::ProjectDirs;
 use log::info;
-use std::fs::remove_dir_all;
-
-use futures::StreamExt;
-use rocket::{futures, Config};
-use ruma::{DeviceId, UserId};
+use notifier::{NotifyKey, Subscription};
+use rocket::Config;
+use ruma::UserId;
+use std::{fs::remove_dir_all, iter, sync::Arc};
+use tokio::sync::Notify;
 
 pub struct Database<'a> {
     pub globals: globals::Globals<'a>,
@@ -67,8 +68,16 @@ impl<'a> Database<'a> {
         let db = sled::open(&path)?;
         info!("Opened sled database at {}", path);
 
+        let globals = globals::Globals::load(
+            db.open_tree("global")?,
+            db.open_tree("server_signingkeys")?,
+            config,
+        )?;
+        let media_storage =
+            media::Media::load_storage(config, &path, globals.reqwest_client().clone())?;
+
         Ok(Self {
-            globals: globals::Globals::load(db.open_tree("global")?, config)?,
+            globals,
             users: users::Users {
                 userid_password: db.open_tree("userid_password")?,
                 userid_displayname: db.open_tree("userid_displayname")?,
@@ -84,6 +93,13 @@ impl<'a> Database<'a> {
                 userid_selfsigningkeyid: db.open_tree("userid_selfsigningkeyid")?,
                 userid_usersigningkeyid: db.open_tree("userid_usersigningkeyid")?,
                 todeviceid_events: db.open_tree("todeviceid_events")?,
+                userfilterid_filter: db.open_tree("userfilterid_filter")?,
+                userpusherid_pusher: db.open_tree("userpusherid_pusher")?,
+                userpusherid_deviceid: db.open_tree("userpusherid_deviceid")?,
+                pusheruserid: db.open_tree("pusheruserid")?,
+                userthreepid_addedts: db.open_tree("userthreepid_addedts")?,
+                threepid_userid: db.open_tree("threepid_userid")?,
+                clientsecretsid_threepidsession: db.open_tree("clientsecretsid_threepidsession")?,
             },
             uiaa: uiaa::Uiaa {
                 userdeviceid_uiaainfo: db.open_tree("userdeviceid_uiaainfo")?,
@@ -98,9 +114,20 @@ impl<'a> Database<'a> {
                     roomid_lasttypingupdate: db.open_tree("roomid_lasttypingupdate")?,
                     presenceid_presence: db.open_tree("presenceid_presence")?,
                     userid_lastpresenceupdate: db.open_tree("userid_lastpresenceupdate")?,
+                    userid_presencestate: db.open_tree("userid_presencestate")?,
+                },
+                push_queue: rooms::PushQueue {
+                    queueid_pduid: db.open_tree("queueid_pduid")?,
+                    pusherqueueid_push: db.open_tree("pusherqueueid_push")?,
+                    pusherqueueid_email: db.open_tree("pusherqueueid_email")?,
+                    pusherid_backoff: db.open_tree("pusherid_backoff")?,
+                    wakeup: Arc::new(Notify::new()),
+                    in_flight: Default::default(),
                 },
                 pduid_pdu: db.open_tree("pduid_pdu")?,
                 eventid_pduid: db.open_tree("eventid_pduid")?,
+                eventid_status: db.open_tree("eventid_status")?,
+                eventid_outlierpdu: db.open_tree("eventid_outlierpdu")?,
                 roomid_pduleaves: db.open_tree("roomid_pduleaves")?,
                 roomstateid_pdu: db.open_tree("roomstateid_pdu")?,
 
@@ -116,12 +143,29 @@ impl<'a> Database<'a> {
                 userroomid_invited: db.open_tree("userroomid_invited")?,
                 roomuserid_invited: db.open_tree("roomuserid_invited")?,
                 userroomid_left: db.open_tree("userroomid_left")?,
+
+                eventid_stateid: db.open_tree("eventid_stateid")?,
+                stateid_state: db.open_tree("stateid_state")?,
+                stateid_statediff: db.open_tree("stateid_statediff")?,
+                roomid_stateid: db.open_tree("roomid_stateid")?,
+
+                lazyloadedids: db.open_tree("lazyloadedids")?,
+                lazyloadpendingids: db.open_tree("lazyloadpendingids")?,
+                slidingsyncids: db.open_tree("slidingsyncids")?,
+
+                usercount_notification: db.open_tree("usercount_notification")?,
+                userroomcount_highlight: db.open_tree("userroomcount_highlight")?,
+                userroomid_notificationcount: db.open_tree("userroomid_notificationcount")?,
+                userroomid_highlightcount: db.open_tree("userroomid_highlightcount")?,
+
+                roomid_mutex: Default::default(),
             },
             account_data: account_data::AccountData {
                 roomuserdataid_accountdata: db.open_tree("roomuserdataid_accountdata")?,
             },
             media: media::Media {
                 mediaid_file: db.open_tree("mediaid_file")?,
+                storage: media_storage,
             },
             key_backups: key_backups::KeyBackups {
                 backupid_algorithm: db.open_tree("backupid_algorithm")?,
@@ -130,92 +174,21 @@ impl<'a> Database<'a> {
             },
             transaction_ids: transaction_ids::TransactionIds {
                 userdevicetxnid_response: db.open_tree("userdevicetxnid_response")?,
+                eventid_devicetxnid: db.open_tree("eventid_devicetxnid")?,
             },
             _db: db,
         })
     }
 
-    pub async fn watch(&self, user_id: &UserId, device_id: &DeviceId) {
-        let userid_bytes = user_id.to_string().as_bytes().to_vec();
-        let mut userid_prefix = userid_bytes.clone();
-        userid_prefix.push(0xff);
-
-        let mut userdeviceid_prefix = userid_prefix.clone();
-        userdeviceid_prefix.extend_from_slice(device_id.as_bytes());
-        userdeviceid_prefix.push(0xff);
-
-        let mut futures = futures::stream::FuturesUnordered::new();
-
-        // Return when *any* user changed his key
-        // TODO: only send for user they share a room with
-        futures.push(
-            self.users
-                .todeviceid_events
-                .watch_prefix(&userdeviceid_prefix),
-        );
-
-        futures.push(self.rooms.userroomid_joined.watch_prefix(&userid_prefix));
-        futures.push(self.rooms.userroomid_invited.watch_prefix(&userid_prefix));
-        futures.push(self.rooms.userroomid_left.watch_prefix(&userid_prefix));
-
-        // Events for rooms we are in
-        for room_id in self.rooms.rooms_joined(user_id).filter_map(|r| r.ok()) {
-            let roomid_bytes = room_id.to_string().as_bytes().to_vec();
-            let mut roomid_prefix = roomid_bytes.clone();
-            roomid_prefix.push(0xff);
-
-            // PDUs
-            futures.push(self.rooms.pduid_pdu.watch_prefix(&roomid_prefix));
-
-            // EDUs
-            futures.push(
-                self.rooms
-                    .edus
-                    .roomid_lasttypingupdate
-                    .watch_prefix(&roomid_bytes),
-            );
-
-            futures.push(
+    /// Subscribes to everything that can change the /sync response of this user.
+    pub fn watch(&self, user_id: &UserId) -> Subscription<'_> {
+        self.globals.notifier().subscribe(
+            iter::once(NotifyKey::User(user_id.clone())).chain(
                 self.rooms
-                    .edus
-                    .readreceiptid_readreceipt
-                    .watch_prefix(&roomid_prefix),
-            );
-
-            // Key changes
-            futures.push(self.users.keychangeid_userid.watch_prefix(&roomid_prefix));
-
-            // Room account data
-            let mut roomuser_prefix = roomid_prefix.clone();
-            roomuser_prefix.extend_from_slice(&userid_prefix);
-
-            futures.push(
-                self.account_data
-                    .roomuserdataid_accountdata
-                    .watch_prefix(&roomuser_prefix),
-            );
-        }
-
-        let mut globaluserdata_prefix = vec![0xff];
-        globaluserdata_prefix.extend_from_slice(&userid_prefix);
-
-        futures.push(
-            self.account_data
-                .roomuserdataid_accountdata
-                .watch_prefix(&globaluserdata_prefix),
-        );
-
-        // More key changes (used when user is not joined to any rooms)
-        futures.push(self.users.keychangeid_userid.watch_prefix(&userid_prefix));
-
-        // One time keys
-        futures.push(
-            self.users
-                .userid_lastonetimekeyupdate
-                .watch_prefix(&userid_bytes),
-        );
-
-        // Wait until one of them finds something
-        futures.next().await;
+                    .rooms_joined(user_id)
+                    .filter_map(|r| r.ok())
+                    .map(NotifyKey::Room),
+            ),
+        )
     }
 }
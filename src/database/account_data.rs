@@ -1,4 +1,4 @@
-use crate::{utils, Error, Result};
+use crate::{database::globals::Stream, utils, Error, Result};
 use ruma::{
     api::client::error::ErrorKind,
     events::{AnyEvent as EduEvent, EventType},
@@ -8,6 +8,7 @@ use serde::{de::DeserializeOwned, Serialize};
 use sled::IVec;
 use std::{collections::HashMap, convert::TryFrom};
 
+#[derive(Clone)]
 pub struct AccountData {
     pub(super) roomuserdataid_accountdata: sled::Tree, // RoomUserDataId = Room + User + Count + Type
 }
@@ -38,7 +39,11 @@ impl AccountData {
         }
 
         let mut key = prefix;
-        key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+        key.extend_from_slice(
+            &globals
+                .next_stream_count(Stream::AccountData)?
+                .to_be_bytes(),
+        );
         key.push(0xff);
         key.extend_from_slice(event_type.to_string().as_bytes());
 
@@ -53,6 +58,8 @@ impl AccountData {
         self.roomuserdataid_accountdata
             .insert(key, &*json.to_string())?;
 
+        globals.notifier().notify_user(user_id);
+
         Ok(())
     }
 
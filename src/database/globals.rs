@@ -1,22 +1,126 @@
+use super::notifier::Notifier;
 use crate::{utils, Error, Result};
-use ruma::ServerName;
-use std::convert::TryInto;
+use lettre::{
+    smtp::{authentication::Credentials, ClientSecurity},
+    SmtpClient, Transport,
+};
+use lettre_email::EmailBuilder;
+use ruma::{api::client::error::ErrorKind, ServerName};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, convert::TryInto, time::Duration};
 
 pub const COUNTER: &str = "c";
 
+/// Set once the contents of all media files were moved out of the database.
+const MEDIA_MIGRATED: &str = "media_migrated";
+
+const DEFAULT_EMAIL_SUBJECT: &str = "[{server_name}] You have {count} unread messages";
+const DEFAULT_EMAIL_TEMPLATE: &str = "Hi {user_id},
+
+You have {count} unread messages on {server_name}:
+
+{notifications}
+";
+
+/// Parts of the server state that clients follow with their own position in a sync token.
+#[derive(Clone, Copy)]
+pub enum Stream {
+    Pdus,
+    Receipts,
+    Typing,
+    Presence,
+    ToDevice,
+    AccountData,
+    DeviceLists,
+}
+
+impl Stream {
+    fn counter(self) -> &'static str {
+        match self {
+            // Pdus share the global counter with everything that isn't a stream
+            Stream::Pdus => COUNTER,
+            Stream::Receipts => "c_receipts",
+            Stream::Typing => "c_typing",
+            Stream::Presence => "c_presence",
+            Stream::ToDevice => "c_todevice",
+            Stream::AccountData => "c_accountdata",
+            Stream::DeviceLists => "c_devicelists",
+        }
+    }
+}
+
+/// Settings for email notifications. Only present if an SMTP relay is configured.
+#[derive(Clone)]
+pub struct EmailConfig {
+    pub smtp_relay: String,
+    /// Only used without TLS, TLS connections always go to the submissions port
+    pub smtp_port: u16,
+    pub smtp_tls: bool,
+    pub smtp_credentials: Option<(String, String)>,
+    pub from: String,
+    /// How long notifications are collected before they are mailed
+    pub delay: Duration,
+    pub subject_template: String,
+    pub body_template: String,
+}
+
+impl EmailConfig {
+    /// Sends an email through the configured SMTP relay. This blocks until the relay accepted it.
+    pub fn send(&self, to: &str, subject: String, body: String) -> Result<()> {
+        let email = EmailBuilder::new()
+            .to(to)
+            .from(self.from.as_str())
+            .subject(subject)
+            .text(body)
+            .build()
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address."))?;
+
+        let mut client = if self.smtp_tls {
+            SmtpClient::new_simple(&self.smtp_relay)?
+        } else {
+            SmtpClient::new(
+                (self.smtp_relay.as_str(), self.smtp_port),
+                ClientSecurity::None,
+            )?
+        };
+        if let Some((username, password)) = &self.smtp_credentials {
+            client = client.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        client.transport().send(email.into())?;
+
+        Ok(())
+    }
+}
+
+/// The verify keys of another server, as cached in server_signingkeys.
+#[derive(Deserialize, Serialize)]
+struct CachedSigningKeys {
+    keys: BTreeMap<String, String>,
+    valid_until_ts: u64,
+}
+
 pub struct Globals<'a> {
     pub(super) globals: sled::Tree,
+    server_signingkeys: sled::Tree, // ServerName -> CachedSigningKeys
     keypair: ruma::signatures::Ed25519KeyPair,
     reqwest_client: reqwest::Client,
     server_name: Box<ServerName>,
     max_request_size: u32,
+    max_timeline_limit: usize,
     registration_disabled: bool,
     encryption_disabled: bool,
     jwt_decoding_key: jsonwebtoken::DecodingKey<'a>,
+    email: Option<EmailConfig>,
+    notifier: Notifier,
 }
 
 impl Globals<'_> {
-    pub fn load(globals: sled::Tree, config: &rocket::Config) -> Result<Self> {
+    pub fn load(
+        globals: sled::Tree,
+        server_signingkeys: sled::Tree,
+        config: &rocket::Config,
+    ) -> Result<Self> {
         let keypair = ruma::signatures::Ed25519KeyPair::new(
             &*globals
                 .update_and_fetch("keypair", utils::generate_keypair)?
@@ -36,6 +140,7 @@ impl Globals<'_> {
 
         Ok(Self {
             globals,
+            server_signingkeys,
             keypair,
             reqwest_client: reqwest::Client::new(),
             server_name: config
@@ -51,17 +156,112 @@ impl Globals<'_> {
                 .unwrap_or(20 * 1024 * 1024) // Default to 20 MB
                 .try_into()
                 .map_err(|_| Error::BadConfig("Invalid max_request_size."))?,
+            max_timeline_limit: config
+                .get_int("max_timeline_limit")
+                .unwrap_or(100)
+                .try_into()
+                .map_err(|_| Error::BadConfig("Invalid max_timeline_limit."))?,
             registration_disabled: config.get_bool("registration_disabled").unwrap_or(false),
             encryption_disabled: config.get_bool("encryption_disabled").unwrap_or(false),
             jwt_decoding_key,
+            email: Self::load_email_config(config)?,
+            notifier: Notifier::default(),
         })
     }
 
+    fn load_email_config(config: &rocket::Config) -> Result<Option<EmailConfig>> {
+        let smtp_relay = match config.get_str("smtp_relay") {
+            Ok(smtp_relay) => smtp_relay.to_owned(),
+            Err(_) => return Ok(None),
+        };
+
+        let from = config
+            .get_str("email_from")
+            .map_err(|_| Error::BadConfig("email_from is required for email notifications."))?
+            .to_owned();
+        lettre::EmailAddress::new(from.clone())
+            .map_err(|_| Error::BadConfig("Invalid email_from."))?;
+
+        let smtp_credentials = match (
+            config.get_str("smtp_username"),
+            config.get_str("smtp_password"),
+        ) {
+            (Ok(username), Ok(password)) => Some((username.to_owned(), password.to_owned())),
+            _ => None,
+        };
+
+        let body_template = match config.get_str("email_template") {
+            Ok(path) => std::fs::read_to_string(path)
+                .map_err(|_| Error::BadConfig("Could not read email_template."))?,
+            Err(_) => DEFAULT_EMAIL_TEMPLATE.to_owned(),
+        };
+
+        Ok(Some(EmailConfig {
+            smtp_relay,
+            smtp_port: config
+                .get_int("smtp_port")
+                .unwrap_or(25)
+                .try_into()
+                .map_err(|_| Error::BadConfig("Invalid smtp_port."))?,
+            smtp_tls: config.get_bool("smtp_tls").unwrap_or(true),
+            smtp_credentials,
+            from,
+            delay: Duration::from_secs(
+                config
+                    .get_int("email_notif_delay")
+                    .unwrap_or(10 * 60) // Default to 10 minutes
+                    .try_into()
+                    .map_err(|_| Error::BadConfig("Invalid email_notif_delay."))?,
+            ),
+            subject_template: config
+                .get_str("email_subject")
+                .unwrap_or(DEFAULT_EMAIL_SUBJECT)
+                .to_owned(),
+            body_template,
+        }))
+    }
+
     /// Returns this server's keypair.
     pub fn keypair(&self) -> &ruma::signatures::Ed25519KeyPair {
         &self.keypair
     }
 
+    /// Caches the verify keys (key id -> base64 public key) of another server.
+    pub fn add_signing_keys(
+        &self,
+        origin: &ServerName,
+        keys: BTreeMap<String, String>,
+        valid_until_ts: u64,
+    ) -> Result<()> {
+        self.server_signingkeys.insert(
+            origin.as_str().as_bytes(),
+            &*serde_json::to_string(&CachedSigningKeys {
+                keys,
+                valid_until_ts,
+            })
+            .expect("signing keys can be serialized"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the cached verify keys of another server if they are still valid.
+    pub fn signing_keys_for(
+        &self,
+        origin: &ServerName,
+    ) -> Result<Option<BTreeMap<String, String>>> {
+        Ok(self
+            .server_signingkeys
+            .get(origin.as_str().as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice::<CachedSigningKeys>(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid signing keys in server_signingkeys."))
+            })
+            .transpose()?
+            .filter(|cached| cached.valid_until_ts > utils::millis_since_unix_epoch())
+            .map(|cached| cached.keys))
+    }
+
     /// Returns a reqwest client which can be used to send requests.
     pub fn reqwest_client(&self) -> &reqwest::Client {
         &self.reqwest_client
@@ -84,6 +284,41 @@ impl Globals<'_> {
         })
     }
 
+    pub fn media_migrated(&self) -> Result<bool> {
+        Ok(self.globals.contains_key(MEDIA_MIGRATED)?)
+    }
+
+    pub fn set_media_migrated(&self) -> Result<()> {
+        self.globals.insert(MEDIA_MIGRATED, &[])?;
+        Ok(())
+    }
+
+    /// Returns the next position in a stream.
+    pub fn next_stream_count(&self, stream: Stream) -> Result<u64> {
+        // Streams without their own counter yet start at the global counter, so positions in
+        // old numeric sync tokens stay valid
+        let start = self.current_count()?.to_be_bytes();
+
+        Ok(utils::u64_from_bytes(
+            &self
+                .globals
+                .update_and_fetch(stream.counter(), |old| {
+                    utils::increment(old.or(Some(&start)))
+                })?
+                .expect("utils::increment will always put in a value"),
+        )
+        .map_err(|_| Error::bad_database("Count has invalid bytes."))?)
+    }
+
+    /// Returns the current position in a stream.
+    pub fn current_stream_count(&self, stream: Stream) -> Result<u64> {
+        match self.globals.get(stream.counter())? {
+            Some(bytes) => utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Count has invalid bytes.")),
+            None => self.current_count(),
+        }
+    }
+
     pub fn server_name(&self) -> &ServerName {
         self.server_name.as_ref()
     }
@@ -92,6 +327,11 @@ impl Globals<'_> {
         self.max_request_size
     }
 
+    /// The maximum number of timeline events per room in a /sync response.
+    pub fn max_timeline_limit(&self) -> usize {
+        self.max_timeline_limit
+    }
+
     pub fn registration_disabled(&self) -> bool {
         self.registration_disabled
     }
@@ -103,4 +343,14 @@ impl Globals<'_> {
     pub fn jwt_decoding_key(&self) -> &jsonwebtoken::DecodingKey<'_> {
         &self.jwt_decoding_key
     }
+
+    /// Returns the email settings, if email notifications are enabled.
+    pub fn email(&self) -> Option<&EmailConfig> {
+        self.email.as_ref()
+    }
+
+    /// Returns the notifier that wakes up sync requests when something changes.
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
 }
@@ -1,5 +1,16 @@
+mod filesystem;
+mod s3;
+
+pub use filesystem::FileSystemStorage;
+pub use s3::S3Storage;
+
 use crate::{utils, Error, Result};
-use std::mem;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    mem,
+    path::{Path, PathBuf},
+};
 
 pub struct FileMeta {
     pub filename: Option<String>,
@@ -7,13 +18,142 @@ pub struct FileMeta {
     pub file: Vec<u8>,
 }
 
+/// Keeps the contents of media files. Files are addressed by names that are safe to use in paths
+/// and urls.
+#[rocket::async_trait]
+pub trait MediaStorage: Send + Sync {
+    async fn write(&self, name: &str, file: &[u8]) -> Result<()>;
+
+    /// Returns None if there is no file with this name.
+    async fn read(&self, name: &str) -> Result<Option<Vec<u8>>>;
+}
+
 pub struct Media {
-    pub(super) mediaid_file: sled::Tree, // MediaId = MXC + WidthHeight + Filename + ContentType
+    pub(super) mediaid_file: sled::Tree, // MediaId = MXC + WidthHeight + Filename + ContentType, File = ()
+    pub(super) storage: Box<dyn MediaStorage>,
 }
 
 impl Media {
+    /// Creates the storage backend selected by `media_backend` in the config.
+    pub(super) fn load_storage(
+        config: &rocket::Config,
+        database_path: &str,
+        reqwest_client: reqwest::Client,
+    ) -> Result<Box<dyn MediaStorage>> {
+        let get = |name: &str, error: &'static str| {
+            config
+                .get_str(name)
+                .map(str::to_owned)
+                .map_err(|_| Error::BadConfig(error))
+        };
+
+        match config.get_str("media_backend").unwrap_or("filesystem") {
+            "filesystem" => Ok(Box::new(FileSystemStorage::new(
+                config
+                    .get_str("media_path")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|_| Path::new(database_path).join("media")),
+            )?)),
+            "s3" => Ok(Box::new(S3Storage::new(
+                reqwest_client,
+                &get(
+                    "s3_endpoint",
+                    "s3_endpoint is required for the s3 media_backend.",
+                )?,
+                get(
+                    "s3_bucket",
+                    "s3_bucket is required for the s3 media_backend.",
+                )?,
+                config
+                    .get_str("s3_region")
+                    .unwrap_or("us-east-1")
+                    .to_owned(),
+                get(
+                    "s3_access_key",
+                    "s3_access_key is required for the s3 media_backend.",
+                )?,
+                get(
+                    "s3_secret_key",
+                    "s3_secret_key is required for the s3 media_backend.",
+                )?,
+            )?)),
+            _ => Err(Error::BadConfig("Unknown media_backend.")),
+        }
+    }
+
+    /// Moves file contents that older versions stored as values of mediaid_file into the media
+    /// storage. Files that can't be moved are logged and skipped, the next start tries again.
+    pub async fn migrate_to_storage(&self, globals: &super::globals::Globals<'_>) -> Result<()> {
+        if globals.media_migrated()? {
+            return Ok(());
+        }
+
+        let mut migrated = 0_usize;
+        let mut failed = 0_usize;
+        for r in self.mediaid_file.iter() {
+            let (key, value) = r?;
+
+            let result = async {
+                // Empty files look like migrated ones, they only need a file if there is none yet
+                if value.is_empty() && self.storage.read(&Self::file_name(&key)).await?.is_some() {
+                    return Ok(false);
+                }
+
+                self.write_file(&key, &value).await?;
+                self.mediaid_file.insert(&key, &[])?;
+                Ok::<_, Error>(true)
+            }
+            .await;
+
+            match result {
+                Ok(true) => migrated += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    warn!(
+                        "Could not move media file {} to the media storage: {}",
+                        Self::file_name(&key),
+                        e
+                    );
+                    failed += 1;
+                }
+            }
+        }
+
+        if migrated > 0 {
+            info!("Moved {} media files out of the database", migrated);
+        }
+
+        if failed > 0 {
+            warn!(
+                "{} media files are still in the database, the next start tries again",
+                failed
+            );
+        } else {
+            globals.set_media_migrated()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the name of the file that stores the contents of the media with this id. Ids can
+    /// be longer than file names are allowed to be, so files are named by the hash of the id.
+    fn file_name(key: &[u8]) -> String {
+        utils::hex(&Sha256::digest(key))
+    }
+
+    async fn write_file(&self, key: &[u8], file: &[u8]) -> Result<()> {
+        self.storage.write(&Self::file_name(key), file).await
+    }
+
+    async fn read_file(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.storage
+            .read(&Self::file_name(key))
+            .await?
+            .ok_or_else(|| Error::bad_database("Media file of mediaid_file is missing."))
+    }
+
     /// Uploads or replaces a file.
-    pub fn create(
+    pub async fn create(
         &self,
         mxc: String,
         filename: Option<&String>,
@@ -29,21 +169,25 @@ impl Media {
         key.push(0xff);
         key.extend_from_slice(content_type.as_bytes());
 
-        self.mediaid_file.insert(key, file)?;
+        // Write the file first, so the media never exists without its contents
+        self.write_file(&key, file).await?;
+        self.mediaid_file.insert(key, &[])?;
 
         Ok(())
     }
 
     /// Downloads a file.
-    pub fn get(&self, mxc: String) -> Result<Option<FileMeta>> {
+    pub async fn get(&self, mxc: String) -> Result<Option<FileMeta>> {
         let mut prefix = mxc.as_bytes().to_vec();
         prefix.push(0xff);
         prefix.extend_from_slice(&0_u32.to_be_bytes()); // Width = 0 if it's not a thumbnail
         prefix.extend_from_slice(&0_u32.to_be_bytes()); // Height = 0 if it's not a thumbnail
         prefix.push(0xff);
 
-        if let Some(r) = self.mediaid_file.scan_prefix(&prefix).next() {
-            let (key, file) = r?;
+        let r = self.mediaid_file.scan_prefix(&prefix).next();
+        if let Some(r) = r {
+            let (key, _) = r?;
+            let file = self.read_file(&key).await?;
             let mut parts = key.rsplit(|&b| b == 0xff);
 
             let content_type = utils::string_from_bytes(
@@ -68,7 +212,7 @@ impl Media {
             Ok(Some(FileMeta {
                 filename,
                 content_type,
-                file: file.to_vec(),
+                file,
             }))
         } else {
             Ok(None)
@@ -76,7 +220,12 @@ impl Media {
     }
 
     /// Downloads a file's thumbnail.
-    pub fn get_thumbnail(&self, mxc: String, width: u32, height: u32) -> Result<Option<FileMeta>> {
+    pub async fn get_thumbnail(
+        &self,
+        mxc: String,
+        width: u32,
+        height: u32,
+    ) -> Result<Option<FileMeta>> {
         let mut main_prefix = mxc.as_bytes().to_vec();
         main_prefix.push(0xff);
 
@@ -90,9 +239,13 @@ impl Media {
         original_prefix.extend_from_slice(&0_u32.to_be_bytes()); // Height = 0 if it's not a thumbnail
         original_prefix.push(0xff);
 
-        if let Some(r) = self.mediaid_file.scan_prefix(&thumbnail_prefix).next() {
+        let thumbnail = self.mediaid_file.scan_prefix(&thumbnail_prefix).next();
+        let original = self.mediaid_file.scan_prefix(&original_prefix).next();
+
+        if let Some(r) = thumbnail {
             // Using saved thumbnail
-            let (key, file) = r?;
+            let (key, _) = r?;
+            let file = self.read_file(&key).await?;
             let mut parts = key.rsplit(|&b| b == 0xff);
 
             let content_type = utils::string_from_bytes(
@@ -118,11 +271,12 @@ impl Media {
             Ok(Some(FileMeta {
                 filename,
                 content_type,
-                file: file.to_vec(),
+                file,
             }))
-        } else if let Some(r) = self.mediaid_file.scan_prefix(&original_prefix).next() {
+        } else if let Some(r) = original {
             // Generate a thumbnail
-            let (key, file) = r?;
+            let (key, _) = r?;
+            let file = self.read_file(&key).await?;
             let mut parts = key.rsplit(|&b| b == 0xff);
 
             let content_type = utils::string_from_bytes(
@@ -164,7 +318,8 @@ impl Media {
                     widthheight,
                 );
 
-                self.mediaid_file.insert(thumbnail_key, &*thumbnail_bytes)?;
+                self.write_file(&thumbnail_key, &thumbnail_bytes).await?;
+                self.mediaid_file.insert(thumbnail_key, &[])?;
 
                 Ok(Some(FileMeta {
                     filename,
@@ -0,0 +1,43 @@
+use super::MediaStorage;
+use crate::Result;
+use rocket::tokio::task;
+use std::{fs, io, path::PathBuf};
+
+/// Stores media files in a directory on the local filesystem.
+pub struct FileSystemStorage {
+    path: PathBuf,
+}
+
+impl FileSystemStorage {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&path)?;
+
+        Ok(Self { path })
+    }
+}
+
+// std::fs blocks, so the file operations run on the blocking thread pool
+#[rocket::async_trait]
+impl MediaStorage for FileSystemStorage {
+    async fn write(&self, name: &str, file: &[u8]) -> Result<()> {
+        let path = self.path.join(name);
+        let file = file.to_vec();
+
+        Ok(task::spawn_blocking(move || fs::write(path, file))
+            .await
+            .expect("writing a file does not panic")?)
+    }
+
+    async fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path.join(name);
+
+        match task::spawn_blocking(move || fs::read(path))
+            .await
+            .expect("reading a file does not panic")
+        {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
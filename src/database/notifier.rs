@@ -0,0 +1,98 @@
+use ruma::{RoomId, UserId};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+/// Something a sync request can wait for changes in.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum NotifyKey {
+    User(UserId),
+    Room(RoomId),
+}
+
+/// Wakes up waiting sync requests when data they are interested in changes. Writers publish
+/// changes by user or room, so a change only wakes up the requests that care about it.
+#[derive(Default)]
+pub struct Notifier {
+    listeners: Mutex<HashMap<NotifyKey, Vec<Arc<Notify>>>>,
+}
+
+impl Notifier {
+    /// Listens for changes in any of these keys until the subscription is dropped.
+    pub fn subscribe(&self, keys: impl IntoIterator<Item = NotifyKey>) -> Subscription<'_> {
+        let notify = Arc::new(Notify::new());
+        let keys = keys.into_iter().collect::<Vec<_>>();
+
+        let mut listeners = self
+            .listeners
+            .lock()
+            .expect("notifier mutex is never poisoned");
+        for key in &keys {
+            listeners
+                .entry(key.clone())
+                .or_default()
+                .push(Arc::clone(&notify));
+        }
+
+        Subscription {
+            notifier: self,
+            keys,
+            notify,
+        }
+    }
+
+    pub fn notify_user(&self, user_id: &UserId) {
+        self.notify(&NotifyKey::User(user_id.clone()));
+    }
+
+    pub fn notify_room(&self, room_id: &RoomId) {
+        self.notify(&NotifyKey::Room(room_id.clone()));
+    }
+
+    fn notify(&self, key: &NotifyKey) {
+        if let Some(listeners) = self
+            .listeners
+            .lock()
+            .expect("notifier mutex is never poisoned")
+            .get(key)
+        {
+            for notify in listeners {
+                notify.notify();
+            }
+        }
+    }
+}
+
+/// A registration for changes, see `Notifier::subscribe`.
+pub struct Subscription<'a> {
+    notifier: &'a Notifier,
+    keys: Vec<NotifyKey>,
+    notify: Arc<Notify>,
+}
+
+impl Subscription<'_> {
+    /// Waits until something changed since the subscription was created or last woke up.
+    pub async fn changed(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        let mut listeners = self
+            .notifier
+            .listeners
+            .lock()
+            .expect("notifier mutex is never poisoned");
+        for key in &self.keys {
+            if let Some(notifies) = listeners.get_mut(key) {
+                notifies.retain(|notify| !Arc::ptr_eq(notify, &self.notify));
+                if notifies.is_empty() {
+                    listeners.remove(key);
+                }
+            }
+        }
+    }
+}
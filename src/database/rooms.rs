@@ -1,32 +1,84 @@
 mod edus;
+mod push_queue;
 
 pub use edus::RoomEdus;
-
-use crate::{pdu::PduBuilder, utils, Error, PduEvent, Result};
+pub use push_queue::PushQueue;
+
+use crate::{
+    pdu::PduBuilder,
+    push_rules::{self, Notification, PatternCache, PushContext},
+    room_version::RoomVersion,
+    stateres::{self, StateMap},
+    utils, Error, PduEvent, Result,
+};
 use log::error;
 use ruma::{
     api::client::error::ErrorKind,
     events::{
         ignored_user_list,
         room::{
+            history_visibility::{self, HistoryVisibility},
             join_rules, member,
             power_levels::{self, PowerLevelsEventContent},
         },
         EventType,
     },
-    EventId, Raw, RoomAliasId, RoomId, UserId,
+    DeviceId, EventId, Raw, RoomAliasId, RoomId, ServerName, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sled::{
+    transaction::{ConflictableTransactionError, Transactional},
+    IVec,
 };
-use sled::IVec;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     convert::{TryFrom, TryInto},
     mem,
+    sync::{Arc, Mutex},
 };
 
+/// Why a remote event is not part of the timeline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PduStatus {
+    /// The event is not allowed by its own auth events or the state before it
+    Rejected,
+    /// The event is valid, but not allowed by the current room state
+    SoftFailed,
+}
+
+/// The history visibility and membership of one user per state snapshot, and whether the user is
+/// currently joined per room. Checking many events with one cache loads every state diff once.
+#[derive(Default)]
+pub struct VisibilityCache {
+    states: HashMap<u64, (HistoryVisibility, Option<member::MembershipState>)>,
+    joined: HashMap<RoomId, bool>,
+}
+
+/// Local events reference at most this many leaves.
+const MAX_PREV_EVENTS: usize = 20;
+
+/// Every state snapshot after this many diffs is stored in full, so loading a snapshot never
+/// applies more diffs than this.
+const MAX_STATE_DIFF_DEPTH: u32 = 100;
+
+/// A state snapshot stored as the difference to its parent snapshot. Snapshots without a parent
+/// contain the full state in `added`.
+#[derive(Deserialize, Serialize)]
+struct StateDiff {
+    parent: Option<u64>,
+    added: Vec<((EventType, String), EventId)>,
+    removed: Vec<(EventType, String)>,
+}
+
+#[derive(Clone)]
 pub struct Rooms {
     pub edus: edus::RoomEdus,
+    pub push_queue: PushQueue,
     pub(super) pduid_pdu: sled::Tree, // PduId = RoomId + Count
     pub(super) eventid_pduid: sled::Tree,
+    pub(super) eventid_status: sled::Tree, // Only remote events that are not in the timeline
+    pub(super) eventid_outlierpdu: sled::Tree,
     pub(super) roomid_pduleaves: sled::Tree,
     pub(super) roomstateid_pdu: sled::Tree, // RoomStateId = Room + StateType + StateKey
 
@@ -42,6 +94,53 @@ pub struct Rooms {
     pub(super) userroomid_invited: sled::Tree,
     pub(super) roomuserid_invited: sled::Tree,
     pub(super) userroomid_left: sled::Tree,
+
+    pub(super) eventid_stateid: sled::Tree, // StateId = Count
+    pub(super) stateid_state: sled::Tree,   // Full snapshots from before state diffs
+    pub(super) stateid_statediff: sled::Tree, // StateDiff = Depth + StateDiff json
+    pub(super) roomid_stateid: sled::Tree,
+
+    pub(super) lazyloadedids: sled::Tree, // LazyLoadedId = UserId + DeviceId + RoomId + MemberId
+    pub(super) lazyloadpendingids: sled::Tree, // LazyLoadedId, NextBatch = the sync token of the response with the member
+    pub(super) slidingsyncids: sled::Tree, // SlidingSyncId = UserId + DeviceId + ConnId + Pos + RoomId
+
+    pub(super) usercount_notification: sled::Tree, // UserCount = UserId + Count
+    pub(super) userroomcount_highlight: sled::Tree, // UserRoomCount = UserId + RoomId + Count, Highlight = 1 or 0
+    pub(super) userroomid_notificationcount: sled::Tree,
+    pub(super) userroomid_highlightcount: sled::Tree,
+
+    pub(super) roomid_mutex: Arc<Mutex<HashMap<RoomId, Arc<Mutex<()>>>>>,
+}
+
+/// The mutex of one room. It is removed from `roomid_mutex` when the last user drops it, so the
+/// map doesn't grow with every room that was ever written to.
+struct RoomMutex {
+    roomid_mutex: Arc<Mutex<HashMap<RoomId, Arc<Mutex<()>>>>>,
+    room_id: RoomId,
+    mutex: Arc<Mutex<()>>,
+}
+
+impl std::ops::Deref for RoomMutex {
+    type Target = Mutex<()>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.mutex
+    }
+}
+
+impl Drop for RoomMutex {
+    fn drop(&mut self) {
+        let mut roomid_mutex = self
+            .roomid_mutex
+            .lock()
+            .expect("roomid_mutex is never poisoned");
+
+        // Other users can only clone the mutex while holding the map lock, so the count is stable.
+        // One reference is in the map and one is ours
+        if Arc::strong_count(&self.mutex) == 2 {
+            roomid_mutex.remove(&self.room_id);
+        }
+    }
 }
 
 impl Rooms {
@@ -150,6 +249,23 @@ impl Rooms {
             })
     }
 
+    /// Returns the `count` of the newest pdu in the room, or 0 if it has none. Only one key is
+    /// read, so this is cheap enough to sort all rooms of a user by.
+    pub fn last_pdu_count(&self, room_id: &RoomId) -> Result<u64> {
+        let mut prefix = room_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.pduid_pdu
+            .scan_prefix(&prefix)
+            .keys()
+            .next_back()
+            .transpose()?
+            .map_or(Ok(0), |pdu_id| {
+                utils::u64_from_bytes(&pdu_id[prefix.len()..])
+                    .map_err(|_| Error::bad_database("PDU has invalid count bytes."))
+            })
+    }
+
     /// Returns the json of a pdu.
     pub fn get_pdu_json(&self, event_id: &EventId) -> Result<Option<serde_json::Value>> {
         self.eventid_pduid
@@ -164,6 +280,53 @@ impl Rooms {
             })
     }
 
+    /// Returns the json of a pdu, including rejected and soft failed events. Those are not part
+    /// of the timeline, but other servers might need them for auth chains.
+    pub fn get_pdu_json_with_outliers(
+        &self,
+        event_id: &EventId,
+    ) -> Result<Option<serde_json::Value>> {
+        match self.get_pdu_json(event_id)? {
+            Some(pdu_json) => Ok(Some(pdu_json)),
+            None => self
+                .eventid_outlierpdu
+                .get(event_id.to_string().as_bytes())?
+                .map_or(Ok(None), |pdu| {
+                    Ok(Some(
+                        serde_json::from_slice(&pdu)
+                            .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
+                    ))
+                }),
+        }
+    }
+
+    /// Returns the pdu, including rejected and soft failed events.
+    pub fn get_pdu_with_outliers(&self, event_id: &EventId) -> Result<Option<PduEvent>> {
+        match self.get_pdu(event_id)? {
+            Some(pdu) => Ok(Some(pdu)),
+            None => self
+                .eventid_outlierpdu
+                .get(event_id.to_string().as_bytes())?
+                .map_or(Ok(None), |pdu| {
+                    Ok(Some(
+                        serde_json::from_slice(&pdu)
+                            .map_err(|_| Error::bad_database("Invalid PDU in db."))?,
+                    ))
+                }),
+        }
+    }
+
+    /// Returns if a remote event was rejected or soft failed.
+    pub fn pdu_status(&self, event_id: &EventId) -> Result<Option<PduStatus>> {
+        self.eventid_status
+            .get(event_id.to_string().as_bytes())?
+            .map_or(Ok(None), |status| match &*status {
+                b"rejected" => Ok(Some(PduStatus::Rejected)),
+                b"softfailed" => Ok(Some(PduStatus::SoftFailed)),
+                _ => Err(Error::bad_database("Invalid status in eventid_status.")),
+            })
+    }
+
     /// Returns the pdu's id.
     pub fn get_pdu_id(&self, event_id: &EventId) -> Result<Option<IVec>> {
         self.eventid_pduid
@@ -236,396 +399,1985 @@ impl Rooms {
         Ok(events)
     }
 
-    /// Replace the leaves of a room with a new event.
-    pub fn replace_pdu_leaves(&self, room_id: &RoomId, event_id: &EventId) -> Result<()> {
-        let mut prefix = room_id.to_string().as_bytes().to_vec();
-        prefix.push(0xff);
+    /// Returns the mutex that serializes all writes into a room.
+    fn room_mutex(&self, room_id: &RoomId) -> RoomMutex {
+        let mutex = Arc::clone(
+            self.roomid_mutex
+                .lock()
+                .expect("roomid_mutex is never poisoned")
+                .entry(room_id.clone())
+                .or_default(),
+        );
+
+        RoomMutex {
+            roomid_mutex: Arc::clone(&self.roomid_mutex),
+            room_id: room_id.clone(),
+            mutex,
+        }
+    }
 
-        for key in self.roomid_pduleaves.scan_prefix(&prefix).keys() {
-            self.roomid_pduleaves.remove(key?)?;
+    /// Returns the id of the state snapshot that matches the current room state. Rooms that were
+    /// created before state snapshots existed get one now.
+    fn current_state_id(
+        &self,
+        room_id: &RoomId,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<u64> {
+        if let Some(state_id) = self.roomid_stateid.get(room_id.to_string().as_bytes())? {
+            return utils::u64_from_bytes(&state_id)
+                .map_err(|_| Error::bad_database("Invalid state id in roomid_stateid."));
         }
 
-        prefix.extend_from_slice(event_id.to_string().as_bytes());
-        self.roomid_pduleaves
-            .insert(&prefix, &*event_id.to_string())?;
+        let state = self
+            .room_state_full(room_id)?
+            .into_iter()
+            .map(|(key, pdu)| (key, pdu.event_id))
+            .collect::<StateMap<_>>();
+
+        let state_id = globals.next_count()?;
+        self.stateid_statediff.insert(
+            &state_id.to_be_bytes(),
+            Self::serialize_state_diff(0, &Self::full_state_diff(state)),
+        )?;
+        self.roomid_stateid
+            .insert(room_id.to_string().as_bytes(), &state_id.to_be_bytes())?;
+
+        Ok(state_id)
+    }
 
-        Ok(())
+    fn serialize_state_diff(depth: u32, diff: &StateDiff) -> Vec<u8> {
+        let mut bytes = depth.to_be_bytes().to_vec();
+        bytes.extend_from_slice(
+            serde_json::to_string(diff)
+                .expect("state diffs can be serialized")
+                .as_bytes(),
+        );
+        bytes
     }
 
-    /// Creates a new persisted data unit and adds it to a room.
-    #[allow(clippy::blocks_in_if_conditions)]
-    pub fn append_pdu(
-        &self,
-        pdu_builder: PduBuilder,
-        globals: &super::globals::Globals<'_>,
-        account_data: &super::account_data::AccountData,
-    ) -> Result<EventId> {
-        let PduBuilder {
-            room_id,
-            sender,
-            event_type,
-            content,
-            unsigned,
-            state_key,
-            redacts,
-        } = pdu_builder;
-        // TODO: Make sure this isn't called twice in parallel
-        let prev_events = self.get_pdu_leaves(&room_id)?;
+    fn full_state_diff(state: StateMap<EventId>) -> StateDiff {
+        StateDiff {
+            parent: None,
+            added: state.into_iter().collect(),
+            removed: Vec::new(),
+        }
+    }
 
-        // Is the event authorized?
-        if let Some(state_key) = &state_key {
-            let power_levels = self
-                .room_state_get(&room_id, &EventType::RoomPowerLevels, "")?
-                .map_or_else(
-                    || {
-                        Ok::<_, Error>(power_levels::PowerLevelsEventContent {
-                            ban: 50.into(),
-                            events: BTreeMap::new(),
-                            events_default: 0.into(),
-                            invite: 50.into(),
-                            kick: 50.into(),
-                            redact: 50.into(),
-                            state_default: 0.into(),
-                            users: BTreeMap::new(),
-                            users_default: 0.into(),
-                            notifications:
-                                ruma::events::room::power_levels::NotificationPowerLevels {
-                                    room: 50.into(),
-                                },
-                        })
-                    },
-                    |power_levels| {
-                        Ok(serde_json::from_value::<Raw<PowerLevelsEventContent>>(
-                            power_levels.content,
-                        )
-                        .expect("Raw::from_value always works.")
-                        .deserialize()
-                        .map_err(|_| Error::bad_database("Invalid PowerLevels event in db."))?)
-                    },
-                )?;
-            let sender_membership = self
-                .room_state_get(&room_id, &EventType::RoomMember, &sender.to_string())?
-                .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
-                    Ok(
-                        serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content)
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership,
-                    )
-                })?;
+    fn deserialize_state_diff(bytes: &[u8]) -> Result<StateDiff> {
+        bytes
+            .get(4..)
+            .and_then(|json| serde_json::from_slice::<StateDiff>(json).ok())
+            .ok_or_else(|| Error::bad_database("Invalid state diff in stateid_statediff."))
+    }
 
-            let sender_power = power_levels.users.get(&sender).map_or_else(
-                || {
-                    if sender_membership != member::MembershipState::Join {
-                        None
-                    } else {
-                        Some(&power_levels.users_default)
-                    }
+    /// Returns how many diffs have to be applied to load this snapshot.
+    fn state_diff_depth(&self, state_id: u64) -> Result<u32> {
+        match self.stateid_statediff.get(&state_id.to_be_bytes())? {
+            Some(bytes) => bytes
+                .get(..4)
+                .and_then(|depth| depth.try_into().ok())
+                .map(u32::from_be_bytes)
+                .ok_or_else(|| Error::bad_database("Invalid state diff in stateid_statediff.")),
+            // Snapshots from before state diffs are stored in full
+            None => Ok(0),
+        }
+    }
+
+    /// Creates a snapshot that differs from the snapshot `parent` by `added` and `removed`. The
+    /// snapshot is stored in full instead if the chain of diffs would get too long.
+    ///
+    /// Returns the depth and the diff to save.
+    fn state_diff(
+        &self,
+        parent: u64,
+        added: Vec<((EventType, String), EventId)>,
+        removed: Vec<(EventType, String)>,
+    ) -> Result<(u32, StateDiff)> {
+        let depth = self.state_diff_depth(parent)? + 1;
+        if depth < MAX_STATE_DIFF_DEPTH {
+            return Ok((
+                depth,
+                StateDiff {
+                    parent: Some(parent),
+                    added,
+                    removed,
                 },
-                // If it's okay, wrap with Some(_)
-                Some,
-            );
+            ));
+        }
+
+        let mut state = self.state_snapshot(parent)?;
+        for key in &removed {
+            state.remove(key);
+        }
+        state.extend(added);
+        Ok((0, Self::full_state_diff(state)))
+    }
+
+    /// Like `state_diff`, but calculates the difference between the state of the snapshot
+    /// `parent` and `state`. Returns None if they are equal.
+    fn state_diff_to(
+        &self,
+        parent: u64,
+        parent_state: &StateMap<EventId>,
+        state: &StateMap<EventId>,
+    ) -> Result<Option<(u32, StateDiff)>> {
+        let added = state
+            .iter()
+            .filter(|(key, event_id)| parent_state.get(key) != Some(event_id))
+            .map(|(key, event_id)| (key.clone(), event_id.clone()))
+            .collect::<Vec<_>>();
+        let removed = parent_state
+            .keys()
+            .filter(|key| !state.contains_key(key))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if added.is_empty() && removed.is_empty() {
+            return Ok(None);
+        }
+        self.state_diff(parent, added, removed).map(Some)
+    }
+
+    /// Loads the state of a diff that may not be saved yet.
+    fn apply_state_diff(&self, diff: &StateDiff) -> Result<StateMap<EventId>> {
+        let mut state = match diff.parent {
+            Some(parent) => self.state_snapshot(parent)?,
+            None => StateMap::new(),
+        };
+        for key in &diff.removed {
+            state.remove(key);
+        }
+        state.extend(diff.added.iter().cloned());
+        Ok(state)
+    }
 
-            // Is the event allowed?
-            if !match event_type {
-                EventType::RoomEncryption => {
-                    // Don't allow encryption events when it's disabled
-                    !globals.encryption_disabled()
+    /// Returns the state of a state snapshot. Snapshots are stored as a chain of diffs that
+    /// starts at a full snapshot.
+    fn state_snapshot(&self, state_id: u64) -> Result<StateMap<EventId>> {
+        let mut diffs = Vec::new();
+        let mut state = StateMap::new();
+        let mut next = Some(state_id);
+
+        while let Some(state_id) = next {
+            match self.stateid_statediff.get(&state_id.to_be_bytes())? {
+                Some(bytes) => {
+                    let diff = Self::deserialize_state_diff(&bytes)?;
+                    next = diff.parent;
+                    diffs.push(diff);
                 }
-                EventType::RoomMember => {
-                    let target_user_id = UserId::try_from(&**state_key).map_err(|_| {
-                        Error::BadRequest(
-                            ErrorKind::InvalidParam,
-                            "State key of member event does not contain user id.",
-                        )
-                    })?;
-
-                    let current_membership = self
-                        .room_state_get(
-                            &room_id,
-                            &EventType::RoomMember,
-                            &target_user_id.to_string(),
-                        )?
-                        .map_or(Ok::<_, Error>(member::MembershipState::Leave), |pdu| {
-                            Ok(serde_json::from_value::<Raw<member::MemberEventContent>>(
-                                pdu.content,
-                            )
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership)
+                None => {
+                    let full = self
+                        .stateid_state
+                        .get(&state_id.to_be_bytes())?
+                        .ok_or_else(|| {
+                            Error::bad_database("State id points to nonexistent state.")
                         })?;
+                    state = serde_json::from_slice::<Vec<((EventType, String), EventId)>>(&full)
+                        .map_err(|_| Error::bad_database("Invalid state in stateid_state."))?
+                        .into_iter()
+                        .collect();
+                    next = None;
+                }
+            }
+        }
 
-                    let target_membership =
-                        serde_json::from_value::<Raw<member::MemberEventContent>>(content.clone())
-                            .expect("Raw::from_value always works.")
-                            .deserialize()
-                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
-                            .membership;
-
-                    let target_power = power_levels.users.get(&target_user_id).map_or_else(
-                        || {
-                            if target_membership != member::MembershipState::Join {
-                                None
-                            } else {
-                                Some(&power_levels.users_default)
-                            }
-                        },
-                        // If it's okay, wrap with Some(_)
-                        Some,
-                    );
+        for diff in diffs.into_iter().rev() {
+            for key in &diff.removed {
+                state.remove(key);
+            }
+            state.extend(diff.added);
+        }
 
-                    let join_rules =
-                        self.room_state_get(&room_id, &EventType::RoomJoinRules, "")?
-                            .map_or(Ok::<_, Error>(join_rules::JoinRule::Public), |pdu| {
-                                Ok(serde_json::from_value::<
-                                    Raw<join_rules::JoinRulesEventContent>,
-                                >(pdu.content)
-                                .expect("Raw::from_value always works.")
-                                .deserialize()
-                                .map_err(|_| {
-                                    Error::bad_database("Database contains invalid JoinRules event")
-                                })?
-                                .join_rule)
-                            })?;
+        Ok(state)
+    }
 
-                    if target_membership == member::MembershipState::Join {
-                        let mut prev_events = prev_events.iter();
-                        let prev_event = self
-                            .get_pdu(prev_events.next().ok_or(Error::BadRequest(
-                                ErrorKind::Unknown,
-                                "Membership can't be the first event",
-                            ))?)?
-                            .ok_or_else(|| {
-                                Error::bad_database("PDU leaf points to invalid event!")
-                            })?;
-                        if prev_event.kind == EventType::RoomCreate
-                            && prev_event.prev_events.is_empty()
-                        {
-                            true
-                        } else if sender != target_user_id {
-                            false
-                        } else if let member::MembershipState::Ban = current_membership {
-                            false
-                        } else {
-                            join_rules == join_rules::JoinRule::Invite
-                                && (current_membership == member::MembershipState::Join
-                                    || current_membership == member::MembershipState::Invite)
-                                || join_rules == join_rules::JoinRule::Public
-                        }
-                    } else if target_membership == member::MembershipState::Invite {
-                        if let Some(third_party_invite_json) = content.get("third_party_invite") {
-                            if current_membership == member::MembershipState::Ban {
-                                false
-                            } else {
-                                let _third_party_invite =
-                                    serde_json::from_value::<member::ThirdPartyInvite>(
-                                        third_party_invite_json.clone(),
-                                    )
-                                    .map_err(|_| {
-                                        Error::BadRequest(
-                                            ErrorKind::InvalidParam,
-                                            "ThirdPartyInvite is invalid",
-                                        )
-                                    })?;
-                                todo!("handle third party invites");
-                            }
-                        } else if sender_membership != member::MembershipState::Join
-                            || current_membership == member::MembershipState::Join
-                            || current_membership == member::MembershipState::Ban
-                        {
-                            false
-                        } else {
-                            sender_power
-                                .filter(|&p| p >= &power_levels.invite)
-                                .is_some()
-                        }
-                    } else if target_membership == member::MembershipState::Leave {
-                        if sender == target_user_id {
-                            current_membership == member::MembershipState::Join
-                                || current_membership == member::MembershipState::Invite
-                        } else if sender_membership != member::MembershipState::Join
-                            || current_membership == member::MembershipState::Ban
-                                && sender_power.filter(|&p| p < &power_levels.ban).is_some()
-                        {
-                            false
-                        } else {
-                            sender_power.filter(|&p| p >= &power_levels.kick).is_some()
-                                && target_power < sender_power
-                        }
-                    } else if target_membership == member::MembershipState::Ban {
-                        if sender_membership != member::MembershipState::Join {
-                            false
-                        } else {
-                            sender_power.filter(|&p| p >= &power_levels.ban).is_some()
-                                && target_power < sender_power
+    /// Returns the state of the room right after this event.
+    pub fn state_at_event(
+        &self,
+        event_id: &EventId,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<StateMap<EventId>> {
+        match self.eventid_stateid.get(event_id.as_bytes())? {
+            Some(state_id) => self.state_snapshot(
+                utils::u64_from_bytes(&state_id)
+                    .map_err(|_| Error::bad_database("Invalid state id in eventid_stateid."))?,
+            ),
+            None => {
+                // Events from before state snapshots existed only know the current state
+                let pdu = self
+                    .get_pdu(event_id)?
+                    .ok_or_else(|| Error::bad_database("Event to load state from not found."))?;
+                self.state_snapshot(self.current_state_id(&pdu.room_id, globals)?)
+            }
+        }
+    }
+
+    /// Checks if the history visibility of the room allows the user to see this event. It is
+    /// decided by the room state and the membership of the user before the event.
+    pub fn user_can_see_event(&self, user_id: &UserId, pdu: &PduEvent) -> Result<bool> {
+        self.user_can_see_event_cached(user_id, pdu, &mut VisibilityCache::default())
+    }
+
+    /// Like `user_can_see_event`, but remembers the visibility and membership of every state
+    /// snapshot in `cache`. Use one cache per user when checking many events, then each snapshot
+    /// only loads its own diff.
+    pub fn user_can_see_event_cached(
+        &self,
+        user_id: &UserId,
+        pdu: &PduEvent,
+        cache: &mut VisibilityCache,
+    ) -> Result<bool> {
+        // Users can always see their own membership changes
+        if pdu.kind == EventType::RoomMember && pdu.state_key.as_deref() == Some(user_id.as_str()) {
+            return Ok(true);
+        }
+
+        let (history_visibility, membership) = match self.state_id_of_event(&pdu.event_id)? {
+            Some(state_id) => {
+                self.visibility_in_snapshot(state_id, &pdu.room_id, user_id, cache)?
+            }
+            // Without a snapshot, only the current state is known
+            None => (
+                self.history_visibility_in(&None, &pdu.room_id)?,
+                self.membership_in(&None, &pdu.room_id, user_id)?,
+            ),
+        };
+        let history_visibility = Self::history_visibility_before(pdu, history_visibility);
+
+        Ok(match history_visibility {
+            HistoryVisibility::WorldReadable => true,
+            // Members can see the whole history, former members what happened while they were in
+            // the room
+            HistoryVisibility::Shared => {
+                membership == Some(member::MembershipState::Join) || {
+                    match cache.joined.get(&pdu.room_id) {
+                        Some(joined) => *joined,
+                        None => {
+                            let joined = self.is_joined(user_id, &pdu.room_id)?;
+                            cache.joined.insert(pdu.room_id.clone(), joined);
+                            joined
                         }
-                    } else {
-                        false
                     }
                 }
-                EventType::RoomCreate => prev_events.is_empty(),
-                // Not allow any of the following events if the sender is not joined.
-                _ if sender_membership != member::MembershipState::Join => false,
-                _ => {
-                    // TODO
-                    sender_power.unwrap_or(&power_levels.users_default)
-                        >= &power_levels.state_default
+            }
+            HistoryVisibility::Invited => matches!(
+                membership,
+                Some(member::MembershipState::Join) | Some(member::MembershipState::Invite)
+            ),
+            _ => membership == Some(member::MembershipState::Join),
+        })
+    }
+
+    /// Returns the history visibility and the membership of the user in a state snapshot. The
+    /// chain of diffs is only followed up to the nearest snapshot in the cache, and every snapshot
+    /// on the way is cached too.
+    fn visibility_in_snapshot(
+        &self,
+        state_id: u64,
+        room_id: &RoomId,
+        user_id: &UserId,
+        cache: &mut VisibilityCache,
+    ) -> Result<(HistoryVisibility, Option<member::MembershipState>)> {
+        let visibility_key = (EventType::RoomHistoryVisibility, String::new());
+        let member_key = (EventType::RoomMember, user_id.to_string());
+
+        let mut diffs = Vec::new();
+        let mut base = None;
+        let mut next = Some(state_id);
+        while let Some(current) = next {
+            if let Some(cached) = cache.states.get(&current) {
+                base = Some(cached.clone());
+                break;
+            }
+
+            match self.stateid_statediff.get(&current.to_be_bytes())? {
+                Some(bytes) => {
+                    let diff = Self::deserialize_state_diff(&bytes)?;
+                    next = diff.parent;
+                    diffs.push((current, diff));
+                }
+                None => {
+                    // Snapshots from before state diffs are stored in full
+                    let state = Some(self.state_snapshot(current)?);
+                    let visibility = (
+                        self.history_visibility_in(&state, room_id)?,
+                        self.membership_in(&state, room_id, user_id)?,
+                    );
+                    cache.states.insert(current, visibility.clone());
+                    base = Some(visibility);
+                    next = None;
                 }
-            } {
-                error!("Unauthorized");
-                // Not authorized
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "Event is not authorized",
-                ));
             }
-        } else if !self.is_joined(&sender, &room_id)? {
-            // TODO: auth rules apply to all events, not only those with a state key
-            error!("Unauthorized");
-            return Err(Error::BadRequest(
-                ErrorKind::Forbidden,
-                "Event is not authorized",
-            ));
         }
 
-        // Our depth is the maximum depth of prev_events + 1
-        let depth = prev_events
-            .iter()
-            .filter_map(|event_id| Some(self.get_pdu_json(event_id).ok()??.get("depth")?.as_u64()?))
-            .max()
-            .unwrap_or(0_u64)
-            + 1;
+        // The chain starts at a full snapshot, so keys it doesn't contain are unset
+        let (mut history_visibility, mut membership) =
+            base.unwrap_or((HistoryVisibility::Shared, None));
+        for (current, diff) in diffs.into_iter().rev() {
+            let changes = |key: &(EventType, String)| {
+                diff.removed.contains(key) || diff.added.iter().any(|(added, _)| added == key)
+            };
+            let state = Some(
+                diff.added
+                    .iter()
+                    .filter(|(key, _)| *key == visibility_key || *key == member_key)
+                    .cloned()
+                    .collect::<StateMap<_>>(),
+            );
 
-        let mut unsigned = unsigned.unwrap_or_default();
-        if let Some(state_key) = &state_key {
-            if let Some(prev_pdu) = self.room_state_get(&room_id, &event_type, &state_key)? {
-                unsigned.insert("prev_content".to_owned(), prev_pdu.content);
-                unsigned.insert(
-                    "prev_sender".to_owned(),
-                    serde_json::to_value(prev_pdu.sender).expect("UserId::to_value always works"),
-                );
+            if changes(&visibility_key) {
+                history_visibility = self.history_visibility_in(&state, room_id)?;
+            }
+            if changes(&member_key) {
+                membership = self.membership_in(&state, room_id, user_id)?;
+            }
+            cache
+                .states
+                .insert(current, (history_visibility.clone(), membership.clone()));
+        }
+
+        Ok((history_visibility, membership))
+    }
+
+    /// Checks if the history visibility of the room allows a server to see this event, because
+    /// one of its users could see it.
+    pub fn server_can_see_event(&self, server_name: &ServerName, pdu: &PduEvent) -> Result<bool> {
+        let is_from_server = |user_id: &str| {
+            UserId::try_from(user_id).map_or(false, |user_id| {
+                user_id.server_name().as_str() == server_name.as_str()
+            })
+        };
+
+        // Servers can always see the membership changes of their users
+        if pdu.kind == EventType::RoomMember
+            && pdu.state_key.as_deref().map_or(false, is_from_server)
+        {
+            return Ok(true);
+        }
+
+        // Without a snapshot, only the current state is known
+        let state = self
+            .state_id_of_event(&pdu.event_id)?
+            .map(|id| self.state_snapshot(id))
+            .transpose()?;
+        let history_visibility =
+            Self::history_visibility_before(pdu, self.history_visibility_in(&state, &pdu.room_id)?);
+
+        if let HistoryVisibility::WorldReadable = history_visibility {
+            return Ok(true);
+        }
+
+        let member_ids = match &state {
+            Some(state) => state.keys().cloned().collect::<Vec<_>>(),
+            None => self
+                .room_state_full(&pdu.room_id)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect(),
+        }
+        .into_iter()
+        .filter(|(kind, state_key)| *kind == EventType::RoomMember && is_from_server(state_key))
+        .filter_map(|(_, state_key)| UserId::try_from(state_key).ok());
+
+        let mut memberships = Vec::new();
+        for user_id in member_ids {
+            memberships.push(self.membership_in(&state, &pdu.room_id, &user_id)?);
+        }
+
+        Ok(match history_visibility {
+            HistoryVisibility::Shared => {
+                memberships.contains(&Some(member::MembershipState::Join))
+                    || self
+                        .room_members(&pdu.room_id)
+                        .filter_map(|r| r.ok())
+                        .any(|user_id| user_id.server_name().as_str() == server_name.as_str())
+            }
+            HistoryVisibility::Invited => memberships.iter().any(|membership| {
+                matches!(
+                    membership,
+                    Some(member::MembershipState::Join) | Some(member::MembershipState::Invite)
+                )
+            }),
+            _ => memberships.contains(&Some(member::MembershipState::Join)),
+        })
+    }
+
+    /// Returns the id of the state snapshot after this event, or None for events from before
+    /// state snapshots existed.
+    fn state_id_of_event(&self, event_id: &EventId) -> Result<Option<u64>> {
+        self.eventid_stateid
+            .get(event_id.as_bytes())?
+            .map(|state_id| {
+                utils::u64_from_bytes(&state_id)
+                    .map_err(|_| Error::bad_database("Invalid state id in eventid_stateid."))
+            })
+            .transpose()
+    }
+
+    fn history_visibility_in(
+        &self,
+        state: &Option<StateMap<EventId>>,
+        room_id: &RoomId,
+    ) -> Result<HistoryVisibility> {
+        let event = match state {
+            Some(state) => self.state_get_from_map(state)(&EventType::RoomHistoryVisibility, "")?,
+            None => self.room_state_get(room_id, &EventType::RoomHistoryVisibility, "")?,
+        };
+
+        event.map_or(Ok(HistoryVisibility::Shared), |pdu| {
+            serde_json::from_value::<Raw<history_visibility::HistoryVisibilityEventContent>>(
+                pdu.content,
+            )
+            .expect("Raw::from_value always works")
+            .deserialize()
+            .map(|content| content.history_visibility)
+            .map_err(|_| Error::bad_database("Invalid room history visibility event in database."))
+        })
+    }
+
+    fn membership_in(
+        &self,
+        state: &Option<StateMap<EventId>>,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<member::MembershipState>> {
+        let event = match state {
+            Some(state) => {
+                self.state_get_from_map(state)(&EventType::RoomMember, user_id.as_str())?
+            }
+            None => self.room_state_get(room_id, &EventType::RoomMember, user_id.as_str())?,
+        };
+
+        event
+            .map(|pdu| {
+                serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content)
+                    .expect("Raw::from_value always works")
+                    .deserialize()
+                    .map(|content| content.membership)
+                    .map_err(|_| Error::bad_database("Invalid member event in database."))
+            })
+            .transpose()
+    }
+
+    /// Snapshots contain the state after an event. A history visibility event is judged by the
+    /// visibility before it, which is in its prev_content.
+    fn history_visibility_before(
+        pdu: &PduEvent,
+        history_visibility: HistoryVisibility,
+    ) -> HistoryVisibility {
+        if pdu.kind == EventType::RoomHistoryVisibility && pdu.state_key.as_deref() == Some("") {
+            pdu.unsigned
+                .get("prev_content")
+                .and_then(|content| content.get("history_visibility"))
+                .and_then(|visibility| serde_json::from_value(visibility.clone()).ok())
+                .unwrap_or(HistoryVisibility::Shared)
+        } else {
+            history_visibility
+        }
+    }
+
+    /// Returns the version of a room.
+    pub fn room_version(&self, room_id: &RoomId) -> Result<&'static RoomVersion> {
+        RoomVersion::from_create_content(
+            &self
+                .room_state_get(room_id, &EventType::RoomCreate, "")?
+                .ok_or_else(|| Error::bad_database("Found room without m.room.create event."))?
+                .content,
+        )
+    }
+
+    /// Resolves the state of multiple forks of a room.
+    fn resolve_state(
+        &self,
+        room_version: &RoomVersion,
+        state_sets: &[StateMap<EventId>],
+    ) -> Result<StateMap<EventId>> {
+        stateres::resolve(
+            state_sets,
+            |event_id| self.get_pdu_with_outliers(event_id),
+            |pdu, state| {
+                self.auth_check(
+                    room_version,
+                    &pdu.sender,
+                    &pdu.kind,
+                    pdu.state_key.as_deref(),
+                    &pdu.content,
+                    &pdu.prev_events,
+                    &self.state_get_from_map(state),
+                )
+            },
+        )
+    }
+
+    /// Returns a function that looks up events in a state map.
+    fn state_get_from_map<'a>(
+        &'a self,
+        state: &'a StateMap<EventId>,
+    ) -> impl Fn(&EventType, &str) -> Result<Option<PduEvent>> + 'a {
+        move |kind: &EventType, state_key: &str| {
+            state
+                .get(&(kind.clone(), state_key.to_owned()))
+                .map_or(Ok(None), |event_id| self.get_pdu_with_outliers(event_id))
+        }
+    }
+
+    /// Returns the state before an event that references these prev_events, or None if they are
+    /// exactly the current leaves. The current state already is the resolution of all leaves.
+    fn state_before(
+        &self,
+        room_id: &RoomId,
+        prev_events: &[EventId],
+        room_version: &RoomVersion,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<Option<StateMap<EventId>>> {
+        let leaves = self.get_pdu_leaves(room_id)?;
+        if leaves.iter().all(|leaf| prev_events.contains(leaf))
+            && prev_events.iter().all(|prev| leaves.contains(prev))
+        {
+            return Ok(None);
+        }
+
+        let state_sets = prev_events
+            .iter()
+            .map(|event_id| self.state_at_event(event_id, globals))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some(self.resolve_state(room_version, &state_sets)?))
+    }
+
+    /// Checks if an event is allowed by the auth rules. `state_get` looks up events in the room
+    /// state before the event.
+    #[allow(clippy::blocks_in_if_conditions, clippy::too_many_arguments)]
+    fn auth_check(
+        &self,
+        room_version: &RoomVersion,
+        sender: &UserId,
+        event_type: &EventType,
+        state_key: Option<&str>,
+        content: &serde_json::Value,
+        prev_events: &[EventId],
+        state_get: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>,
+    ) -> Result<bool> {
+        let sender_membership = state_get(&EventType::RoomMember, &sender.to_string())?.map_or(
+            Ok::<_, Error>(member::MembershipState::Leave),
+            |pdu| {
+                Ok(
+                    serde_json::from_value::<Raw<member::MemberEventContent>>(pdu.content)
+                        .expect("Raw::from_value always works.")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid Member event in db."))?
+                        .membership,
+                )
+            },
+        )?;
+
+        let state_key = match state_key {
+            Some(state_key) => state_key,
+            // TODO: auth rules apply to all events, not only those with a state key
+            None => return Ok(sender_membership == member::MembershipState::Join),
+        };
+
+        let power_levels = state_get(&EventType::RoomPowerLevels, "")?.map_or_else(
+            || {
+                Ok::<_, Error>(power_levels::PowerLevelsEventContent {
+                    ban: 50.into(),
+                    events: BTreeMap::new(),
+                    events_default: 0.into(),
+                    invite: 50.into(),
+                    kick: 50.into(),
+                    redact: 50.into(),
+                    state_default: 0.into(),
+                    users: BTreeMap::new(),
+                    users_default: 0.into(),
+                    notifications: ruma::events::room::power_levels::NotificationPowerLevels {
+                        room: 50.into(),
+                    },
+                })
+            },
+            |power_levels| {
+                Ok(
+                    serde_json::from_value::<Raw<PowerLevelsEventContent>>(power_levels.content)
+                        .expect("Raw::from_value always works.")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid PowerLevels event in db."))?,
+                )
+            },
+        )?;
+
+        let sender_power = power_levels.users.get(sender).map_or_else(
+            || {
+                if sender_membership != member::MembershipState::Join {
+                    None
+                } else {
+                    Some(&power_levels.users_default)
+                }
+            },
+            // If it's okay, wrap with Some(_)
+            Some,
+        );
+
+        // Is the event allowed?
+        Ok(match event_type {
+            EventType::RoomMember => {
+                let target_user_id = UserId::try_from(state_key).map_err(|_| {
+                    Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "State key of member event does not contain user id.",
+                    )
+                })?;
+
+                let current_membership =
+                    state_get(&EventType::RoomMember, &target_user_id.to_string())?.map_or(
+                        Ok::<_, Error>(member::MembershipState::Leave),
+                        |pdu| {
+                            Ok(serde_json::from_value::<Raw<member::MemberEventContent>>(
+                                pdu.content,
+                            )
+                            .expect("Raw::from_value always works.")
+                            .deserialize()
+                            .map_err(|_| Error::bad_database("Invalid Member event in db."))?
+                            .membership)
+                        },
+                    )?;
+
+                let target_membership =
+                    serde_json::from_value::<Raw<member::MemberEventContent>>(content.clone())
+                        .expect("Raw::from_value always works.")
+                        .deserialize()
+                        .map_err(|_| Error::bad_database("Invalid Member event in db."))?
+                        .membership;
+
+                let target_power = power_levels.users.get(&target_user_id).map_or_else(
+                    || {
+                        if target_membership != member::MembershipState::Join {
+                            None
+                        } else {
+                            Some(&power_levels.users_default)
+                        }
+                    },
+                    // If it's okay, wrap with Some(_)
+                    Some,
+                );
+
+                let join_rules = state_get(&EventType::RoomJoinRules, "")?.map_or(
+                    Ok::<_, Error>(join_rules::JoinRule::Public),
+                    |pdu| {
+                        Ok(
+                            serde_json::from_value::<Raw<join_rules::JoinRulesEventContent>>(
+                                pdu.content,
+                            )
+                            .expect("Raw::from_value always works.")
+                            .deserialize()
+                            .map_err(|_| {
+                                Error::bad_database("Database contains invalid JoinRules event")
+                            })?
+                            .join_rule,
+                        )
+                    },
+                )?;
+
+                if target_membership == member::MembershipState::Join {
+                    let mut prev_events = prev_events.iter();
+                    let prev_event = self
+                        .get_pdu(prev_events.next().ok_or(Error::BadRequest(
+                            ErrorKind::Unknown,
+                            "Membership can't be the first event",
+                        ))?)?
+                        .ok_or_else(|| Error::bad_database("PDU leaf points to invalid event!"))?;
+                    if prev_event.kind == EventType::RoomCreate && prev_event.prev_events.is_empty()
+                    {
+                        true
+                    } else if sender != &target_user_id {
+                        false
+                    } else if let member::MembershipState::Ban = current_membership {
+                        false
+                    } else {
+                        join_rules == join_rules::JoinRule::Invite
+                            && (current_membership == member::MembershipState::Join
+                                || current_membership == member::MembershipState::Invite)
+                            || join_rules == join_rules::JoinRule::Public
+                    }
+                } else if target_membership == member::MembershipState::Invite {
+                    if let Some(third_party_invite_json) = content.get("third_party_invite") {
+                        if current_membership == member::MembershipState::Ban {
+                            false
+                        } else {
+                            // Make sure the invite has the right shape before looking at the
+                            // signatures
+                            serde_json::from_value::<member::ThirdPartyInvite>(
+                                third_party_invite_json.clone(),
+                            )
+                            .map_err(|_| {
+                                Error::BadRequest(
+                                    ErrorKind::InvalidParam,
+                                    "ThirdPartyInvite is invalid",
+                                )
+                            })?;
+
+                            self.verify_third_party_invite(
+                                &sender,
+                                &target_user_id,
+                                third_party_invite_json,
+                                state_get,
+                            )?
+                        }
+                    } else if sender_membership != member::MembershipState::Join
+                        || current_membership == member::MembershipState::Join
+                        || current_membership == member::MembershipState::Ban
+                    {
+                        false
+                    } else {
+                        sender_power
+                            .filter(|&p| p >= &power_levels.invite)
+                            .is_some()
+                    }
+                } else if target_membership == member::MembershipState::Leave {
+                    if sender == &target_user_id {
+                        current_membership == member::MembershipState::Join
+                            || current_membership == member::MembershipState::Invite
+                    } else if sender_membership != member::MembershipState::Join
+                        || current_membership == member::MembershipState::Ban
+                            && sender_power.filter(|&p| p < &power_levels.ban).is_some()
+                    {
+                        false
+                    } else {
+                        sender_power.filter(|&p| p >= &power_levels.kick).is_some()
+                            && target_power < sender_power
+                    }
+                } else if target_membership == member::MembershipState::Ban {
+                    if sender_membership != member::MembershipState::Join {
+                        false
+                    } else {
+                        sender_power.filter(|&p| p >= &power_levels.ban).is_some()
+                            && target_power < sender_power
+                    }
+                } else {
+                    false
+                }
+            }
+            EventType::RoomCreate => prev_events.is_empty(),
+            EventType::RoomAliases if room_version.special_case_aliases => {
+                state_key == sender.server_name().as_str()
+            }
+            // Not allow any of the following events if the sender is not joined.
+            _ if sender_membership != member::MembershipState::Join => false,
+            EventType::RoomThirdPartyInvite => {
+                sender_power.unwrap_or(&power_levels.users_default) >= &power_levels.invite
+            }
+            EventType::RoomPowerLevels => {
+                sender_power.unwrap_or(&power_levels.users_default) >= &power_levels.state_default
+                    && self.power_levels_change_allowed(
+                        room_version,
+                        sender,
+                        i64::from(*sender_power.unwrap_or(&power_levels.users_default)),
+                        content,
+                        state_get,
+                    )?
+            }
+            _ => {
+                // TODO
+                sender_power.unwrap_or(&power_levels.users_default) >= &power_levels.state_default
+            }
+        })
+    }
+
+    /// Checks that the sender of an m.room.power_levels event only changes levels that are not
+    /// higher than their own.
+    fn power_levels_change_allowed(
+        &self,
+        room_version: &RoomVersion,
+        sender: &UserId,
+        sender_level: i64,
+        new_content: &serde_json::Value,
+        state_get: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>,
+    ) -> Result<bool> {
+        let old_content = match state_get(&EventType::RoomPowerLevels, "")? {
+            Some(pdu) => pdu.content,
+            // The first power levels event can contain anything
+            None => return Ok(true),
+        };
+
+        // Returns None if the level is invalid and Some(None) if it's not set
+        let level = |content: &serde_json::Value, map: Option<&str>, key: &str| {
+            let value = match map {
+                Some(map) => content.get(map).and_then(|map| map.get(key)),
+                None => content.get(key),
+            };
+            match value {
+                Some(value) => room_version.power_level(value).map(Some),
+                None => Some(None),
+            }
+        };
+
+        let map_keys = |map: &str| {
+            let mut keys = Vec::new();
+            for content in &[&old_content, new_content] {
+                for key in content
+                    .get(map)
+                    .and_then(|map| map.as_object())
+                    .into_iter()
+                    .flat_map(|map| map.keys())
+                {
+                    if !keys.contains(key) {
+                        keys.push(key.clone());
+                    }
+                }
+            }
+            keys
+        };
+
+        let mut levels = [
+            "users_default",
+            "events_default",
+            "state_default",
+            "ban",
+            "redact",
+            "kick",
+            "invite",
+        ]
+        .iter()
+        .map(|key| (None, (*key).to_owned()))
+        .collect::<Vec<_>>();
+
+        levels.extend(
+            map_keys("events")
+                .into_iter()
+                .map(|key| (Some("events"), key)),
+        );
+        if room_version.limit_notifications_power_levels {
+            levels.extend(
+                map_keys("notifications")
+                    .into_iter()
+                    .map(|key| (Some("notifications"), key)),
+            );
+        }
+
+        for (map, key) in levels {
+            let (old, new) = match (
+                level(&old_content, map, &key),
+                level(new_content, map, &key),
+            ) {
+                (Some(old), Some(new)) => (old, new),
+                _ => return Ok(false),
+            };
+
+            if old != new
+                && (old.map_or(false, |old| old > sender_level)
+                    || new.map_or(false, |new| new > sender_level))
+            {
+                return Ok(false);
+            }
+        }
+
+        for user in map_keys("users") {
+            let (old, new) = match (
+                level(&old_content, Some("users"), &user),
+                level(new_content, Some("users"), &user),
+            ) {
+                (Some(old), Some(new)) => (old, new),
+                _ => return Ok(false),
+            };
+
+            if old == new {
+                continue;
+            }
+
+            // Users can't change the level of others with the same level, but they can demote
+            // themselves
+            if user != sender.as_str() && old.map_or(false, |old| old >= sender_level)
+                || old.map_or(false, |old| old > sender_level)
+                || new.map_or(false, |new| new > sender_level)
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the ids of the state events that authorize an event.
+    fn auth_events(
+        &self,
+        sender: &UserId,
+        event_type: &EventType,
+        state_key: Option<&str>,
+        content: &serde_json::Value,
+        state_get: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>,
+    ) -> Result<Vec<EventId>> {
+        let mut keys = vec![
+            (EventType::RoomCreate, "".to_owned()),
+            (EventType::RoomPowerLevels, "".to_owned()),
+            (EventType::RoomMember, sender.to_string()),
+        ];
+
+        if event_type == &EventType::RoomMember {
+            if let Some(state_key) = state_key {
+                keys.push((EventType::RoomMember, state_key.to_owned()));
+            }
+
+            if let Some("join") | Some("invite") =
+                content.get("membership").and_then(|m| m.as_str())
+            {
+                keys.push((EventType::RoomJoinRules, "".to_owned()));
+            }
+
+            if let Some(token) = content
+                .get("third_party_invite")
+                .and_then(|invite| invite.get("signed")?.get("token")?.as_str())
+            {
+                keys.push((EventType::RoomThirdPartyInvite, token.to_owned()));
+            }
+        }
+
+        let mut auth_events = Vec::new();
+        for (kind, state_key) in keys {
+            if let Some(pdu) = state_get(&kind, &state_key)? {
+                if !auth_events.contains(&pdu.event_id) {
+                    auth_events.push(pdu.event_id);
+                }
+            }
+        }
+
+        Ok(auth_events)
+    }
+
+    /// Creates a new persisted data unit and adds it to a room.
+    ///
+    /// Only one event is appended to a room at a time, so two events never reference the same
+    /// leaves by accident.
+    pub fn append_pdu(
+        &self,
+        pdu_builder: PduBuilder,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<EventId> {
+        let mutex = self.room_mutex(&pdu_builder.room_id);
+        let _lock = mutex.lock().expect("room mutex is never poisoned");
+
+        self.append_pdu_locked(pdu_builder, globals, account_data)
+    }
+
+    /// Like `append_pdu`, but the caller has to hold the room's mutex already.
+    fn append_pdu_locked(
+        &self,
+        pdu_builder: PduBuilder,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<EventId> {
+        let PduBuilder {
+            room_id,
+            sender,
+            event_type,
+            content,
+            unsigned,
+            state_key,
+            redacts,
+        } = pdu_builder;
+
+        // Reference the deepest leaves, the others will be merged by later events
+        let mut prev_events = self
+            .get_pdu_leaves(&room_id)?
+            .into_iter()
+            .map(|event_id| {
+                let depth = self
+                    .get_pdu(&event_id)?
+                    .map_or(0_u64, |pdu| pdu.depth.into());
+                Ok((depth, event_id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        prev_events.sort_by(|(a, _), (b, _)| b.cmp(a));
+        prev_events.truncate(MAX_PREV_EVENTS);
+        let prev_events = prev_events
+            .into_iter()
+            .map(|(_, event_id)| event_id)
+            .collect::<Vec<_>>();
+
+        let room_version = if event_type == EventType::RoomCreate {
+            RoomVersion::from_create_content(&content)?
+        } else {
+            self.room_version(&room_id)?
+        };
+
+        let state_before = self.state_before(&room_id, &prev_events, room_version, globals)?;
+        let state_get = |kind: &EventType, state_key: &str| match &state_before {
+            Some(state) => self.state_get_from_map(state)(kind, state_key),
+            None => self.room_state_get(&room_id, kind, state_key),
+        };
+
+        // Don't allow encryption events when it's disabled
+        if event_type == EventType::RoomEncryption && globals.encryption_disabled() {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Encryption is disabled on this server.",
+            ));
+        }
+
+        // Is the event authorized?
+        if !self.auth_check(
+            room_version,
+            &sender,
+            &event_type,
+            state_key.as_deref(),
+            &content,
+            &prev_events,
+            &state_get,
+        )? {
+            error!("Unauthorized");
+            // Not authorized
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Event is not authorized",
+            ));
+        }
+
+        let auth_events = self.auth_events(
+            &sender,
+            &event_type,
+            state_key.as_deref(),
+            &content,
+            &state_get,
+        )?;
+
+        // Our depth is the maximum depth of prev_events + 1
+        let depth = prev_events
+            .iter()
+            .filter_map(|event_id| Some(self.get_pdu_json(event_id).ok()??.get("depth")?.as_u64()?))
+            .max()
+            .unwrap_or(0_u64)
+            + 1;
+
+        let mut unsigned = unsigned.unwrap_or_default();
+        if let Some(state_key) = &state_key {
+            if let Some(prev_pdu) = state_get(&event_type, state_key)? {
+                unsigned.insert("prev_content".to_owned(), prev_pdu.content);
+                unsigned.insert(
+                    "prev_sender".to_owned(),
+                    serde_json::to_value(prev_pdu.sender).expect("UserId::to_value always works"),
+                );
+            }
+        }
+
+        let mut pdu = PduEvent {
+            event_id: EventId::try_from("$thiswillbefilledinlater").expect("we know this is valid"),
+            room_id: room_id.clone(),
+            sender: sender.clone(),
+            origin: globals.server_name().to_owned(),
+            origin_server_ts: utils::millis_since_unix_epoch()
+                .try_into()
+                .expect("time is valid"),
+            kind: event_type,
+            content,
+            state_key,
+            prev_events,
+            depth: depth
+                .try_into()
+                .map_err(|_| Error::bad_database("Depth is invalid"))?,
+            auth_events,
+            redacts,
+            unsigned,
+            hashes: ruma::events::pdu::EventHash {
+                sha256: "aaa".to_owned(),
+            },
+            signatures: HashMap::new(),
+        };
+
+        // Generate event id
+        pdu.event_id = room_version
+            .event_id(&serde_json::to_value(&pdu).expect("event is valid, we just created it"))?;
+
+        let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
+        ruma::signatures::hash_and_sign_event(
+            globals.server_name().as_str(),
+            globals.keypair(),
+            &mut pdu_json,
+        )
+        .expect("event is valid, we just created it");
+
+        let index = self.store_pdu(
+            &pdu,
+            &pdu_json,
+            state_before,
+            room_version,
+            globals,
+            account_data,
+        )?;
+
+        self.edus
+            .private_read_set(&room_id, &sender, index, &globals)?;
+        self.reset_notification_counts(&sender, &room_id)?;
+
+        Ok(pdu.event_id)
+    }
+
+    /// Adds an event we received over federation to a room.
+    ///
+    /// The event may reference any number of leaves, the state before the event is resolved from
+    /// the state after each of them.
+    ///
+    /// The caller has to check the signatures and content hash of the event.
+    pub fn append_remote_pdu(
+        &self,
+        mut pdu_json: serde_json::Value,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<EventId> {
+        let event_id = self.remote_event_id(&pdu_json)?;
+
+        pdu_json
+            .as_object_mut()
+            .ok_or(Error::BadRequest(ErrorKind::InvalidParam, "Invalid PDU."))?
+            .insert("event_id".to_owned(), event_id.to_string().into());
+
+        let pdu = serde_json::from_value::<PduEvent>(pdu_json.clone())
+            .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid PDU."))?;
+
+        let room_version = self.room_version(&pdu.room_id)?;
+
+        let mutex = self.room_mutex(&pdu.room_id);
+        let _lock = mutex.lock().expect("room mutex is never poisoned");
+
+        // We already know this event
+        if self.get_pdu_id(&event_id)?.is_some() {
+            return Ok(event_id);
+        }
+        match self.pdu_status(&event_id)? {
+            Some(PduStatus::Rejected) => {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Event is not authorized",
+                ))
+            }
+            Some(PduStatus::SoftFailed) => return Ok(event_id),
+            None => {}
+        }
+
+        // TODO: Fetch missing prev_events and auth_events
+        let mut prev_events = Vec::new();
+        for prev_event in &pdu.prev_events {
+            if self.get_pdu_id(prev_event)?.is_some()
+                || self.pdu_status(prev_event)? == Some(PduStatus::SoftFailed)
+            {
+                prev_events.push(prev_event.clone());
+            }
+        }
+
+        if prev_events.is_empty() {
+            return Err(Error::BadRequest(
+                ErrorKind::Unknown,
+                "PDU does not reference any known events.",
+            ));
+        }
+
+        // 1. The event has to be allowed by its own auth events
+        let mut rejected = false;
+        let mut auth_state = StateMap::new();
+        for auth_event in &pdu.auth_events {
+            if self.pdu_status(auth_event)? == Some(PduStatus::Rejected) {
+                rejected = true;
+            }
+
+            let auth_pdu = self
+                .get_pdu_with_outliers(auth_event)?
+                .ok_or(Error::BadRequest(
+                    ErrorKind::Unknown,
+                    "PDU references unknown auth events.",
+                ))?;
+
+            if let Some(state_key) = auth_pdu.state_key {
+                auth_state.insert((auth_pdu.kind, state_key), auth_pdu.event_id);
+            }
+        }
+
+        let auth_check = |state_get: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>| {
+            self.auth_check(
+                room_version,
+                &pdu.sender,
+                &pdu.kind,
+                pdu.state_key.as_deref(),
+                &pdu.content,
+                &pdu.prev_events,
+                state_get,
+            )
+        };
+
+        rejected = rejected || !auth_check(&self.state_get_from_map(&auth_state))?;
+
+        // 2. The event has to be allowed by the state before the event
+        let state_before = self.state_before(&pdu.room_id, &prev_events, room_version, globals)?;
+        if !rejected {
+            if let Some(state) = &state_before {
+                rejected = !auth_check(&self.state_get_from_map(state))?;
+            }
+        }
+
+        // 3. Events that are not allowed by the current state are soft failed
+        let current_state_get =
+            |kind: &EventType, state_key: &str| self.room_state_get(&pdu.room_id, kind, state_key);
+        let status = if rejected {
+            Some(PduStatus::Rejected)
+        } else if !auth_check(&current_state_get)? {
+            Some(PduStatus::SoftFailed)
+        } else {
+            None
+        };
+
+        match status {
+            None => {
+                self.store_pdu(
+                    &pdu,
+                    &pdu_json,
+                    state_before,
+                    room_version,
+                    globals,
+                    account_data,
+                )?;
+                Ok(event_id)
+            }
+            Some(status) => {
+                self.store_outlier(&pdu, &pdu_json, status, state_before, globals)?;
+
+                if status == PduStatus::Rejected {
+                    Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "Event is not authorized",
+                    ))
+                } else {
+                    Ok(event_id)
+                }
+            }
+        }
+    }
+
+    /// Stores a rejected or soft failed event. It does not show up in timelines and it's never a
+    /// leaf, but other servers can still request it.
+    fn store_outlier(
+        &self,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
+        status: PduStatus,
+        state_before: Option<StateMap<EventId>>,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<()> {
+        let pdu_json_string = pdu_json.to_string();
+
+        // Later events may reference soft failed events, so they need a state
+        let (state_id, snapshot) = match status {
+            PduStatus::Rejected => (None, None),
+            PduStatus::SoftFailed => {
+                let (state_id, snapshot) = self.state_after(pdu, state_before, globals)?;
+                (
+                    Some(state_id),
+                    snapshot.map(|(depth, diff)| Self::serialize_state_diff(depth, &diff)),
+                )
+            }
+        };
+
+        let status: &[u8] = match status {
+            PduStatus::Rejected => b"rejected",
+            PduStatus::SoftFailed => b"softfailed",
+        };
+
+        (
+            &self.eventid_outlierpdu,
+            &self.eventid_status,
+            &self.eventid_stateid,
+            &self.stateid_statediff,
+        )
+            .transaction(
+                |(eventid_outlierpdu, eventid_status, eventid_stateid, stateid_statediff)| {
+                    eventid_outlierpdu.insert(pdu.event_id.as_bytes(), &*pdu_json_string)?;
+                    eventid_status.insert(pdu.event_id.as_bytes(), status)?;
+
+                    if let Some(state_id) = state_id {
+                        if let Some(snapshot) = &snapshot {
+                            stateid_statediff.insert(&state_id.to_be_bytes(), &**snapshot)?;
+                        }
+                        eventid_stateid.insert(pdu.event_id.as_bytes(), &state_id.to_be_bytes())?;
+                    }
+
+                    Ok::<_, ConflictableTransactionError>(())
+                },
+            )?;
+
+        Ok(())
+    }
+
+    /// Returns the id of the state snapshot after this event and the depth and diff of the
+    /// snapshot if it's a new one that still has to be saved. New snapshots are diffs against the
+    /// current state, events that don't change it share its snapshot.
+    fn state_after(
+        &self,
+        pdu: &PduEvent,
+        state_before: Option<StateMap<EventId>>,
+        globals: &super::globals::Globals<'_>,
+    ) -> Result<(u64, Option<(u32, StateDiff)>)> {
+        let current_state_id = self.current_state_id(&pdu.room_id, globals)?;
+        let own_state = pdu
+            .state_key
+            .as_ref()
+            .map(|state_key| ((pdu.kind.clone(), state_key.clone()), pdu.event_id.clone()));
+
+        let diff = match state_before {
+            // Following the current state, only the event itself can change it
+            None => match own_state {
+                Some(own_state) => {
+                    Some(self.state_diff(current_state_id, vec![own_state], Vec::new())?)
+                }
+                None => None,
+            },
+            Some(mut state) => {
+                state.extend(own_state);
+                self.state_diff_to(
+                    current_state_id,
+                    &self.state_snapshot(current_state_id)?,
+                    &state,
+                )?
+            }
+        };
+
+        Ok(match diff {
+            Some(diff) => (globals.next_count()?, Some(diff)),
+            None => (current_state_id, None),
+        })
+    }
+
+    /// Calculates the id of an event we received over federation. The event id is not part of
+    /// the event, its format depends on the room version.
+    pub fn remote_event_id(&self, pdu_json: &serde_json::Value) -> Result<EventId> {
+        self.remote_room_version(pdu_json)?.event_id(pdu_json)
+    }
+
+    /// Redacts an event we received over federation whose content hash does not match, so only
+    /// the parts covered by the signatures are kept.
+    pub fn redact_remote_pdu(&self, pdu_json: &mut serde_json::Value) -> Result<()> {
+        let room_version = self.remote_room_version(pdu_json)?;
+        let kind = EventType::from(pdu_json.get("type").and_then(|kind| kind.as_str()).ok_or(
+            Error::BadRequest(ErrorKind::InvalidParam, "PDU has no type."),
+        )?);
+        let allowed = room_version.redaction_allowed_keys(&kind);
+
+        let pdu = pdu_json
+            .as_object_mut()
+            .ok_or(Error::BadRequest(ErrorKind::InvalidParam, "Invalid PDU."))?;
+        pdu.remove("unsigned");
+        if let Some(content) = pdu.get_mut("content").and_then(|c| c.as_object_mut()) {
+            let mut new_content = serde_json::Map::new();
+            for key in allowed {
+                if let Some(value) = content.remove(*key) {
+                    new_content.insert((*key).to_owned(), value);
+                }
+            }
+            *content = new_content;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the room version of the room of an event we received over federation.
+    fn remote_room_version(&self, pdu_json: &serde_json::Value) -> Result<&'static RoomVersion> {
+        let room_id = pdu_json
+            .get("room_id")
+            .and_then(|room_id| room_id.as_str())
+            .and_then(|room_id| RoomId::try_from(room_id).ok())
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "PDU has no valid room id.",
+            ))?;
+
+        if !self.exists(&room_id)? {
+            return Err(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Room is unknown to this server.",
+            ));
+        }
+
+        self.room_version(&room_id)
+    }
+
+    /// Persists an event that passed the auth checks and updates the leaves and the room state.
+    /// `state_before` is None if the event references all leaves and therefore follows the
+    /// current state.
+    ///
+    /// Returns the count of the new pdu.
+    fn store_pdu(
+        &self,
+        pdu: &PduEvent,
+        pdu_json: &serde_json::Value,
+        state_before: Option<StateMap<EventId>>,
+        room_version: &RoomVersion,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<u64> {
+        let room_id = &pdu.room_id;
+
+        // Increment the last index and use that
+        // This is also the next_batch/since value
+        let index = globals.next_count()?;
+
+        let mut pdu_id = room_id.to_string().as_bytes().to_vec();
+        pdu_id.push(0xff);
+        pdu_id.extend_from_slice(&index.to_be_bytes());
+
+        let pdu_json_string = pdu_json.to_string();
+
+        let (state_id, snapshot) = self.state_after(pdu, state_before, globals)?;
+        let mut snapshots = snapshot
+            .map(|state| (state_id, state))
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let mut leaves = self
+            .get_pdu_leaves(room_id)?
+            .into_iter()
+            .filter(|leaf| !pdu.prev_events.contains(leaf))
+            .collect::<Vec<_>>();
+
+        // The current state is the resolved state of all leaves
+        let (room_state_id, room_state) = if leaves.is_empty() {
+            (state_id, None)
+        } else {
+            let mut state_sets = leaves
+                .iter()
+                .map(|leaf| self.state_at_event(leaf, globals))
+                .collect::<Result<Vec<_>>>()?;
+            let own_state = match snapshots.first() {
+                Some((_, (_, diff))) => self.apply_state_diff(diff)?,
+                None => self.state_snapshot(state_id)?,
+            };
+            state_sets.push(own_state);
+
+            let room_state = self.resolve_state(room_version, &state_sets)?;
+            let room_state_id = if Some(&room_state) == state_sets.last() {
+                state_id
+            } else {
+                let current_state_id = self.current_state_id(room_id, globals)?;
+                match self.state_diff_to(
+                    current_state_id,
+                    &self.state_snapshot(current_state_id)?,
+                    &room_state,
+                )? {
+                    Some(diff) => {
+                        let room_state_id = globals.next_count()?;
+                        snapshots.push((room_state_id, diff));
+                        room_state_id
+                    }
+                    None => current_state_id,
+                }
+            };
+            (room_state_id, Some(room_state))
+        };
+        leaves.push(pdu.event_id.clone());
+
+        // Changes to the roomstateid_pdu tree
+        let mut changed_state = Vec::new();
+        let mut removed_state = Vec::new();
+        match room_state {
+            None => {
+                if pdu.state_key.is_some() {
+                    changed_state.push(pdu.clone());
+                }
+            }
+            Some(room_state) => {
+                let old_state = self.room_state_full(room_id)?;
+                for (key, old_pdu) in &old_state {
+                    if room_state.get(key) != Some(&old_pdu.event_id) {
+                        removed_state.push(key.clone());
+                    }
+                }
+                for (key, event_id) in &room_state {
+                    if old_state.get(key).map(|pdu| &pdu.event_id) != Some(event_id) {
+                        changed_state.push(if event_id == &pdu.event_id {
+                            pdu.clone()
+                        } else {
+                            self.get_pdu_with_outliers(event_id)?.ok_or_else(|| {
+                                Error::bad_database("Resolved state contains unknown event.")
+                            })?
+                        });
+                    }
+                }
+            }
+        }
+
+        let roomstate_id = |kind: &EventType, state_key: &str| {
+            let mut key = room_id.to_string().as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(kind.to_string().as_bytes());
+            key.push(0xff);
+            key.extend_from_slice(state_key.as_bytes());
+            key
+        };
+
+        let mut state_updates = removed_state
+            .iter()
+            .map(|(kind, state_key)| (roomstate_id(kind, state_key), None))
+            .collect::<Vec<_>>();
+        for changed in &changed_state {
+            let state_key = changed.state_key.as_ref().ok_or_else(|| {
+                Error::bad_database("Room state contains event without state_key.")
+            })?;
+            let json = if changed.event_id == pdu.event_id {
+                pdu_json_string.clone()
+            } else {
+                self.get_pdu_json_with_outliers(&changed.event_id)?
+                    .ok_or_else(|| Error::bad_database("Resolved state contains unknown event."))?
+                    .to_string()
+            };
+            state_updates.push((roomstate_id(&changed.kind, state_key), Some(json)));
+        }
+
+        let snapshots = snapshots
+            .iter()
+            .map(|(state_id, (depth, diff))| {
+                (
+                    state_id.to_be_bytes(),
+                    Self::serialize_state_diff(*depth, diff),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut leaf_prefix = room_id.to_string().as_bytes().to_vec();
+        leaf_prefix.push(0xff);
+
+        let mut token_ids = Vec::new();
+        if pdu.kind == EventType::RoomMessage {
+            if let Some(body) = pdu.content.get("body").and_then(|b| b.as_str()) {
+                for word in body
+                    .split_terminator(|c: char| !c.is_alphanumeric())
+                    .map(str::to_lowercase)
+                {
+                    let mut key = room_id.to_string().as_bytes().to_vec();
+                    key.push(0xff);
+                    key.extend_from_slice(word.as_bytes());
+                    key.push(0xff);
+                    key.extend_from_slice(&pdu_id);
+                    token_ids.push(key);
+                }
+            }
+        }
+
+        // Write everything at once, so a crash can't leave the room half-updated
+        (
+            &self.pduid_pdu,
+            &self.eventid_pduid,
+            &self.roomstateid_pdu,
+            &self.roomid_pduleaves,
+            &self.tokenids,
+            &self.eventid_stateid,
+            &self.stateid_statediff,
+            &self.roomid_stateid,
+        )
+            .transaction(
+                |(
+                    pduid_pdu,
+                    eventid_pduid,
+                    roomstateid_pdu,
+                    roomid_pduleaves,
+                    tokenids,
+                    eventid_stateid,
+                    stateid_statediff,
+                    roomid_stateid,
+                )| {
+                    pduid_pdu.insert(&*pdu_id, &*pdu_json_string)?;
+                    eventid_pduid.insert(pdu.event_id.as_bytes(), &*pdu_id)?;
+
+                    for (state_id, snapshot) in &snapshots {
+                        stateid_statediff.insert(state_id, &**snapshot)?;
+                    }
+                    eventid_stateid.insert(pdu.event_id.as_bytes(), &state_id.to_be_bytes())?;
+                    roomid_stateid
+                        .insert(room_id.to_string().as_bytes(), &room_state_id.to_be_bytes())?;
+
+                    for (key, json) in &state_updates {
+                        match json {
+                            Some(json) => roomstateid_pdu.insert(&**key, &**json)?,
+                            None => roomstateid_pdu.remove(&**key)?,
+                        };
+                    }
+
+                    // The new event replaces the leaves it references
+                    for leaf in &pdu.prev_events {
+                        let mut key = leaf_prefix.clone();
+                        key.extend_from_slice(leaf.as_bytes());
+                        roomid_pduleaves.remove(key)?;
+                    }
+                    let mut key = leaf_prefix.clone();
+                    key.extend_from_slice(pdu.event_id.as_bytes());
+                    roomid_pduleaves.insert(key, pdu.event_id.as_bytes())?;
+
+                    for token_id in &token_ids {
+                        tokenids.insert(&**token_id, &[])?;
+                    }
+
+                    Ok::<_, ConflictableTransactionError>(())
+                },
+            )?;
+
+        if pdu.kind == EventType::RoomRedaction {
+            if let Some(redact_id) = &pdu.redacts {
+                self.redact_pdu(&redact_id, &pdu)?;
+            }
+        }
+
+        // Update our membership info for every member event that changed the room state, we
+        // do this here incase a user is invited and immediately leaves we need the DB to record
+        // the invite event for auth
+        for member_pdu in changed_state
+            .into_iter()
+            .filter(|pdu| pdu.kind == EventType::RoomMember)
+        {
+            if let Some(state_key) = &member_pdu.state_key {
+                let target_user_id = UserId::try_from(&**state_key)
+                    .map_err(|_| Error::bad_database("Invalid state key in member event."))?;
+                self.update_membership(
+                    room_id,
+                    &target_user_id,
+                    serde_json::from_value::<member::MemberEventContent>(member_pdu.content)
+                        .map_err(|_| {
+                            Error::BadRequest(
+                                ErrorKind::InvalidParam,
+                                "Invalid member event content.",
+                            )
+                        })?,
+                    &member_pdu.sender,
+                    account_data,
+                    globals,
+                )?;
+
+                // The room lists of the target user changed
+                globals.notifier().notify_user(&target_user_id);
+            }
+        }
+
+        // Push rules are evaluated in the background, so appending doesn't wait for them
+        self.push_queue.enqueue(index, &pdu_id)?;
+
+        globals.notifier().notify_room(room_id);
+
+        Ok(index)
+    }
+
+    /// Evaluates the push rules of every local member of the room and of a local user invited by
+    /// the event, stores the notifications they produce and queues them for their pushers. The
+    /// push queue handler calls this after the event was appended.
+    #[allow(clippy::too_many_arguments)]
+    fn evaluate_push_rules(
+        &self,
+        pdu: &PduEvent,
+        index: u64,
+        server_name: &ServerName,
+        users: &super::users::Users,
+        account_data: &super::account_data::AccountData,
+        emails_enabled: bool,
+        cache: &mut PatternCache,
+    ) -> Result<()> {
+        let power_levels = self
+            .room_state_get(&pdu.room_id, &EventType::RoomPowerLevels, "")?
+            .map(|power_levels| power_levels.content);
+        let member_count = self.room_members(&pdu.room_id).count() as u64;
+        let room_name = self
+            .room_state_get(&pdu.room_id, &EventType::RoomName, "")?
+            .and_then(|name| Some(name.content.get("name")?.as_str()?.to_owned()));
+        let sender_display_name = self
+            .room_state_get(&pdu.room_id, &EventType::RoomMember, pdu.sender.as_str())?
+            .and_then(|member| Some(member.content.get("displayname")?.as_str()?.to_owned()));
+        let event = serde_json::to_value(pdu).expect("pdus can be serialized");
+
+        let mut user_ids = self
+            .room_members(&pdu.room_id)
+            .collect::<Result<Vec<_>>>()?;
+
+        // Invitees are not members, but should be notified about their invite
+        if pdu.kind == EventType::RoomMember
+            && pdu.content.get("membership").and_then(|m| m.as_str()) == Some("invite")
+        {
+            if let Some(invitee) = pdu
+                .state_key
+                .as_deref()
+                .and_then(|state_key| UserId::try_from(state_key).ok())
+            {
+                if !user_ids.contains(&invitee) {
+                    user_ids.push(invitee);
+                }
+            }
+        }
+
+        for user_id in user_ids {
+            // Nobody is notified about their own events, remote users are notified by their server
+            if user_id == pdu.sender || user_id.server_name().as_str() != server_name.as_str() {
+                continue;
+            }
+
+            let ruleset = match account_data.get::<serde_json::Value>(
+                None,
+                &user_id,
+                EventType::PushRules,
+            )? {
+                Some(mut event) => event["content"]["global"].take(),
+                None => serde_json::to_value(push_rules::default_pushrules(&user_id))
+                    .expect("rulesets can be serialized"),
+            };
+
+            let display_name = self
+                .room_state_get(&pdu.room_id, &EventType::RoomMember, user_id.as_str())?
+                .and_then(|member| {
+                    member
+                        .content
+                        .get("displayname")?
+                        .as_str()
+                        .map(str::to_owned)
+                });
+
+            let actions = push_rules::evaluate(
+                &ruleset,
+                pdu,
+                &event,
+                &PushContext {
+                    display_name: display_name.as_deref(),
+                    member_count,
+                    power_levels: power_levels.as_ref(),
+                },
+                cache,
+            );
+
+            if actions.notify {
+                let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+                userroom_id.push(0xff);
+                userroom_id.extend_from_slice(pdu.room_id.to_string().as_bytes());
+
+                // The user could have read the event before it was evaluated
+                let unread = self
+                    .edus
+                    .private_read_get(&pdu.room_id, &user_id)?
+                    .map_or(true, |last_read| index > last_read);
+
+                if unread {
+                    self.userroomid_notificationcount
+                        .update_and_fetch(&userroom_id, utils::increment)?;
+                    if actions.highlight {
+                        self.userroomid_highlightcount
+                            .update_and_fetch(&userroom_id, utils::increment)?;
+                    }
+
+                    let mut userroomcount = userroom_id.clone();
+                    userroomcount.push(0xff);
+                    userroomcount.extend_from_slice(&index.to_be_bytes());
+                    self.userroomcount_highlight
+                        .insert(userroomcount, &[actions.highlight as u8])?;
+                }
+
+                let mut notification = json!({
+                    "event_id": pdu.event_id,
+                    "room_id": pdu.room_id,
+                    "type": pdu.kind,
+                    "sender": pdu.sender,
+                    "content": pdu.content,
+                    "prio": if actions.highlight { "high" } else { "low" },
+                    "counts": {
+                        "unread": self.unread_notification_count(&user_id)?,
+                    },
+                });
+                if let Some(room_name) = &room_name {
+                    notification["room_name"] = json!(room_name);
+                }
+                if let Some(sender_display_name) = &sender_display_name {
+                    notification["sender_display_name"] = json!(sender_display_name);
+                }
+
+                let mut tweaks = json!({ "highlight": actions.highlight });
+                if let Some(sound) = &actions.sound {
+                    tweaks["sound"] = json!(sound);
+                }
+
+                self.push_queue.queue_pushes(
+                    users,
+                    &user_id,
+                    index,
+                    &notification,
+                    &tweaks,
+                    emails_enabled,
+                )?;
+
+                let mut key = user_id.to_string().as_bytes().to_vec();
+                key.push(0xff);
+                key.extend_from_slice(&index.to_be_bytes());
+
+                self.usercount_notification.insert(
+                    key,
+                    &*serde_json::to_string(&Notification {
+                        room_id: pdu.room_id.clone(),
+                        event_id: pdu.event_id.clone(),
+                        actions,
+                        ts: utils::millis_since_unix_epoch(),
+                    })
+                    .expect("notifications can be serialized"),
+                )?;
             }
         }
 
-        let mut pdu = PduEvent {
-            event_id: EventId::try_from("$thiswillbefilledinlater").expect("we know this is valid"),
-            room_id: room_id.clone(),
-            sender: sender.clone(),
-            origin: globals.server_name().to_owned(),
-            origin_server_ts: utils::millis_since_unix_epoch()
-                .try_into()
-                .expect("time is valid"),
-            kind: event_type.clone(),
-            content: content.clone(),
-            state_key: state_key.clone(),
-            prev_events,
-            depth: depth
-                .try_into()
-                .map_err(|_| Error::bad_database("Depth is invalid"))?,
-            auth_events: Vec::new(),
-            redacts: redacts.clone(),
-            unsigned,
-            hashes: ruma::events::pdu::EventHash {
-                sha256: "aaa".to_owned(),
-            },
-            signatures: HashMap::new(),
-        };
-
-        // Generate event id
-        pdu.event_id = EventId::try_from(&*format!(
-            "${}",
-            ruma::signatures::reference_hash(
-                &serde_json::to_value(&pdu).expect("event is valid, we just created it")
-            )
-            .expect("ruma can calculate reference hashes")
-        ))
-        .expect("ruma's reference hashes are valid event ids");
+        Ok(())
+    }
 
-        let mut pdu_json = serde_json::to_value(&pdu).expect("event is valid, we just created it");
-        ruma::signatures::hash_and_sign_event(
-            globals.server_name().as_str(),
-            globals.keypair(),
-            &mut pdu_json,
-        )
-        .expect("event is valid, we just created it");
+    /// Checks the `third_party_invite` of an invite event against the `m.room.third_party_invite`
+    /// event it references. The signed part has to be signed with one of the public keys of the
+    /// identity server that created the invite.
+    fn verify_third_party_invite(
+        &self,
+        sender: &UserId,
+        target_user_id: &UserId,
+        third_party_invite: &serde_json::Value,
+        state_get: &dyn Fn(&EventType, &str) -> Result<Option<PduEvent>>,
+    ) -> Result<bool> {
+        let signed = match third_party_invite.get("signed") {
+            Some(signed) => signed,
+            None => return Ok(false),
+        };
 
-        self.replace_pdu_leaves(&room_id, &pdu.event_id)?;
+        let (mxid, token) = match (
+            signed.get("mxid").and_then(|mxid| mxid.as_str()),
+            signed.get("token").and_then(|token| token.as_str()),
+        ) {
+            (Some(mxid), Some(token)) => (mxid, token),
+            _ => return Ok(false),
+        };
 
-        // Increment the last index and use that
-        // This is also the next_batch/since value
-        let index = globals.next_count()?;
+        if mxid != target_user_id.as_str() {
+            return Ok(false);
+        }
 
-        let mut pdu_id = room_id.to_string().as_bytes().to_vec();
-        pdu_id.push(0xff);
-        pdu_id.extend_from_slice(&index.to_be_bytes());
+        let invite_event = match state_get(&EventType::RoomThirdPartyInvite, token)? {
+            Some(pdu) => pdu,
+            None => return Ok(false),
+        };
 
-        self.pduid_pdu.insert(&pdu_id, &*pdu_json.to_string())?;
+        if &invite_event.sender != sender {
+            return Ok(false);
+        }
 
-        self.eventid_pduid
-            .insert(pdu.event_id.to_string(), pdu_id.clone())?;
+        // The invite has one `public_key` and a list of `public_keys`, any of them is fine
+        let public_keys = invite_event
+            .content
+            .get("public_key")
+            .and_then(|key| key.as_str())
+            .into_iter()
+            .chain(
+                invite_event
+                    .content
+                    .get("public_keys")
+                    .and_then(|keys| keys.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|key| key.get("public_key")?.as_str()),
+            )
+            .collect::<Vec<_>>();
 
-        if let Some(state_key) = &pdu.state_key {
-            let mut key = room_id.to_string().as_bytes().to_vec();
-            key.push(0xff);
-            key.extend_from_slice(pdu.kind.to_string().as_bytes());
-            key.push(0xff);
-            key.extend_from_slice(state_key.as_bytes());
-            self.roomstateid_pdu.insert(key, &*pdu_json.to_string())?;
-        }
+        let signatures = match signed.get("signatures").and_then(|s| s.as_object()) {
+            Some(signatures) => signatures,
+            None => return Ok(false),
+        };
 
-        match event_type {
-            EventType::RoomRedaction => {
-                if let Some(redact_id) = &redacts {
-                    self.redact_pdu(&redact_id, &pdu)?;
-                }
-            }
-            EventType::RoomMember => {
-                if let Some(state_key) = state_key {
-                    // if the state_key fails
-                    let target_user_id = UserId::try_from(state_key)
-                        .expect("This state_key was previously validated");
-                    // Update our membership info, we do this here incase a user is invited
-                    // and immediately leaves we need the DB to record the invite event for auth
-                    self.update_membership(
-                        &room_id,
-                        &target_user_id,
-                        serde_json::from_value::<member::MemberEventContent>(content).map_err(
-                            |_| {
-                                Error::BadRequest(
-                                    ErrorKind::InvalidParam,
-                                    "Invalid redaction event content.",
-                                )
-                            },
-                        )?,
-                        &sender,
-                        account_data,
-                        globals,
-                    )?;
-                }
-            }
-            EventType::RoomMessage => {
-                if let Some(body) = content.get("body").and_then(|b| b.as_str()) {
-                    for word in body
-                        .split_terminator(|c: char| !c.is_alphanumeric())
-                        .map(str::to_lowercase)
-                    {
-                        let mut key = room_id.to_string().as_bytes().to_vec();
-                        key.push(0xff);
-                        key.extend_from_slice(word.as_bytes());
-                        key.push(0xff);
-                        key.extend_from_slice(&pdu_id);
-                        self.tokenids.insert(key, &[])?;
+        for (server, server_signatures) in signatures {
+            for key_id in server_signatures
+                .as_object()
+                .into_iter()
+                .flat_map(|s| s.keys())
+            {
+                for public_key in &public_keys {
+                    let mut public_key_set = ruma::signatures::PublicKeySet::new();
+                    public_key_set.insert(key_id.clone(), (*public_key).to_owned());
+                    let mut public_key_map = ruma::signatures::PublicKeyMap::new();
+                    public_key_map.insert(server.clone(), public_key_set);
+
+                    if ruma::signatures::verify_json(&public_key_map, signed).is_ok() {
+                        return Ok(true);
                     }
                 }
             }
-            _ => {}
         }
-        self.edus
-            .private_read_set(&room_id, &sender, index, &globals)?;
 
-        Ok(pdu.event_id)
+        Ok(false)
+    }
+
+    /// Turns a signed third party invite into a real invite for the `mxid` in `signed`.
+    ///
+    /// The auth rules in `append_pdu` make sure the signatures match the public keys in the
+    /// `m.room.third_party_invite` event and that `sender` created that event.
+    pub fn exchange_third_party_invite(
+        &self,
+        room_id: &RoomId,
+        sender: &UserId,
+        signed: serde_json::Value,
+        globals: &super::globals::Globals<'_>,
+        account_data: &super::account_data::AccountData,
+    ) -> Result<EventId> {
+        let token =
+            signed
+                .get("token")
+                .and_then(|token| token.as_str())
+                .ok_or(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Signed third party invite has no token.",
+                ))?;
+
+        let mxid = signed
+            .get("mxid")
+            .and_then(|mxid| mxid.as_str())
+            .and_then(|mxid| UserId::try_from(mxid).ok())
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Signed third party invite has no valid mxid.",
+            ))?;
+
+        // We sign the invite on behalf of the sender, so they must be one of our users and be
+        // allowed to invite the target
+        if sender.server_name() != globals.server_name()
+            || !self.auth_check(
+                self.room_version(room_id)?,
+                sender,
+                &EventType::RoomMember,
+                Some(mxid.as_str()),
+                &json!({ "membership": "invite" }),
+                &[],
+                &|kind, state_key| self.room_state_get(room_id, kind, state_key),
+            )?
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Sender of the third party invite is not allowed to invite.",
+            ));
+        }
+
+        let invite_event = self
+            .room_state_get(room_id, &EventType::RoomThirdPartyInvite, token)?
+            .ok_or(Error::BadRequest(
+                ErrorKind::NotFound,
+                "Third party invite not found.",
+            ))?;
+
+        let display_name = invite_event
+            .content
+            .get("display_name")
+            .cloned()
+            .unwrap_or_default();
+
+        self.append_pdu(
+            PduBuilder {
+                room_id: room_id.clone(),
+                sender: sender.clone(),
+                event_type: EventType::RoomMember,
+                content: json!({
+                    "membership": "invite",
+                    "third_party_invite": {
+                        "display_name": display_name,
+                        "signed": signed,
+                    },
+                }),
+                unsigned: None,
+                state_key: Some(mxid.to_string()),
+                redacts: None,
+            },
+            globals,
+            account_data,
+        )
     }
 
     /// Returns an iterator over all PDUs in a room.
@@ -633,18 +2385,20 @@ impl Rooms {
         &self,
         user_id: &UserId,
         room_id: &RoomId,
-    ) -> Result<impl Iterator<Item = Result<PduEvent>>> {
+    ) -> Result<impl Iterator<Item = Result<PduEvent>> + '_> {
         self.pdus_since(user_id, room_id, 0)
     }
 
     /// Returns a double-ended iterator over all events in a room that happened after the event with id `since`
     /// in chronological order.
+    ///
+    /// Events the user is not allowed to see because of the history visibility are skipped.
     pub fn pdus_since(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         since: u64,
-    ) -> Result<impl DoubleEndedIterator<Item = Result<PduEvent>>> {
+    ) -> Result<impl DoubleEndedIterator<Item = Result<PduEvent>> + '_> {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
 
@@ -656,28 +2410,32 @@ impl Rooms {
         last_pdu_id.extend_from_slice(&u64::MAX.to_be_bytes());
 
         let user_id = user_id.clone();
+        let mut cache = VisibilityCache::default();
         Ok(self
             .pduid_pdu
             .range(first_pdu_id..last_pdu_id)
             .filter_map(|r| r.ok())
             .map(move |(_, v)| {
-                let mut pdu = serde_json::from_slice::<PduEvent>(&v)
+                let pdu = serde_json::from_slice::<PduEvent>(&v)
                     .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
-                if pdu.sender != user_id {
-                    pdu.unsigned.remove("transaction_id");
+                if !self.user_can_see_event_cached(&user_id, &pdu, &mut cache)? {
+                    return Ok(None);
                 }
-                Ok(pdu)
-            }))
+                Ok(Some(pdu))
+            })
+            .filter_map(Result::transpose))
     }
 
     /// Returns an iterator over all events and their tokens in a room that happened before the
     /// event with id `until` in reverse-chronological order.
+    ///
+    /// Events the user is not allowed to see because of the history visibility are skipped.
     pub fn pdus_until(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         until: u64,
-    ) -> impl Iterator<Item = Result<(u64, PduEvent)>> {
+    ) -> impl Iterator<Item = Result<(u64, PduEvent)>> + '_ {
         // Create the first part of the full pdu id
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
@@ -689,33 +2447,37 @@ impl Rooms {
 
         let user_id = user_id.clone();
         let prefixlen = prefix.len();
+        let mut cache = VisibilityCache::default();
         self.pduid_pdu
             .range(..current)
             .rev()
             .filter_map(|r| r.ok())
             .take_while(move |(k, _)| k.starts_with(&prefix))
             .map(move |(k, v)| {
-                let mut pdu = serde_json::from_slice::<PduEvent>(&v)
+                let pdu = serde_json::from_slice::<PduEvent>(&v)
                     .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
-                if pdu.sender != user_id {
-                    pdu.unsigned.remove("transaction_id");
+                if !self.user_can_see_event_cached(&user_id, &pdu, &mut cache)? {
+                    return Ok(None);
                 }
-                Ok((
+                Ok(Some((
                     utils::u64_from_bytes(&k[prefixlen..])
                         .map_err(|_| Error::bad_database("Invalid pdu id in db."))?,
                     pdu,
-                ))
+                )))
             })
+            .filter_map(Result::transpose)
     }
 
     /// Returns an iterator over all events and their token in a room that happened after the event
     /// with id `from` in chronological order.
+    ///
+    /// Events the user is not allowed to see because of the history visibility are skipped.
     pub fn pdus_after(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         from: u64,
-    ) -> impl Iterator<Item = Result<(u64, PduEvent)>> {
+    ) -> impl Iterator<Item = Result<(u64, PduEvent)>> + '_ {
         // Create the first part of the full pdu id
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
@@ -727,22 +2489,24 @@ impl Rooms {
 
         let user_id = user_id.clone();
         let prefixlen = prefix.len();
+        let mut cache = VisibilityCache::default();
         self.pduid_pdu
             .range(current..)
             .filter_map(|r| r.ok())
             .take_while(move |(k, _)| k.starts_with(&prefix))
             .map(move |(k, v)| {
-                let mut pdu = serde_json::from_slice::<PduEvent>(&v)
+                let pdu = serde_json::from_slice::<PduEvent>(&v)
                     .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
-                if pdu.sender != user_id {
-                    pdu.unsigned.remove("transaction_id");
+                if !self.user_can_see_event_cached(&user_id, &pdu, &mut cache)? {
+                    return Ok(None);
                 }
-                Ok((
+                Ok(Some((
                     utils::u64_from_bytes(&k[prefixlen..])
                         .map_err(|_| Error::bad_database("Invalid pdu id in db."))?,
                     pdu,
-                ))
+                )))
             })
+            .filter_map(Result::transpose)
     }
 
     /// Replace a PDU with the redacted form.
@@ -751,7 +2515,7 @@ impl Rooms {
             let mut pdu = self
                 .get_pdu_from_id(&pdu_id)?
                 .ok_or_else(|| Error::bad_database("PDU ID points to invalid PDU."))?;
-            pdu.redact(&reason)?;
+            pdu.redact(&reason, self.room_version(&pdu.room_id)?)?;
             self.replace_pdu(&pdu_id, &pdu)?;
             Ok(())
         } else {
@@ -889,7 +2653,8 @@ impl Rooms {
                 if is_ignored {
                     member_content.membership = member::MembershipState::Leave;
 
-                    self.append_pdu(
+                    // We are still holding the room mutex from the invite event
+                    self.append_pdu_locked(
                         PduBuilder {
                             room_id: room_id.clone(),
                             sender: user_id.clone(),
@@ -1233,6 +2998,158 @@ impl Rooms {
             })
     }
 
+    /// Returns the token of the event with which the user left the room, if they left it.
+    pub fn left_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<Option<u64>> {
+        if !self.is_left(user_id, room_id)? {
+            return Ok(None);
+        }
+
+        // While the user is gone, their member event is the leave (or kick or ban)
+        match self.room_state_get(room_id, &EventType::RoomMember, &user_id.to_string())? {
+            Some(pdu) => self.get_pdu_count(&pdu.event_id),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns how many events notified the user in the room since their read marker.
+    pub fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+        Self::userroom_count(&self.userroomid_notificationcount, user_id, room_id)
+    }
+
+    /// Returns how many events highlighted the room for the user since their read marker.
+    pub fn highlight_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+        Self::userroom_count(&self.userroomid_highlightcount, user_id, room_id)
+    }
+
+    /// Returns an iterator over the notifications of the user and their tokens that are older than
+    /// `until` in reverse-chronological order.
+    pub fn notifications(
+        &self,
+        user_id: &UserId,
+        until: u64,
+    ) -> impl Iterator<Item = Result<(u64, Notification)>> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut current = prefix.clone();
+        current.extend_from_slice(&until.to_be_bytes());
+
+        let prefixlen = prefix.len();
+        self.usercount_notification
+            .range(..current)
+            .rev()
+            .filter_map(|r| r.ok())
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(move |(k, v)| {
+                Ok((
+                    utils::u64_from_bytes(&k[prefixlen..])
+                        .map_err(|_| Error::bad_database("Invalid notification id in db."))?,
+                    serde_json::from_slice(&v)
+                        .map_err(|_| Error::bad_database("Invalid notification in db."))?,
+                ))
+            })
+    }
+
+    /// Removes notifications that are older than `max_age` milliseconds.
+    pub fn prune_notifications(&self, max_age: u64) -> Result<()> {
+        let oldest = utils::millis_since_unix_epoch().saturating_sub(max_age);
+
+        for r in self.usercount_notification.iter() {
+            let (key, value) = r?;
+            let notification = serde_json::from_slice::<Notification>(&value)
+                .map_err(|_| Error::bad_database("Invalid notification in db."))?;
+
+            if notification.ts >= oldest {
+                continue;
+            }
+
+            // UserCount = UserId + 0xff + Count
+            let (user_id, count) = key.split_at(key.len() - 8);
+            let mut userroomcount = user_id.to_vec();
+            userroomcount.extend_from_slice(notification.room_id.to_string().as_bytes());
+            userroomcount.push(0xff);
+            userroomcount.extend_from_slice(count);
+
+            self.userroomcount_highlight.remove(userroomcount)?;
+            self.usercount_notification.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns how many events notified the user in all rooms since their read markers.
+    pub fn unread_notification_count(&self, user_id: &UserId) -> Result<u64> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.userroomid_notificationcount
+            .scan_prefix(prefix)
+            .values()
+            .map(|count| {
+                utils::u64_from_bytes(&count?)
+                    .map_err(|_| Error::bad_database("Invalid notification count in db."))
+            })
+            .sum()
+    }
+
+    fn userroom_count(tree: &sled::Tree, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        tree.get(userroom_id)?.map_or(Ok(0), |bytes| {
+            utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Invalid notification count in db."))
+        })
+    }
+
+    /// Recounts the notifications of the user in the room after their read marker moved.
+    pub fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let last_read = self.edus.private_read_get(room_id, user_id)?.unwrap_or(0);
+
+        let mut userroom_id = user_id.to_string().as_bytes().to_vec();
+        userroom_id.push(0xff);
+        userroom_id.extend_from_slice(room_id.to_string().as_bytes());
+
+        let mut prefix = userroom_id.clone();
+        prefix.push(0xff);
+
+        let mut first_unread = prefix.clone();
+        first_unread.extend_from_slice(&(last_read + 1).to_be_bytes());
+
+        // Read notifications are not counted anymore
+        for key in self
+            .userroomcount_highlight
+            .range(prefix.clone()..first_unread.clone())
+            .keys()
+        {
+            self.userroomcount_highlight.remove(key?)?;
+        }
+
+        let mut notification_count = 0_u64;
+        let mut highlight_count = 0_u64;
+        for r in self.userroomcount_highlight.range(first_unread..) {
+            let (key, highlight) = r?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            notification_count += 1;
+            if highlight.first() == Some(&1) {
+                highlight_count += 1;
+            }
+        }
+
+        self.userroomid_notificationcount
+            .insert(&userroom_id, &notification_count.to_be_bytes())?;
+        self.userroomid_highlightcount
+            .insert(&userroom_id, &highlight_count.to_be_bytes())?;
+
+        self.push_queue.remove_read_emails(user_id, room_id)?;
+
+        Ok(())
+    }
+
     pub fn once_joined(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
         let mut userroom_id = user_id.to_string().as_bytes().to_vec();
         userroom_id.push(0xff);
@@ -1264,4 +3181,188 @@ impl Rooms {
 
         Ok(self.userroomid_left.get(userroom_id)?.is_some())
     }
+
+    fn lazy_load_prefix(user_id: &UserId, device_id: &DeviceId, room_id: &RoomId) -> Vec<u8> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+        prefix.extend_from_slice(room_id.to_string().as_bytes());
+        prefix.push(0xff);
+        prefix
+    }
+
+    /// Returns true if the member event of `member_id` was already sent to this device.
+    pub fn lazy_load_was_sent_before(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        member_id: &UserId,
+    ) -> Result<bool> {
+        let mut key = Self::lazy_load_prefix(user_id, device_id, room_id);
+        key.extend_from_slice(member_id.to_string().as_bytes());
+
+        Ok(self.lazyloadedids.get(key)?.is_some())
+    }
+
+    /// Remembers that the member events of these users were sent to this device.
+    pub fn lazy_load_mark_sent(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        member_ids: impl IntoIterator<Item = UserId>,
+    ) -> Result<()> {
+        let prefix = Self::lazy_load_prefix(user_id, device_id, room_id);
+
+        for member_id in member_ids {
+            let mut key = prefix.clone();
+            key.extend_from_slice(member_id.to_string().as_bytes());
+            self.lazyloadedids.insert(key, &[])?;
+        }
+
+        Ok(())
+    }
+
+    /// Remembers that the member events of these users are in the sync response with
+    /// `next_batch`. They only count as sent once the client syncs with that token, because the
+    /// response might never reach the client.
+    pub fn lazy_load_mark_pending(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+        member_ids: impl IntoIterator<Item = UserId>,
+        next_batch: &str,
+    ) -> Result<()> {
+        let prefix = Self::lazy_load_prefix(user_id, device_id, room_id);
+
+        for member_id in member_ids {
+            let mut key = prefix.clone();
+            key.extend_from_slice(member_id.to_string().as_bytes());
+            self.lazyloadpendingids.insert(key, next_batch.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Marks the member events of the sync response with `since` as sent, now that the client
+    /// used its token.
+    pub fn lazy_load_confirm_delivery(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        since: &str,
+    ) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+
+        for r in self.lazyloadpendingids.scan_prefix(&prefix) {
+            let (key, next_batch) = r?;
+            if &*next_batch == since.as_bytes() {
+                self.lazyloadedids.insert(&key, &[])?;
+                self.lazyloadpendingids.remove(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets which member events were sent to this device, for example because the client
+    /// starts over with an initial sync.
+    pub fn lazy_load_reset(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        room_id: &RoomId,
+    ) -> Result<()> {
+        let prefix = Self::lazy_load_prefix(user_id, device_id, room_id);
+
+        for key in self.lazyloadedids.scan_prefix(&prefix).keys() {
+            self.lazyloadedids.remove(key?)?;
+        }
+        for key in self.lazyloadpendingids.scan_prefix(&prefix).keys() {
+            self.lazyloadpendingids.remove(key?)?;
+        }
+
+        Ok(())
+    }
+
+    fn sliding_sync_prefix(user_id: &UserId, device_id: &DeviceId, conn_id: &str) -> Vec<u8> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(device_id.as_bytes());
+        prefix.push(0xff);
+        prefix.extend_from_slice(conn_id.as_bytes());
+        prefix.push(0xff);
+        prefix
+    }
+
+    /// Returns the rooms a sliding sync connection knows after the response with `pos`.
+    pub fn sliding_sync_rooms(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        conn_id: &str,
+        pos: &str,
+    ) -> Result<HashSet<RoomId>> {
+        let mut prefix = Self::sliding_sync_prefix(user_id, device_id, conn_id);
+        prefix.extend_from_slice(pos.as_bytes());
+        prefix.push(0xff);
+
+        self.slidingsyncids
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|key| {
+                let key = key?;
+                RoomId::try_from(
+                    utils::string_from_bytes(&key[prefix.len()..])
+                        .map_err(|_| Error::bad_database("Invalid room id in slidingsyncids."))?,
+                )
+                .map_err(|_| Error::bad_database("Invalid room id in slidingsyncids."))
+            })
+            .collect()
+    }
+
+    /// Remembers the rooms a sliding sync connection knows after the response with `pos`. What it
+    /// knew at older positions is forgotten, except at `since` in case the response gets lost and
+    /// the client retries.
+    pub fn sliding_sync_update<'a>(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        conn_id: &str,
+        since: Option<&str>,
+        pos: &str,
+        room_ids: impl IntoIterator<Item = &'a RoomId>,
+    ) -> Result<()> {
+        let prefix = Self::sliding_sync_prefix(user_id, device_id, conn_id);
+
+        let mut kept_prefixes = Vec::new();
+        for kept in since.into_iter().chain(Some(pos)) {
+            let mut kept_prefix = prefix.clone();
+            kept_prefix.extend_from_slice(kept.as_bytes());
+            kept_prefix.push(0xff);
+            kept_prefixes.push(kept_prefix);
+        }
+
+        for key in self.slidingsyncids.scan_prefix(&prefix).keys() {
+            let key = key?;
+            if !kept_prefixes.iter().any(|kept| key.starts_with(kept)) {
+                self.slidingsyncids.remove(key)?;
+            }
+        }
+
+        let pos_prefix = kept_prefixes.pop().expect("pos is always kept");
+        for room_id in room_ids {
+            let mut key = pos_prefix.clone();
+            key.extend_from_slice(room_id.to_string().as_bytes());
+            self.slidingsyncids.insert(key, &[])?;
+        }
+
+        Ok(())
+    }
 }
@@ -1,4 +1,4 @@
-use crate::{utils, Error, Result};
+use crate::{database::globals::Stream, utils, Error, Result};
 use js_int::UInt;
 use ruma::{
     events::{
@@ -13,6 +13,7 @@ use std::{
     convert::{TryFrom, TryInto},
 };
 
+#[derive(Clone)]
 pub struct RoomEdus {
     pub(in super::super) readreceiptid_readreceipt: sled::Tree, // ReadReceiptId = RoomId + Count + UserId
     pub(in super::super) roomuserid_privateread: sled::Tree, // RoomUserId = Room + User, PrivateRead = Count
@@ -21,6 +22,7 @@ pub struct RoomEdus {
     pub(in super::super) roomid_lasttypingupdate: sled::Tree, // LastRoomTypingUpdate = Count
     pub(in super::super) presenceid_presence: sled::Tree, // PresenceId = RoomId + Count + UserId
     pub(in super::super) userid_lastpresenceupdate: sled::Tree, // LastPresenceUpdate = Count
+    pub(in super::super) userid_presencestate: sled::Tree,
 }
 
 impl RoomEdus {
@@ -55,7 +57,8 @@ impl RoomEdus {
         }
 
         let mut room_latest_id = prefix;
-        room_latest_id.extend_from_slice(&globals.next_count()?.to_be_bytes());
+        room_latest_id
+            .extend_from_slice(&globals.next_stream_count(Stream::Receipts)?.to_be_bytes());
         room_latest_id.push(0xff);
         room_latest_id.extend_from_slice(&user_id.to_string().as_bytes());
 
@@ -64,6 +67,8 @@ impl RoomEdus {
             &*serde_json::to_string(&event).expect("EduEvent::to_string always works"),
         )?;
 
+        globals.notifier().notify_room(room_id);
+
         Ok(())
     }
 
@@ -106,8 +111,12 @@ impl RoomEdus {
         self.roomuserid_privateread
             .insert(&key, &count.to_be_bytes())?;
 
-        self.roomuserid_lastprivatereadupdate
-            .insert(&key, &globals.next_count()?.to_be_bytes())?;
+        self.roomuserid_lastprivatereadupdate.insert(
+            &key,
+            &globals.next_stream_count(Stream::Receipts)?.to_be_bytes(),
+        )?;
+
+        globals.notifier().notify_user(user_id);
 
         Ok(())
     }
@@ -154,7 +163,7 @@ impl RoomEdus {
         let mut prefix = room_id.to_string().as_bytes().to_vec();
         prefix.push(0xff);
 
-        let count = globals.next_count()?.to_be_bytes();
+        let count = globals.next_stream_count(Stream::Typing)?.to_be_bytes();
 
         let mut room_typing_id = prefix;
         room_typing_id.extend_from_slice(&timeout.to_be_bytes());
@@ -167,6 +176,8 @@ impl RoomEdus {
         self.roomid_lasttypingupdate
             .insert(&room_id.to_string().as_bytes(), &count)?;
 
+        globals.notifier().notify_room(room_id);
+
         Ok(())
     }
 
@@ -198,8 +209,10 @@ impl RoomEdus {
         if found_outdated {
             self.roomid_lasttypingupdate.insert(
                 &room_id.to_string().as_bytes(),
-                &globals.next_count()?.to_be_bytes(),
+                &globals.next_stream_count(Stream::Typing)?.to_be_bytes(),
             )?;
+
+            globals.notifier().notify_room(room_id);
         }
 
         Ok(())
@@ -244,8 +257,10 @@ impl RoomEdus {
         if found_outdated {
             self.roomid_lasttypingupdate.insert(
                 &room_id.to_string().as_bytes(),
-                &globals.next_count()?.to_be_bytes(),
+                &globals.next_stream_count(Stream::Typing)?.to_be_bytes(),
             )?;
+
+            globals.notifier().notify_room(room_id);
         }
 
         Ok(())
@@ -313,7 +328,7 @@ impl RoomEdus {
     ) -> Result<()> {
         // TODO: Remove old entry? Or maybe just wipe completely from time to time?
 
-        let count = globals.next_count()?.to_be_bytes();
+        let count = globals.next_stream_count(Stream::Presence)?.to_be_bytes();
 
         let mut presence_id = room_id.to_string().as_bytes().to_vec();
         presence_id.push(0xff);
@@ -326,14 +341,33 @@ impl RoomEdus {
             &*serde_json::to_string(&presence).expect("PresenceEvent can be serialized"),
         )?;
 
+        globals.notifier().notify_room(room_id);
+
         self.userid_lastpresenceupdate.insert(
             &user_id.to_string().as_bytes(),
             &utils::millis_since_unix_epoch().to_be_bytes(),
         )?;
 
+        self.userid_presencestate.insert(
+            &user_id.to_string().as_bytes(),
+            serde_json::to_vec(&presence.content.presence)
+                .expect("PresenceState can be serialized"),
+        )?;
+
         Ok(())
     }
 
+    /// Returns the presence state of the last presence update of this user.
+    pub fn presence_state(&self, user_id: &UserId) -> Result<Option<PresenceState>> {
+        self.userid_presencestate
+            .get(&user_id.to_string().as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid state in userid_presencestate."))
+            })
+            .transpose()
+    }
+
     /// Resets the presence timeout, so the user will stay in their current presence state.
     pub fn ping_presence(&self, user_id: &UserId) -> Result<()> {
         self.userid_lastpresenceupdate.insert(
@@ -382,9 +416,14 @@ impl RoomEdus {
         // 5 Minutes
         {
             self.userid_lastpresenceupdate.remove(&user_id_bytes)?;
+            self.userid_presencestate.insert(
+                &user_id_bytes,
+                serde_json::to_vec(&PresenceState::Offline)
+                    .expect("PresenceState can be serialized"),
+            )?;
 
             // Send new presence events to set the user offline
-            let count = globals.next_count()?.to_be_bytes();
+            let count = globals.next_stream_count(Stream::Presence)?.to_be_bytes();
             let user_id = utils::string_from_bytes(&user_id_bytes)
                 .map_err(|_| {
                     Error::bad_database("Invalid UserId bytes in userid_lastpresenceupdate.")
@@ -415,6 +454,8 @@ impl RoomEdus {
                     })
                     .expect("PresenceEvent can be serialized"),
                 )?;
+
+                globals.notifier().notify_room(&room_id);
             }
         }
 
@@ -0,0 +1,540 @@
+mod email;
+
+use super::Rooms;
+use crate::{
+    database::{
+        account_data::AccountData,
+        globals::{EmailConfig, Globals},
+        users::Users,
+    },
+    push_rules::PatternCache,
+    utils, Error, Result,
+};
+use email::QueuedEmail;
+use log::warn;
+use reqwest::header::CONTENT_TYPE;
+use ruma::{RoomId, ServerName, UserId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+/// Pushes are dropped after failing this often.
+const MAX_ATTEMPTS: u64 = 10;
+
+/// Notifications are kept for 30 days.
+const NOTIFICATION_RETENTION: u64 = 30 * 24 * 60 * 60 * 1000;
+
+/// A request to the push gateway of one pusher.
+#[derive(Deserialize, Serialize)]
+struct QueuedPush {
+    user_id: UserId,
+    app_id: String,
+    pushkey: String,
+    url: String,
+    body: serde_json::Value,
+}
+
+/// Evaluates push rules for new events, sends the notifications to the push gateways of http
+/// pushers and mails digests to email pushers in the background. Every pusher has its own queue
+/// and backoff, so a gateway that is down only delays its own pushes.
+#[derive(Clone)]
+pub struct PushQueue {
+    pub(in super::super) queueid_pduid: sled::Tree, // QueueId = Count of the pdu
+    pub(in super::super) pusherqueueid_push: sled::Tree, // PusherQueueId = PusherId + QueueId
+    pub(in super::super) pusherqueueid_email: sled::Tree, // PusherQueueId = PusherId + QueueId
+    pub(in super::super) pusherid_backoff: sled::Tree, // PusherId = UserId + AppId + PushKey, Backoff = Attempts + NextAttempt
+    pub(in super::super) wakeup: Arc<Notify>,
+    pub(in super::super) in_flight: Arc<Mutex<HashSet<Vec<u8>>>>, // PusherIds with a push being sent
+}
+
+impl PushQueue {
+    /// Queues a new event for push rule evaluation.
+    pub(super) fn enqueue(&self, index: u64, pdu_id: &[u8]) -> Result<()> {
+        self.queueid_pduid.insert(&index.to_be_bytes(), pdu_id)?;
+
+        self.wakeup.notify();
+
+        Ok(())
+    }
+
+    /// Forgets queued emails about a room after the user read it.
+    pub(super) fn remove_read_emails(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for r in self.pusherqueueid_email.scan_prefix(prefix) {
+            let (key, value) = r?;
+            let queued = serde_json::from_slice::<QueuedEmail>(&value)
+                .map_err(|_| Error::bad_database("Invalid email in push queue."))?;
+
+            if &queued.room_id == room_id {
+                self.pusherqueueid_email.remove(key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates push rules for new events and delivers queued pushes and emails until the server
+    /// stops.
+    pub fn start_handler(
+        &self,
+        rooms: Rooms,
+        users: Users,
+        account_data: AccountData,
+        globals: &Globals<'_>,
+    ) {
+        let queue = self.clone();
+        let client = globals.reqwest_client().clone();
+        let email_config = globals.email().cloned();
+        let server_name = globals.server_name().to_owned();
+        rocket::tokio::spawn(async move {
+            let mut last_prune = None;
+            loop {
+                if last_prune.map_or(true, |last_prune: Instant| {
+                    last_prune.elapsed() > Duration::from_secs(60 * 60)
+                }) {
+                    if let Err(e) = rooms.prune_notifications(NOTIFICATION_RETENTION) {
+                        warn!("Could not remove old notifications: {}", e);
+                    }
+                    last_prune = Some(Instant::now());
+                }
+
+                if let Err(e) = queue.evaluate(
+                    &rooms,
+                    &users,
+                    &account_data,
+                    &server_name,
+                    email_config.is_some(),
+                ) {
+                    warn!("Could not evaluate push rules: {}", e);
+                }
+                if let Err(e) = queue.deliver(&users, &client) {
+                    warn!("Could not deliver pushes: {}", e);
+                }
+                if let Some(email_config) = &email_config {
+                    if let Err(e) = queue
+                        .send_emails(&users, email_config, server_name.as_str())
+                        .await
+                    {
+                        warn!("Could not send email notifications: {}", e);
+                    }
+                }
+
+                // Wake up for new notifications, or to retry failed pushes
+                let _ =
+                    rocket::tokio::time::timeout(Duration::from_secs(1), queue.wakeup.notified())
+                        .await;
+            }
+        });
+    }
+
+    /// Evaluates the push rules of the members of the room for every queued event.
+    fn evaluate(
+        &self,
+        rooms: &Rooms,
+        users: &Users,
+        account_data: &AccountData,
+        server_name: &ServerName,
+        emails_enabled: bool,
+    ) -> Result<()> {
+        let mut cache = PatternCache::default();
+
+        for r in self.queueid_pduid.iter() {
+            let (queue_id, pdu_id) = r?;
+            let index = utils::u64_from_bytes(&queue_id)
+                .map_err(|_| Error::bad_database("Invalid queue id in queueid_pduid."))?;
+
+            // The event is gone if the room was purged
+            if let Some(pdu) = rooms.get_pdu_from_id(&pdu_id)? {
+                rooms.evaluate_push_rules(
+                    &pdu,
+                    index,
+                    server_name,
+                    users,
+                    account_data,
+                    emails_enabled,
+                    &mut cache,
+                )?;
+            }
+
+            self.queueid_pduid.remove(queue_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Turns a notification into a request for every http pusher of the user and into a digest
+    /// entry for every email pusher.
+    pub(super) fn queue_pushes(
+        &self,
+        users: &Users,
+        user_id: &UserId,
+        queue_id: u64,
+        notification: &serde_json::Value,
+        tweaks: &serde_json::Value,
+        emails_enabled: bool,
+    ) -> Result<()> {
+        for pusher in users.pushers(user_id) {
+            let pusher = pusher?;
+            let kind = pusher.get("kind").and_then(|k| k.as_str());
+            if kind != Some("http") && (kind != Some("email") || !emails_enabled) {
+                continue;
+            }
+
+            let field = |name: &str| {
+                pusher
+                    .get(name)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+            let app_id = field("app_id");
+            let pushkey = field("pushkey");
+
+            let mut key = Self::pusher_id(user_id, &app_id, &pushkey);
+            key.extend_from_slice(&queue_id.to_be_bytes());
+
+            if kind == Some("email") {
+                self.pusherqueueid_email.insert(
+                    key,
+                    &*serde_json::to_string(&QueuedEmail::new(
+                        user_id.clone(),
+                        app_id,
+                        pushkey,
+                        notification,
+                        utils::millis_since_unix_epoch(),
+                    )?)
+                    .expect("email can be serialized"),
+                )?;
+                continue;
+            }
+
+            // The url is for us, everything else in data is for the gateway
+            let mut data = pusher.get("data").cloned().unwrap_or_else(|| json!({}));
+            let url = match data
+                .as_object_mut()
+                .and_then(|data| data.remove("url"))
+                .and_then(|url| url.as_str().map(str::to_owned))
+            {
+                Some(url) if Self::is_valid_gateway_url(&url) => url,
+                _ => continue,
+            };
+
+            let mut notification =
+                if data.get("format").and_then(|f| f.as_str()) == Some("event_id_only") {
+                    json!({
+                        "event_id": notification["event_id"],
+                        "room_id": notification["room_id"],
+                        "counts": notification["counts"],
+                    })
+                } else {
+                    notification.clone()
+                };
+            notification["devices"] = json!([{
+                "app_id": app_id,
+                "pushkey": pushkey,
+                "data": data,
+                "tweaks": tweaks,
+            }]);
+
+            self.pusherqueueid_push.insert(
+                key,
+                &*serde_json::to_string(&QueuedPush {
+                    user_id: user_id.clone(),
+                    app_id,
+                    pushkey,
+                    url,
+                    body: json!({ "notification": notification }),
+                })
+                .expect("push can be serialized"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts sending the oldest push of every pusher that is not backing off and has no push in
+    /// flight. Every pusher gets its own task, so a slow gateway only delays its own pushes.
+    fn deliver(&self, users: &Users, client: &reqwest::Client) -> Result<()> {
+        let mut last_pusher_id = None;
+        for r in self.pusherqueueid_push.iter() {
+            let (key, value) = r?;
+            let pusher_id = key[..key.len() - 8].to_vec();
+
+            // Pushes of one pusher are sent in order
+            if last_pusher_id.as_ref() == Some(&pusher_id) {
+                continue;
+            }
+            last_pusher_id = Some(pusher_id.clone());
+
+            if !self.backoff_passed(&pusher_id)?
+                || !self
+                    .in_flight
+                    .lock()
+                    .expect("in_flight is never poisoned")
+                    .insert(pusher_id.clone())
+            {
+                continue;
+            }
+
+            let push = serde_json::from_slice::<QueuedPush>(&value)
+                .map_err(|_| Error::bad_database("Invalid push in push queue."))?;
+
+            let queue = self.clone();
+            let users = users.clone();
+            let client = client.clone();
+            rocket::tokio::spawn(async move {
+                if let Err(e) = queue
+                    .send_push(&users, &client, &pusher_id, &key, push)
+                    .await
+                {
+                    warn!("Could not deliver push: {}", e);
+                }
+
+                queue
+                    .in_flight
+                    .lock()
+                    .expect("in_flight is never poisoned")
+                    .remove(&pusher_id);
+                queue.wakeup.notify();
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Sends one push to the gateway of its pusher and removes it from the queue unless it should
+    /// be retried.
+    async fn send_push(
+        &self,
+        users: &Users,
+        client: &reqwest::Client,
+        pusher_id: &[u8],
+        key: &[u8],
+        push: QueuedPush,
+    ) -> Result<()> {
+        let response = client
+            .post(&push.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(push.body.to_string())
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await;
+
+        let response = match response {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                warn!("Push gateway {} returned {}", push.url, response.status());
+                if self.back_off(pusher_id)? {
+                    self.pusherqueueid_push.remove(key)?;
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Could not reach push gateway {}: {}", push.url, e);
+                if self.back_off(pusher_id)? {
+                    self.pusherqueueid_push.remove(key)?;
+                }
+                return Ok(());
+            }
+        };
+
+        let rejected = response
+            .text()
+            .await
+            .ok()
+            .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+            .and_then(|body| {
+                Some(
+                    body.get("rejected")?
+                        .as_array()?
+                        .iter()
+                        .any(|pushkey| pushkey.as_str() == Some(&push.pushkey)),
+                )
+            })
+            .unwrap_or(false);
+
+        self.pusherqueueid_push.remove(key)?;
+        self.pusherid_backoff.remove(pusher_id)?;
+
+        // The gateway will never accept this pushkey again
+        if rejected {
+            users.remove_pusher(&push.user_id, &push.app_id, &push.pushkey)?;
+            for key in self.pusherqueueid_push.scan_prefix(pusher_id).keys() {
+                self.pusherqueueid_push.remove(key?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Mails a digest to every email pusher whose oldest queued notification waited long enough.
+    async fn send_emails(
+        &self,
+        users: &Users,
+        config: &EmailConfig,
+        server_name: &str,
+    ) -> Result<()> {
+        // Keys are sorted, so the emails of one pusher are next to each other
+        let mut digests: Vec<(Vec<u8>, Vec<sled::IVec>, Vec<QueuedEmail>)> = Vec::new();
+        for r in self.pusherqueueid_email.iter() {
+            let (key, value) = r?;
+            let pusher_id = key[..key.len() - 8].to_vec();
+            let email = serde_json::from_slice::<QueuedEmail>(&value)
+                .map_err(|_| Error::bad_database("Invalid email in push queue."))?;
+
+            match digests.last_mut() {
+                Some((last_pusher_id, keys, emails)) if last_pusher_id == &pusher_id => {
+                    keys.push(key);
+                    emails.push(email);
+                }
+                _ => digests.push((pusher_id, vec![key], vec![email])),
+            }
+        }
+
+        let now = utils::millis_since_unix_epoch();
+        for (pusher_id, keys, emails) in digests {
+            let oldest = &emails[0];
+            if oldest.ts + config.delay.as_millis() as u64 > now
+                || !self.backoff_passed(&pusher_id)?
+            {
+                continue;
+            }
+
+            // The pusher could have been removed while the notifications were waiting
+            let pusher_exists =
+                users
+                    .pushers(&oldest.user_id)
+                    .filter_map(|r| r.ok())
+                    .any(|pusher| {
+                        pusher.get("app_id").and_then(|a| a.as_str())
+                            == Some(oldest.app_id.as_str())
+                            && pusher.get("pushkey").and_then(|p| p.as_str())
+                                == Some(oldest.pushkey.as_str())
+                    });
+
+            if pusher_exists {
+                let (subject, body) = email::render(config, &oldest.user_id, server_name, &emails);
+                let to = oldest.pushkey.clone();
+                let config = config.clone();
+                let result =
+                    rocket::tokio::task::spawn_blocking(move || config.send(&to, subject, body))
+                        .await
+                        .expect("sending an email does not panic");
+
+                if let Err(e) = result {
+                    warn!("Could not send email to {}: {}", oldest.pushkey, e);
+                    if !self.back_off(&pusher_id)? {
+                        continue;
+                    }
+                } else {
+                    self.pusherid_backoff.remove(&pusher_id)?;
+                }
+            }
+
+            for key in keys {
+                self.pusherqueueid_email.remove(key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn backoff_passed(&self, pusher_id: &[u8]) -> Result<bool> {
+        Ok(match self.pusherid_backoff.get(pusher_id)? {
+            Some(backoff) => Self::parse_backoff(&backoff)?.1 <= utils::millis_since_unix_epoch(),
+            None => true,
+        })
+    }
+
+    /// Delays the next attempt of the pusher exponentially. Returns true if it failed too often and
+    /// the caller should drop what it tried to send.
+    fn back_off(&self, pusher_id: &[u8]) -> Result<bool> {
+        let attempts = match self.pusherid_backoff.get(pusher_id)? {
+            Some(backoff) => Self::parse_backoff(&backoff)?.0,
+            None => 0,
+        } + 1;
+
+        if attempts >= MAX_ATTEMPTS {
+            warn!("Giving up on a push after {} failed attempts", attempts);
+            self.pusherid_backoff.remove(pusher_id)?;
+            return Ok(true);
+        }
+
+        // Wait 2, 4, 8, ... seconds, but at most an hour
+        let delay = (1000_u64 << attempts).min(60 * 60 * 1000);
+
+        let mut backoff = attempts.to_be_bytes().to_vec();
+        backoff.extend_from_slice(&(utils::millis_since_unix_epoch() + delay).to_be_bytes());
+        self.pusherid_backoff.insert(pusher_id, backoff)?;
+
+        Ok(false)
+    }
+
+    fn parse_backoff(backoff: &[u8]) -> Result<(u64, u64)> {
+        let invalid = || Error::bad_database("Invalid backoff in pusherid_backoff.");
+
+        Ok((
+            utils::u64_from_bytes(backoff.get(..8).ok_or_else(invalid)?).map_err(|_| invalid())?,
+            utils::u64_from_bytes(backoff.get(8..).ok_or_else(invalid)?).map_err(|_| invalid())?,
+        ))
+    }
+
+    /// Push gateways are only allowed to be reached over http(s) and at the notify endpoint.
+    pub fn is_valid_gateway_url(url: &str) -> bool {
+        reqwest::Url::parse(url).map_or(false, |url| {
+            (url.scheme() == "https" || url.scheme() == "http")
+                && url.path() == "/_matrix/push/v1/notify"
+        })
+    }
+
+    fn pusher_id(user_id: &UserId, app_id: &str, pushkey: &str) -> Vec<u8> {
+        let mut pusher_id = user_id.to_string().as_bytes().to_vec();
+        pusher_id.push(0xff);
+        pusher_id.extend_from_slice(app_id.as_bytes());
+        pusher_id.push(0xff);
+        pusher_id.extend_from_slice(pushkey.as_bytes());
+        pusher_id.push(0xff);
+        pusher_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PushQueue;
+
+    #[test]
+    fn accepts_notify_endpoints() {
+        assert!(PushQueue::is_valid_gateway_url(
+            "https://push.example.com/_matrix/push/v1/notify"
+        ));
+        assert!(PushQueue::is_valid_gateway_url(
+            "http://localhost:5000/_matrix/push/v1/notify"
+        ));
+    }
+
+    #[test]
+    fn rejects_other_urls() {
+        for url in &[
+            "",
+            "push.example.com/_matrix/push/v1/notify",
+            "ftp://push.example.com/_matrix/push/v1/notify",
+            "file:///_matrix/push/v1/notify",
+            "https://push.example.com",
+            "https://push.example.com/_matrix/push/v1/notify/other",
+            "https://push.example.com/_matrix/push/v2/notify",
+        ] {
+            assert!(
+                !PushQueue::is_valid_gateway_url(url),
+                "{} was accepted",
+                url
+            );
+        }
+    }
+}
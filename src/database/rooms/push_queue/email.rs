@@ -0,0 +1,83 @@
+use crate::{database::globals::EmailConfig, Error, Result};
+use ruma::{RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+/// A notification waiting for the next email digest of one email pusher.
+#[derive(Deserialize, Serialize)]
+pub(super) struct QueuedEmail {
+    pub user_id: UserId,
+    pub app_id: String,
+    pub pushkey: String,
+    pub room_id: RoomId,
+    /// The room name, or the room id if the room has no name
+    pub room: String,
+    /// The display name of the sender, or their user id
+    pub sender: String,
+    pub body: String,
+    /// When the notification was queued
+    pub ts: u64,
+}
+
+impl QueuedEmail {
+    /// Creates the digest entry for a notification as built by `Rooms::evaluate_push_rules`.
+    pub fn new(
+        user_id: UserId,
+        app_id: String,
+        pushkey: String,
+        notification: &serde_json::Value,
+        ts: u64,
+    ) -> Result<Self> {
+        let field = |name: &str| notification.get(name).and_then(|v| v.as_str());
+
+        let room_id = serde_json::from_value(notification["room_id"].clone())
+            .map_err(|_| Error::bad_database("Invalid room id in queued notification."))?;
+
+        Ok(Self {
+            user_id,
+            app_id,
+            pushkey,
+            room_id,
+            room: field("room_name")
+                .or_else(|| field("room_id"))
+                .unwrap_or_default()
+                .to_owned(),
+            sender: field("sender_display_name")
+                .or_else(|| field("sender"))
+                .unwrap_or_default()
+                .to_owned(),
+            body: notification["content"]
+                .get("body")
+                .and_then(|body| body.as_str())
+                .map(str::to_owned)
+                .unwrap_or_else(|| format!("sent a {} event", field("type").unwrap_or_default())),
+            ts,
+        })
+    }
+}
+
+/// Fills in the subject and body templates for a digest of notifications.
+///
+/// Both templates can use `{user_id}`, `{count}` and `{server_name}`. `{notifications}` lists one
+/// notification per line.
+pub(super) fn render(
+    config: &EmailConfig,
+    user_id: &UserId,
+    server_name: &str,
+    emails: &[QueuedEmail],
+) -> (String, String) {
+    let notifications = emails
+        .iter()
+        .map(|email| format!("[{}] {}: {}", email.room, email.sender, email.body))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let fill = |template: &str| {
+        template
+            .replace("{user_id}", user_id.as_str())
+            .replace("{count}", &emails.len().to_string())
+            .replace("{server_name}", server_name)
+            .replace("{notifications}", &notifications)
+    };
+
+    (fill(&config.subject_template), fill(&config.body_template))
+}
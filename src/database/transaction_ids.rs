@@ -1,9 +1,10 @@
-use crate::Result;
-use ruma::{DeviceId, UserId};
+use crate::{utils, Error, PduEvent, Result};
+use ruma::{DeviceId, EventId, UserId};
 use sled::IVec;
 
 pub struct TransactionIds {
     pub(super) userdevicetxnid_response: sled::Tree, // Response can be empty (/sendToDevice) or the event id (/send)
+    pub(super) eventid_devicetxnid: sled::Tree,      // DeviceTxnId = DeviceId + TxnId
 }
 
 impl TransactionIds {
@@ -40,4 +41,51 @@ impl TransactionIds {
         // If there's no entry, this is a new transaction
         Ok(self.userdevicetxnid_response.get(key)?)
     }
+
+    /// Remembers which device sent an event with which transaction id.
+    pub fn add_event_txnid(
+        &self,
+        event_id: &EventId,
+        device_id: &DeviceId,
+        txn_id: &str,
+    ) -> Result<()> {
+        let mut value = device_id.as_bytes().to_vec();
+        value.push(0xff);
+        value.extend_from_slice(txn_id.as_bytes());
+
+        self.eventid_devicetxnid
+            .insert(event_id.as_bytes(), value)?;
+
+        Ok(())
+    }
+
+    /// Returns the transaction id of the event if this device of the user sent it.
+    pub fn event_txnid(
+        &self,
+        pdu: &PduEvent,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<String>> {
+        if &pdu.sender != user_id {
+            return Ok(None);
+        }
+
+        let value = match self.eventid_devicetxnid.get(pdu.event_id.as_bytes())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let mut parts = value.splitn(2, |&b| b == 0xff);
+        if parts.next() != Some(device_id.as_bytes()) {
+            return Ok(None);
+        }
+
+        parts
+            .next()
+            .map(|txn_id| {
+                utils::string_from_bytes(txn_id)
+                    .map_err(|_| Error::bad_database("Invalid txn id in eventid_devicetxnid."))
+            })
+            .transpose()
+    }
 }
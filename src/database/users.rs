@@ -1,10 +1,11 @@
-use crate::{utils, Error, Result};
+use crate::{database::globals::Stream, utils, Error, Result};
 use js_int::UInt;
 use ruma::{
     api::client::{
         error::ErrorKind,
         r0::{
             device::Device,
+            filter::FilterDefinition,
             keys::{CrossSigningKey, OneTimeKey},
         },
     },
@@ -12,8 +13,12 @@ use ruma::{
     events::{AnyToDeviceEvent, EventType},
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, Raw, UserId,
 };
+use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, convert::TryFrom, mem, time::SystemTime};
 
+const FILTER_ID_LENGTH: usize = 10;
+
+#[derive(Clone)]
 pub struct Users {
     pub(super) userid_password: sled::Tree,
     pub(super) userid_displayname: sled::Tree,
@@ -31,6 +36,27 @@ pub struct Users {
     pub(super) userid_usersigningkeyid: sled::Tree,
 
     pub(super) todeviceid_events: sled::Tree, // ToDeviceId = UserId + DeviceId + Count
+
+    pub(super) userfilterid_filter: sled::Tree, // UserFilterId = UserId + FilterId
+
+    pub(super) userpusherid_pusher: sled::Tree, // UserPusherId = UserId + PusherId
+    pub(super) userpusherid_deviceid: sled::Tree, // The device whose access token created the pusher
+    pub(super) pusheruserid: sled::Tree,          // PusherId = AppId + PushKey
+
+    pub(super) userthreepid_addedts: sled::Tree, // UserThreepid = UserId + Medium + Address
+    pub(super) threepid_userid: sled::Tree,      // Threepid = Medium + Address
+    pub(super) clientsecretsid_threepidsession: sled::Tree, // ClientSecretSid = ClientSecret + Sid
+}
+
+/// A request to validate that a user owns a third party id, e.g. by mailing them a token.
+#[derive(Deserialize, Serialize)]
+pub struct ThreepidSession {
+    pub medium: String,
+    pub address: String,
+    pub token: String,
+    pub send_attempt: u64,
+    pub created_ts: u64,
+    pub validated_ts: Option<u64>,
 }
 
 impl Users {
@@ -198,6 +224,297 @@ impl Users {
         Ok(())
     }
 
+    /// Stores a filter and returns its id.
+    pub fn create_filter(&self, user_id: &UserId, filter: &FilterDefinition) -> Result<String> {
+        let filter_id = utils::random_string(FILTER_ID_LENGTH);
+
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(filter_id.as_bytes());
+
+        self.userfilterid_filter.insert(
+            key,
+            &*serde_json::to_string(filter).expect("filter can be serialized"),
+        )?;
+
+        Ok(filter_id)
+    }
+
+    /// Returns a filter the user created before.
+    pub fn get_filter(
+        &self,
+        user_id: &UserId,
+        filter_id: &str,
+    ) -> Result<Option<FilterDefinition>> {
+        let mut key = user_id.to_string().as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(filter_id.as_bytes());
+
+        self.userfilterid_filter
+            .get(key)?
+            .map_or(Ok(None), |filter| {
+                Ok(Some(serde_json::from_slice(&filter).map_err(|_| {
+                    Error::bad_database("Invalid filter in userfilterid_filter.")
+                })?))
+            })
+    }
+
+    /// Adds a pusher or replaces the user's pusher with the same app id and pushkey.
+    pub fn add_pusher(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        app_id: &str,
+        pushkey: &str,
+        pusher: &serde_json::Value,
+    ) -> Result<()> {
+        let pusher_id = Self::pusher_id(app_id, pushkey);
+
+        let mut userpusher_id = user_id.to_string().as_bytes().to_vec();
+        userpusher_id.push(0xff);
+        userpusher_id.extend_from_slice(&pusher_id);
+
+        let mut pusheruser_id = pusher_id;
+        pusheruser_id.push(0xff);
+        pusheruser_id.extend_from_slice(user_id.to_string().as_bytes());
+
+        self.userpusherid_pusher.insert(
+            &userpusher_id,
+            &*serde_json::to_string(pusher).expect("pusher can be serialized"),
+        )?;
+        self.userpusherid_deviceid
+            .insert(&userpusher_id, device_id.as_bytes())?;
+        self.pusheruserid.insert(pusheruser_id, &[])?;
+
+        Ok(())
+    }
+
+    /// Removes the user's pusher with this app id and pushkey, if it exists.
+    pub fn remove_pusher(&self, user_id: &UserId, app_id: &str, pushkey: &str) -> Result<()> {
+        let pusher_id = Self::pusher_id(app_id, pushkey);
+
+        let mut userpusher_id = user_id.to_string().as_bytes().to_vec();
+        userpusher_id.push(0xff);
+        userpusher_id.extend_from_slice(&pusher_id);
+
+        let mut pusheruser_id = pusher_id;
+        pusheruser_id.push(0xff);
+        pusheruser_id.extend_from_slice(user_id.to_string().as_bytes());
+
+        self.userpusherid_pusher.remove(&userpusher_id)?;
+        self.userpusherid_deviceid.remove(&userpusher_id)?;
+        self.pusheruserid.remove(pusheruser_id)?;
+
+        Ok(())
+    }
+
+    /// Returns an iterator over all pushers of the user.
+    pub fn pushers(&self, user_id: &UserId) -> impl Iterator<Item = Result<serde_json::Value>> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.userpusherid_pusher
+            .scan_prefix(prefix)
+            .values()
+            .map(|bytes| {
+                Ok(serde_json::from_slice(&bytes?)
+                    .map_err(|_| Error::bad_database("Invalid pusher in userpusherid_pusher."))?)
+            })
+    }
+
+    /// Returns an iterator over all users that have a pusher with this app id and pushkey.
+    pub fn pushkey_users(
+        &self,
+        app_id: &str,
+        pushkey: &str,
+    ) -> impl Iterator<Item = Result<UserId>> {
+        let mut prefix = Self::pusher_id(app_id, pushkey);
+        prefix.push(0xff);
+
+        self.pusheruserid.scan_prefix(prefix).keys().map(|key| {
+            Ok(UserId::try_from(
+                utils::string_from_bytes(
+                    &key?
+                        .rsplit(|&b| b == 0xff)
+                        .next()
+                        .expect("rsplit always returns an element"),
+                )
+                .map_err(|_| Error::bad_database("User ID in pusheruserid is invalid unicode."))?,
+            )
+            .map_err(|_| Error::bad_database("User ID in pusheruserid is invalid."))?)
+        })
+    }
+
+    fn pusher_id(app_id: &str, pushkey: &str) -> Vec<u8> {
+        let mut pusher_id = app_id.as_bytes().to_vec();
+        pusher_id.push(0xff);
+        pusher_id.extend_from_slice(pushkey.as_bytes());
+        pusher_id
+    }
+
+    /// Stores a validation session of a third party id.
+    pub fn set_threepid_session(
+        &self,
+        client_secret: &str,
+        sid: &str,
+        session: &ThreepidSession,
+    ) -> Result<()> {
+        let mut key = client_secret.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(sid.as_bytes());
+
+        self.clientsecretsid_threepidsession.insert(
+            key,
+            &*serde_json::to_string(session).expect("threepid session can be serialized"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the validation session with this client secret and session id.
+    pub fn threepid_session(
+        &self,
+        client_secret: &str,
+        sid: &str,
+    ) -> Result<Option<ThreepidSession>> {
+        let mut key = client_secret.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(sid.as_bytes());
+
+        self.clientsecretsid_threepidsession
+            .get(key)?
+            .map_or(Ok(None), |session| {
+                Ok(Some(serde_json::from_slice(&session).map_err(|_| {
+                    Error::bad_database("Invalid session in clientsecretsid_threepidsession.")
+                })?))
+            })
+    }
+
+    /// Finds the validation session a client started for this third party id, so retries of the
+    /// token request don't start new sessions.
+    pub fn find_threepid_session(
+        &self,
+        client_secret: &str,
+        medium: &str,
+        address: &str,
+    ) -> Result<Option<(String, ThreepidSession)>> {
+        let mut prefix = client_secret.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for r in self.clientsecretsid_threepidsession.scan_prefix(&prefix) {
+            let (key, value) = r?;
+            let session = serde_json::from_slice::<ThreepidSession>(&value).map_err(|_| {
+                Error::bad_database("Invalid session in clientsecretsid_threepidsession.")
+            })?;
+
+            if session.medium == medium && session.address == address {
+                let sid = utils::string_from_bytes(&key[prefix.len()..]).map_err(|_| {
+                    Error::bad_database("Sid in clientsecretsid_threepidsession is invalid.")
+                })?;
+                return Ok(Some((sid, session)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Removes a validation session after its third party id was bound.
+    pub fn remove_threepid_session(&self, client_secret: &str, sid: &str) -> Result<()> {
+        let mut key = client_secret.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(sid.as_bytes());
+
+        self.clientsecretsid_threepidsession.remove(key)?;
+
+        Ok(())
+    }
+
+    fn threepid_id(medium: &str, address: &str) -> Vec<u8> {
+        let mut threepid_id = medium.as_bytes().to_vec();
+        threepid_id.push(0xff);
+        threepid_id.extend_from_slice(address.as_bytes());
+        threepid_id
+    }
+
+    /// Binds a validated third party id to the account.
+    pub fn add_threepid(&self, user_id: &UserId, medium: &str, address: &str) -> Result<()> {
+        let threepid_id = Self::threepid_id(medium, address);
+
+        let mut userthreepid_id = user_id.to_string().as_bytes().to_vec();
+        userthreepid_id.push(0xff);
+        userthreepid_id.extend_from_slice(&threepid_id);
+
+        self.userthreepid_addedts.insert(
+            userthreepid_id,
+            &utils::millis_since_unix_epoch().to_be_bytes(),
+        )?;
+        self.threepid_userid
+            .insert(threepid_id, user_id.to_string().as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Unbinds a third party id from the account. Returns false if it was not bound to it.
+    pub fn remove_threepid(&self, user_id: &UserId, medium: &str, address: &str) -> Result<bool> {
+        let threepid_id = Self::threepid_id(medium, address);
+
+        let mut userthreepid_id = user_id.to_string().as_bytes().to_vec();
+        userthreepid_id.push(0xff);
+        userthreepid_id.extend_from_slice(&threepid_id);
+
+        if self.userthreepid_addedts.remove(userthreepid_id)?.is_none() {
+            return Ok(false);
+        }
+        self.threepid_userid.remove(threepid_id)?;
+
+        Ok(true)
+    }
+
+    /// Returns the user a third party id is bound to.
+    pub fn threepid_user(&self, medium: &str, address: &str) -> Result<Option<UserId>> {
+        self.threepid_userid
+            .get(Self::threepid_id(medium, address))?
+            .map_or(Ok(None), |bytes| {
+                Ok(Some(
+                    UserId::try_from(utils::string_from_bytes(&bytes).map_err(|_| {
+                        Error::bad_database("User ID in threepid_userid is invalid unicode.")
+                    })?)
+                    .map_err(|_| Error::bad_database("User ID in threepid_userid is invalid."))?,
+                ))
+            })
+    }
+
+    /// Returns an iterator over the (medium, address, added_at) of the user's third party ids.
+    pub fn threepids(
+        &self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<(String, String, u64)>> {
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+        let prefix_len = prefix.len();
+
+        self.userthreepid_addedts.scan_prefix(prefix).map(move |r| {
+            let (key, value) = r?;
+            let mut parts = key[prefix_len..].splitn(2, |&b| b == 0xff);
+            let medium = parts.next().expect("splitn always returns one element");
+            let address = parts
+                .next()
+                .ok_or_else(|| Error::bad_database("Invalid key in userthreepid_addedts."))?;
+
+            Ok((
+                utils::string_from_bytes(medium).map_err(|_| {
+                    Error::bad_database("Medium in userthreepid_addedts is invalid unicode.")
+                })?,
+                utils::string_from_bytes(address).map_err(|_| {
+                    Error::bad_database("Address in userthreepid_addedts is invalid unicode.")
+                })?,
+                utils::u64_from_bytes(&value).map_err(|_| {
+                    Error::bad_database("Invalid added_at in userthreepid_addedts.")
+                })?,
+            ))
+        })
+    }
+
     /// Removes a device from a user.
     pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
         let mut userdeviceid = user_id.to_string().as_bytes().to_vec();
@@ -219,6 +536,23 @@ impl Users {
 
         // TODO: Remove onetimekeys
 
+        // Pushers belong to the access token that created them
+        let mut prefix = user_id.to_string().as_bytes().to_vec();
+        prefix.push(0xff);
+
+        for r in self.userpusherid_deviceid.scan_prefix(&prefix) {
+            let (userpusher_id, pusher_device_id) = r?;
+            if &*pusher_device_id == device_id.as_bytes() {
+                let mut pusheruser_id = userpusher_id[prefix.len()..].to_vec();
+                pusheruser_id.push(0xff);
+                pusheruser_id.extend_from_slice(user_id.to_string().as_bytes());
+
+                self.userpusherid_pusher.remove(&userpusher_id)?;
+                self.userpusherid_deviceid.remove(&userpusher_id)?;
+                self.pusheruserid.remove(pusheruser_id)?;
+            }
+        }
+
         self.userdeviceid_metadata.remove(&userdeviceid)?;
 
         Ok(())
@@ -299,9 +633,13 @@ impl Users {
 
         self.userid_lastonetimekeyupdate.insert(
             &user_id.to_string().as_bytes(),
-            &globals.next_count()?.to_be_bytes(),
+            &globals
+                .next_stream_count(Stream::DeviceLists)?
+                .to_be_bytes(),
         )?;
 
+        globals.notifier().notify_user(user_id);
+
         Ok(())
     }
 
@@ -333,9 +671,13 @@ impl Users {
 
         self.userid_lastonetimekeyupdate.insert(
             &user_id.to_string().as_bytes(),
-            &globals.next_count()?.to_be_bytes(),
+            &globals
+                .next_stream_count(Stream::DeviceLists)?
+                .to_be_bytes(),
         )?;
 
+        globals.notifier().notify_user(user_id);
+
         self.onetimekeyid_onetimekeys
             .scan_prefix(&prefix)
             .next()
@@ -596,7 +938,9 @@ impl Users {
         rooms: &super::rooms::Rooms,
         globals: &super::globals::Globals<'_>,
     ) -> Result<()> {
-        let count = globals.next_count()?.to_be_bytes();
+        let count = globals
+            .next_stream_count(Stream::DeviceLists)?
+            .to_be_bytes();
         for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
             // Don't send key updates to unencrypted rooms
             if rooms
@@ -611,12 +955,14 @@ impl Users {
             key.extend_from_slice(&count);
 
             self.keychangeid_userid.insert(key, &*user_id.to_string())?;
+            globals.notifier().notify_room(&room_id);
         }
 
         let mut key = user_id.to_string().as_bytes().to_vec();
         key.push(0xff);
         key.extend_from_slice(&count);
         self.keychangeid_userid.insert(key, &*user_id.to_string())?;
+        globals.notifier().notify_user(user_id);
 
         Ok(())
     }
@@ -717,7 +1063,7 @@ impl Users {
         key.push(0xff);
         key.extend_from_slice(target_device_id.as_bytes());
         key.push(0xff);
-        key.extend_from_slice(&globals.next_count()?.to_be_bytes());
+        key.extend_from_slice(&globals.next_stream_count(Stream::ToDevice)?.to_be_bytes());
 
         let mut json = serde_json::Map::new();
         json.insert("type".to_owned(), event_type.to_string().into());
@@ -729,6 +1075,8 @@ impl Users {
             &*serde_json::to_string(&json).expect("Map::to_string always works"),
         )?;
 
+        globals.notifier().notify_user(target_user_id);
+
         Ok(())
     }
 
@@ -857,7 +1205,12 @@ impl Users {
         // password without logging in should check if the account is deactivated.
         self.userid_password.insert(user_id.to_string(), "")?;
 
-        // TODO: Unhook 3PID
+        // Free the third party ids, so they can be bound to other accounts
+        for threepid in self.threepids(user_id).collect::<Vec<_>>() {
+            let (medium, address, _) = threepid?;
+            self.remove_threepid(user_id, &medium, &address)?;
+        }
+
         Ok(())
     }
 }
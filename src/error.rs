@@ -32,6 +32,16 @@ pub enum Error {
         #[from]
         source: reqwest::Error,
     },
+    #[error("Could not access the filesystem.")]
+    IoError {
+        #[from]
+        source: std::io::Error,
+    },
+    #[error("Could not send email.")]
+    SmtpError {
+        #[from]
+        source: lettre::smtp::error::Error,
+    },
     #[error("{0}")]
     BadServerResponse(&'static str),
     #[error("{0}")]
@@ -48,6 +58,17 @@ pub enum Error {
     Conflict(&'static str), // This is only needed for when a room alias already exists
 }
 
+impl From<sled::transaction::TransactionError> for Error {
+    fn from(error: sled::transaction::TransactionError) -> Self {
+        match error {
+            sled::transaction::TransactionError::Abort(()) => {
+                Self::bad_database("Database transaction was aborted.")
+            }
+            sled::transaction::TransactionError::Storage(source) => Self::SledError { source },
+        }
+    }
+}
+
 impl Error {
     pub fn bad_database(message: &'static str) -> Self {
         error!("BadDatabase: {}", message);
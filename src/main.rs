@@ -7,15 +7,20 @@ mod database;
 mod error;
 mod pdu;
 mod push_rules;
+mod room_version;
 mod ruma_wrapper;
+mod stateres;
 mod utils;
 
 pub use database::Database;
 pub use error::{Error, Result};
 pub use pdu::PduEvent;
 pub use rocket::State;
-pub use ruma_wrapper::{ConduitResult, Ruma, RumaResponse};
+pub use ruma_wrapper::{
+    AuthenticatedServer, AuthenticatedUser, ConduitResult, Ruma, RumaResponse, XMatrix,
+};
 
+use log::error;
 use rocket::{fairing::AdHoc, routes};
 
 fn setup_rocket() -> rocket::Rocket {
@@ -33,10 +38,21 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::logout_all_route,
                 client_server::change_password_route,
                 client_server::deactivate_route,
+                client_server::request_3pid_email_token_route,
+                client_server::submit_3pid_email_token_route,
+                client_server::submit_3pid_email_token_link_route,
+                client_server::add_3pid_route,
+                client_server::get_3pids_route,
+                client_server::delete_3pid_route,
                 client_server::get_capabilities_route,
                 client_server::get_pushrules_all_route,
+                client_server::get_pushrule_route,
                 client_server::set_pushrule_route,
+                client_server::delete_pushrule_route,
+                client_server::get_pushrule_enabled_route,
                 client_server::set_pushrule_enabled_route,
+                client_server::get_pushrule_actions_route,
+                client_server::set_pushrule_actions_route,
                 client_server::get_room_event_route,
                 client_server::get_filter_route,
                 client_server::create_filter_route,
@@ -65,6 +81,7 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_backup_key_sessions_route,
                 client_server::get_backup_keys_route,
                 client_server::set_read_marker_route,
+                client_server::create_receipt_route,
                 client_server::create_typing_event_route,
                 client_server::create_room_route,
                 client_server::redact_event_route,
@@ -95,6 +112,7 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_state_events_for_key_route,
                 client_server::get_state_events_for_empty_key_route,
                 client_server::sync_events_route,
+                client_server::sliding_sync_route,
                 client_server::get_context_route,
                 client_server::get_message_events_route,
                 client_server::search_events_route,
@@ -118,6 +136,7 @@ fn setup_rocket() -> rocket::Rocket {
                 client_server::get_key_changes_route,
                 client_server::get_pushers_route,
                 client_server::set_pushers_route,
+                client_server::get_notifications_route,
                 client_server::upgrade_room_route,
                 server_server::well_known_server,
                 server_server::get_server_version,
@@ -125,10 +144,21 @@ fn setup_rocket() -> rocket::Rocket {
                 server_server::get_server_keys_deprecated,
                 server_server::get_public_rooms_route,
                 server_server::send_transaction_message_route,
+                server_server::get_event_route,
+                server_server::exchange_third_party_invite_route,
             ],
         )
         .attach(AdHoc::on_attach("Config", |mut rocket| async {
             let data = Database::load_or_create(rocket.config().await).expect("valid config");
+            if let Err(e) = data.media.migrate_to_storage(&data.globals).await {
+                error!("Could not move media files to the media storage: {}", e);
+            }
+            data.rooms.push_queue.start_handler(
+                data.rooms.clone(),
+                data.users.clone(),
+                data.account_data.clone(),
+                &data.globals,
+            );
 
             Ok(rocket.manage(data))
         }))
@@ -1,4 +1,4 @@
-use crate::{Error, Result};
+use crate::{room_version::RoomVersion, utils, Error, Result};
 use js_int::UInt;
 use ruma::{
     events::{
@@ -11,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PduEvent {
     pub event_id: EventId,
     pub room_id: RoomId,
@@ -35,26 +35,10 @@ pub struct PduEvent {
 }
 
 impl PduEvent {
-    pub fn redact(&mut self, reason: &PduEvent) -> Result<()> {
+    pub fn redact(&mut self, reason: &PduEvent, room_version: &RoomVersion) -> Result<()> {
         self.unsigned.clear();
 
-        let allowed: &[&str] = match self.kind {
-            EventType::RoomMember => &["membership"],
-            EventType::RoomCreate => &["creator"],
-            EventType::RoomJoinRules => &["join_rule"],
-            EventType::RoomPowerLevels => &[
-                "ban",
-                "events",
-                "events_default",
-                "kick",
-                "redact",
-                "state_default",
-                "users",
-                "users_default",
-            ],
-            EventType::RoomHistoryVisibility => &["history_visibility"],
-            _ => &[],
-        };
+        let allowed = room_version.redaction_allowed_keys(&self.kind);
 
         let old_content = self
             .content
@@ -81,14 +65,37 @@ impl PduEvent {
         Ok(())
     }
 
-    pub fn to_sync_room_event(&self) -> Raw<AnySyncRoomEvent> {
+    /// Returns `unsigned` as the receiver should see it.
+    ///
+    /// `transaction_id` is only given for the device that sent the event, see
+    /// `TransactionIds::event_txnid`. The `age` is computed from the time the event was sent.
+    fn unsigned_for(
+        &self,
+        transaction_id: Option<&str>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut unsigned = self.unsigned.clone();
+
+        // Older events stored these in the pdu
+        unsigned.remove("device_id");
+        unsigned.remove("transaction_id");
+        if let Some(transaction_id) = transaction_id {
+            unsigned.insert("transaction_id".to_owned(), transaction_id.into());
+        }
+
+        let age = utils::millis_since_unix_epoch().saturating_sub(self.origin_server_ts.into());
+        unsigned.insert("age".to_owned(), age.into());
+
+        unsigned
+    }
+
+    pub fn to_sync_room_event(&self, transaction_id: Option<&str>) -> Raw<AnySyncRoomEvent> {
         let mut json = json!({
             "content": self.content,
             "type": self.kind,
             "event_id": self.event_id,
             "sender": self.sender,
             "origin_server_ts": self.origin_server_ts,
-            "unsigned": self.unsigned,
+            "unsigned": self.unsigned_for(transaction_id),
         });
 
         if let Some(state_key) = &self.state_key {
@@ -101,14 +108,14 @@ impl PduEvent {
         serde_json::from_value(json).expect("Raw::from_value always works")
     }
 
-    pub fn to_room_event(&self) -> Raw<AnyRoomEvent> {
+    pub fn to_room_event(&self, transaction_id: Option<&str>) -> Raw<AnyRoomEvent> {
         let mut json = json!({
             "content": self.content,
             "type": self.kind,
             "event_id": self.event_id,
             "sender": self.sender,
             "origin_server_ts": self.origin_server_ts,
-            "unsigned": self.unsigned,
+            "unsigned": self.unsigned_for(transaction_id),
             "room_id": self.room_id,
         });
 
@@ -129,7 +136,7 @@ impl PduEvent {
             "event_id": self.event_id,
             "sender": self.sender,
             "origin_server_ts": self.origin_server_ts,
-            "unsigned": self.unsigned,
+            "unsigned": self.unsigned_for(None),
             "room_id": self.room_id,
             "state_key": self.state_key,
         });
@@ -144,7 +151,7 @@ impl PduEvent {
             "event_id": self.event_id,
             "sender": self.sender,
             "origin_server_ts": self.origin_server_ts,
-            "unsigned": self.unsigned,
+            "unsigned": self.unsigned_for(None),
             "state_key": self.state_key,
         });
 
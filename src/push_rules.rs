@@ -1,10 +1,15 @@
+use crate::PduEvent;
+use regex::Regex;
 use ruma::{
     push::{
         Action, ConditionalPushRule, ConditionalPushRuleInit, PatternedPushRule,
         PatternedPushRuleInit, PushCondition, RoomMemberCountIs, Ruleset, Tweak,
     },
-    UserId,
+    EventId, RoomId, UserId,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{hash_map::Entry, HashMap};
 
 pub fn default_pushrules(user_id: &UserId) -> Ruleset {
     let mut rules = Ruleset::default();
@@ -12,7 +17,7 @@ pub fn default_pushrules(user_id: &UserId) -> Ruleset {
     rules.override_ = vec![
         master_rule(),
         suppress_notices_rule(),
-        invite_for_me_rule(),
+        invite_for_me_rule(&user_id),
         member_event_rule(),
         contains_display_name_rule(),
         tombstone_rule(),
@@ -53,7 +58,7 @@ pub fn suppress_notices_rule() -> ConditionalPushRule {
     .into()
 }
 
-pub fn invite_for_me_rule() -> ConditionalPushRule {
+pub fn invite_for_me_rule(user_id: &UserId) -> ConditionalPushRule {
     ConditionalPushRuleInit {
         actions: vec![
             Action::Notify,
@@ -63,10 +68,20 @@ pub fn invite_for_me_rule() -> ConditionalPushRule {
         default: true,
         enabled: true,
         rule_id: ".m.rule.invite_for_me".to_owned(),
-        conditions: vec![PushCondition::EventMatch {
-            key: "content.membership".to_owned(),
-            pattern: "m.invite".to_owned(),
-        }],
+        conditions: vec![
+            PushCondition::EventMatch {
+                key: "type".to_owned(),
+                pattern: "m.room.member".to_owned(),
+            },
+            PushCondition::EventMatch {
+                key: "content.membership".to_owned(),
+                pattern: "invite".to_owned(),
+            },
+            PushCondition::EventMatch {
+                key: "state_key".to_owned(),
+                pattern: user_id.to_string(),
+            },
+        ],
     }
     .into()
 }
@@ -245,3 +260,432 @@ pub fn encrypted_rule() -> ConditionalPushRule {
     }
     .into()
 }
+
+/// What should happen for an event according to the push rules of a user.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PushActions {
+    pub notify: bool,
+    pub highlight: bool,
+    pub sound: Option<String>,
+}
+
+impl PushActions {
+    fn from_actions(actions: &[Value]) -> Self {
+        let mut push_actions = Self::default();
+
+        for action in actions {
+            match action {
+                Value::String(action) if action == "notify" || action == "coalesce" => {
+                    push_actions.notify = true
+                }
+                Value::Object(tweak) => match tweak.get("set_tweak").and_then(|t| t.as_str()) {
+                    // A highlight tweak without a value means true
+                    Some("highlight") => {
+                        push_actions.highlight =
+                            tweak.get("value").and_then(|v| v.as_bool()).unwrap_or(true)
+                    }
+                    Some("sound") => {
+                        push_actions.sound = tweak
+                            .get("value")
+                            .and_then(|v| v.as_str())
+                            .map(str::to_owned)
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        // Tweaks only matter for events that notify
+        if !push_actions.notify {
+            push_actions = Self::default();
+        }
+
+        push_actions
+    }
+
+    /// Returns the actions in the format of push rules.
+    pub fn to_actions(&self) -> Vec<Value> {
+        if !self.notify {
+            return vec![json!("dont_notify")];
+        }
+
+        let mut actions = vec![
+            json!("notify"),
+            json!({ "set_tweak": "highlight", "value": self.highlight }),
+        ];
+        if let Some(sound) = &self.sound {
+            actions.push(json!({ "set_tweak": "sound", "value": sound }));
+        }
+        actions
+    }
+}
+
+/// An event that notified a user, as stored after push rule evaluation.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Notification {
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    pub actions: PushActions,
+    /// When the event was evaluated. Old notifications are removed after a while
+    #[serde(default)]
+    pub ts: u64,
+}
+
+/// The parts of the room that push rule conditions depend on.
+pub struct PushContext<'a> {
+    pub display_name: Option<&'a str>,
+    pub member_count: u64,
+    /// The content of the m.room.power_levels event
+    pub power_levels: Option<&'a Value>,
+}
+
+/// Compiled patterns of push rules. Most users share the default rules, so evaluating one event
+/// for all members of a room compiles each pattern once.
+#[derive(Default)]
+pub struct PatternCache {
+    regexes: HashMap<String, Option<Regex>>,
+}
+
+impl PatternCache {
+    fn is_match(&mut self, expression: String, value: &str) -> bool {
+        let regex = match self.regexes.entry(expression) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let regex = Regex::new(entry.key()).ok();
+                entry.insert(regex)
+            }
+        };
+
+        regex.as_ref().map_or(false, |regex| regex.is_match(value))
+    }
+}
+
+/// Evaluates the push rules of a user for an event. `ruleset` is the json of the global rules and
+/// `event` the json of the pdu, so they are only serialized once. The actions of the first
+/// matching rule are returned.
+pub fn evaluate(
+    ruleset: &Value,
+    pdu: &PduEvent,
+    event: &Value,
+    context: &PushContext<'_>,
+    cache: &mut PatternCache,
+) -> PushActions {
+    // Rule kinds in order of priority
+    for kind in &["override", "content", "room", "sender", "underride"] {
+        let rules = ruleset.get(kind).and_then(|rules| rules.as_array());
+        for rule in rules.into_iter().flatten() {
+            if rule.get("enabled").and_then(|e| e.as_bool()) == Some(false) {
+                continue;
+            }
+
+            let rule_id = rule
+                .get("rule_id")
+                .and_then(|id| id.as_str())
+                .unwrap_or_default();
+
+            let matches = match *kind {
+                "content" => rule
+                    .get("pattern")
+                    .and_then(|p| p.as_str())
+                    .map_or(false, |pattern| {
+                        event_match(event, "content.body", pattern, cache)
+                    }),
+                "room" => pdu.room_id.as_str() == rule_id,
+                "sender" => pdu.sender.as_str() == rule_id,
+                _ => rule
+                    .get("conditions")
+                    .and_then(|c| c.as_array())
+                    .into_iter()
+                    .flatten()
+                    .all(|condition| condition_matches(condition, event, pdu, context, cache)),
+            };
+
+            if matches {
+                return PushActions::from_actions(
+                    rule.get("actions")
+                        .and_then(|a| a.as_array())
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                );
+            }
+        }
+    }
+
+    PushActions::default()
+}
+
+fn condition_matches(
+    condition: &Value,
+    event: &Value,
+    pdu: &PduEvent,
+    context: &PushContext<'_>,
+    cache: &mut PatternCache,
+) -> bool {
+    let field = |name: &str| condition.get(name).and_then(|v| v.as_str());
+
+    match field("kind") {
+        Some("event_match") => match (field("key"), field("pattern")) {
+            (Some(key), Some(pattern)) => event_match(event, key, pattern, cache),
+            _ => false,
+        },
+        Some("contains_display_name") => {
+            match (
+                context.display_name.filter(|name| !name.is_empty()),
+                pdu.content.get("body").and_then(|b| b.as_str()),
+            ) {
+                (Some(display_name), Some(body)) => {
+                    regex_matches(&regex::escape(display_name), body, true, cache)
+                }
+                _ => false,
+            }
+        }
+        Some("room_member_count") => {
+            field("is").map_or(false, |is| member_count_is(is, context.member_count))
+        }
+        Some("sender_notification_permission") => field("key").map_or(false, |key| {
+            sender_notification_permission(&pdu.sender, key, context.power_levels)
+        }),
+        // Conditions we don't know never match
+        _ => false,
+    }
+}
+
+/// Checks if the field at the dotted `key` of the event matches the glob `pattern`.
+/// `content.body` matches if the pattern matches any of its words.
+fn event_match(event: &Value, key: &str, pattern: &str, cache: &mut PatternCache) -> bool {
+    let value = key
+        .split('.')
+        .try_fold(event, |value, part| value.get(part))
+        .and_then(|value| value.as_str());
+
+    let mut expression = String::new();
+    for c in pattern.chars() {
+        match c {
+            '*' => expression.push_str(".*?"),
+            '?' => expression.push('.'),
+            c => expression.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    value.map_or(false, |value| {
+        regex_matches(&expression, value, key == "content.body", cache)
+    })
+}
+
+/// Matches case-insensitively. With `words`, the expression can match whole words anywhere in the
+/// value, otherwise it has to match the whole value.
+fn regex_matches(expression: &str, value: &str, words: bool, cache: &mut PatternCache) -> bool {
+    let expression = if words {
+        format!(r"(?is)(^|\W){}(\W|$)", expression)
+    } else {
+        format!("(?is)^{}$", expression)
+    };
+
+    cache.is_match(expression, value)
+}
+
+/// Compares the member count with a condition like `2`, `==2`, `<10` or `>=5`.
+fn member_count_is(is: &str, member_count: u64) -> bool {
+    let number_start = is
+        .find(|c: char| c.is_ascii_digit())
+        .unwrap_or_else(|| is.len());
+    let (prefix, number) = is.split_at(number_start);
+
+    let number = match number.parse::<u64>() {
+        Ok(number) => number,
+        Err(_) => return false,
+    };
+
+    match prefix {
+        "" | "==" => member_count == number,
+        "<" => member_count < number,
+        ">" => member_count > number,
+        "<=" => member_count <= number,
+        ">=" => member_count >= number,
+        _ => false,
+    }
+}
+
+/// Checks if the sender has the power level needed to send notifications of this kind, like
+/// `@room` notifications.
+fn sender_notification_permission(
+    sender: &UserId,
+    key: &str,
+    power_levels: Option<&Value>,
+) -> bool {
+    let power_levels = match power_levels {
+        Some(power_levels) => power_levels,
+        None => return false,
+    };
+
+    // Old rooms can have power levels as strings
+    let level = |value: Option<&Value>| {
+        value.and_then(|v| {
+            v.as_i64()
+                .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+        })
+    };
+
+    let sender_level = level(
+        power_levels
+            .get("users")
+            .and_then(|users| users.get(sender.as_str())),
+    )
+    .or_else(|| level(power_levels.get("users_default")))
+    .unwrap_or(0);
+
+    let required_level = level(
+        power_levels
+            .get("notifications")
+            .and_then(|notifications| notifications.get(key)),
+    )
+    .unwrap_or(50);
+
+    sender_level >= required_level
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn matches(key: &str, value: &str, pattern: &str) -> bool {
+        let mut event = json!({});
+        let mut field = &mut event;
+        for part in key.split('.') {
+            field = &mut field[part];
+        }
+        *field = json!(value);
+
+        event_match(&event, key, pattern, &mut PatternCache::default())
+    }
+
+    #[test]
+    fn glob_matches_whole_value() {
+        assert!(matches("type", "m.room.message", "m.room.message"));
+        assert!(matches("type", "m.room.message", "m.room.*"));
+        assert!(matches("type", "m.room.message", "M.ROOM.MESSAGE"));
+        assert!(matches("type", "m.call.invite", "m.call.invit?"));
+        assert!(!matches("type", "m.room.message", "m.room"));
+        assert!(!matches("type", "m.room.message", "room.*"));
+        assert!(!matches("type", "m.room.message", "m.room.messag?s"));
+    }
+
+    #[test]
+    fn glob_escapes_regex_syntax() {
+        assert!(matches("type", "m.room.message", "m.room.message"));
+        assert!(!matches("type", "mxroomxmessage", "m.room.message"));
+        assert!(matches("state_key", "[a]+", "[a]+"));
+        assert!(!matches("state_key", "aa", "[a]+"));
+    }
+
+    #[test]
+    fn glob_matches_words_of_body() {
+        assert!(matches("content.body", "hello alice!", "alice"));
+        assert!(matches("content.body", "Alice: hi", "alice"));
+        assert!(matches("content.body", "hi @room", "@room"));
+        assert!(matches("content.body", "hello alice", "ali*"));
+        assert!(!matches("content.body", "hello alicebob", "alice"));
+        assert!(!matches("content.body", "malice", "alice"));
+    }
+
+    #[test]
+    fn glob_needs_string_value() {
+        let event = json!({ "content": { "body": 5 } });
+        assert!(!event_match(
+            &event,
+            "content.body",
+            "5",
+            &mut PatternCache::default()
+        ));
+        assert!(!event_match(
+            &event,
+            "content.missing",
+            "*",
+            &mut PatternCache::default()
+        ));
+    }
+
+    #[test]
+    fn member_count_comparisons() {
+        assert!(member_count_is("2", 2));
+        assert!(!member_count_is("2", 3));
+        assert!(member_count_is("==2", 2));
+        assert!(member_count_is("<10", 9));
+        assert!(!member_count_is("<10", 10));
+        assert!(member_count_is(">5", 6));
+        assert!(!member_count_is(">5", 5));
+        assert!(member_count_is("<=10", 10));
+        assert!(member_count_is(">=5", 5));
+        assert!(!member_count_is(">=5", 4));
+    }
+
+    #[test]
+    fn member_count_rejects_invalid_conditions() {
+        assert!(!member_count_is("", 0));
+        assert!(!member_count_is("==", 0));
+        assert!(!member_count_is("!=2", 3));
+        assert!(!member_count_is("=2", 2));
+        assert!(!member_count_is("2x", 2));
+    }
+
+    #[test]
+    fn notification_permission_uses_power_levels() {
+        let alice = UserId::try_from("@alice:example.com").unwrap();
+        let bob = UserId::try_from("@bob:example.com").unwrap();
+        let power_levels = json!({
+            "users": { "@alice:example.com": 50 },
+            "users_default": 0,
+        });
+
+        assert!(sender_notification_permission(
+            &alice,
+            "room",
+            Some(&power_levels)
+        ));
+        assert!(!sender_notification_permission(
+            &bob,
+            "room",
+            Some(&power_levels)
+        ));
+        assert!(!sender_notification_permission(&alice, "room", None));
+    }
+
+    #[test]
+    fn notification_permission_with_custom_levels() {
+        let alice = UserId::try_from("@alice:example.com").unwrap();
+        let power_levels = json!({
+            "users_default": "10",
+            "notifications": { "room": 10, "other": "20" },
+        });
+
+        assert!(sender_notification_permission(
+            &alice,
+            "room",
+            Some(&power_levels)
+        ));
+        assert!(!sender_notification_permission(
+            &alice,
+            "other",
+            Some(&power_levels)
+        ));
+    }
+
+    #[test]
+    fn actions_without_notify_are_ignored() {
+        let actions = PushActions::from_actions(&[
+            json!("notify"),
+            json!({ "set_tweak": "highlight" }),
+            json!({ "set_tweak": "sound", "value": "default" }),
+        ]);
+        assert!(actions.notify);
+        assert!(actions.highlight);
+        assert_eq!(actions.sound.as_deref(), Some("default"));
+
+        let actions =
+            PushActions::from_actions(&[json!("dont_notify"), json!({ "set_tweak": "highlight" })]);
+        assert!(!actions.notify);
+        assert!(!actions.highlight);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{Error, Result};
+use ruma::{api::client::error::ErrorKind, events::EventType, EventId, RoomVersionId};
+use std::convert::TryFrom;
+
+/// How the id of an event is derived from the event.
+pub enum EventIdFormat {
+    /// `$` followed by the base64 encoded reference hash (v3)
+    Base64,
+    /// `$` followed by the url safe base64 encoded reference hash (v4 and newer)
+    UrlSafeBase64,
+}
+
+/// Everything that differs between the room versions we support.
+pub struct RoomVersion {
+    pub id: RoomVersionId,
+    pub stable: bool,
+    pub event_id_format: EventIdFormat,
+    /// m.room.aliases events can only be sent by the server in the state key, regardless of power
+    /// levels, and they keep their aliases when redacted (v1 to v5)
+    pub special_case_aliases: bool,
+    /// Power levels can't be strings (v6 and newer)
+    pub integer_power_levels: bool,
+    /// Changes to the `notifications` power levels need enough power, like all other levels (v6
+    /// and newer)
+    pub limit_notifications_power_levels: bool,
+}
+
+/// All room versions this server supports. Adding a version only needs a new entry here.
+static ROOM_VERSIONS: &[RoomVersion] = &[
+    RoomVersion {
+        id: RoomVersionId::Version3,
+        stable: true,
+        event_id_format: EventIdFormat::Base64,
+        special_case_aliases: true,
+        integer_power_levels: false,
+        limit_notifications_power_levels: false,
+    },
+    RoomVersion {
+        id: RoomVersionId::Version4,
+        stable: true,
+        event_id_format: EventIdFormat::UrlSafeBase64,
+        special_case_aliases: true,
+        integer_power_levels: false,
+        limit_notifications_power_levels: false,
+    },
+    RoomVersion {
+        id: RoomVersionId::Version5,
+        stable: true,
+        event_id_format: EventIdFormat::UrlSafeBase64,
+        special_case_aliases: true,
+        integer_power_levels: false,
+        limit_notifications_power_levels: false,
+    },
+    RoomVersion {
+        id: RoomVersionId::Version6,
+        stable: true,
+        event_id_format: EventIdFormat::UrlSafeBase64,
+        special_case_aliases: false,
+        integer_power_levels: true,
+        limit_notifications_power_levels: true,
+    },
+];
+
+/// The version of new rooms if the client doesn't ask for a specific one.
+pub static DEFAULT_ROOM_VERSION: RoomVersionId = RoomVersionId::Version6;
+
+impl RoomVersion {
+    /// Returns the rules of a room version or None if we don't support it.
+    pub fn get(id: &RoomVersionId) -> Option<&'static RoomVersion> {
+        ROOM_VERSIONS.iter().find(|version| &version.id == id)
+    }
+
+    /// Like `get`, but returns an error for unsupported room versions.
+    pub fn get_supported(id: &RoomVersionId) -> Result<&'static RoomVersion> {
+        Self::get(id).ok_or(Error::BadRequest(
+            ErrorKind::UnsupportedRoomVersion,
+            "This server does not support that room version.",
+        ))
+    }
+
+    /// Returns all room versions this server supports.
+    pub fn supported() -> impl Iterator<Item = &'static RoomVersion> {
+        ROOM_VERSIONS.iter()
+    }
+
+    /// Returns the room version of a room given the content of its m.room.create event.
+    pub fn from_create_content(content: &serde_json::Value) -> Result<&'static RoomVersion> {
+        // Rooms without a version in the create event are version 1, which this server doesn't
+        // support
+        let id = content
+            .get("room_version")
+            .cloned()
+            .ok_or(Error::BadRequest(
+                ErrorKind::UnsupportedRoomVersion,
+                "This server does not support room version 1.",
+            ))?;
+
+        Self::get_supported(
+            &serde_json::from_value::<RoomVersionId>(id)
+                .map_err(|_| Error::bad_database("Invalid room version in m.room.create event."))?,
+        )
+    }
+
+    /// Calculates the id of an event.
+    pub fn event_id(&self, pdu_json: &serde_json::Value) -> Result<EventId> {
+        // Ruma encodes reference hashes url safe
+        let hash = ruma::signatures::reference_hash(pdu_json)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid PDU."))?;
+
+        let hash = match self.event_id_format {
+            EventIdFormat::Base64 => hash.replace('-', "+").replace('_', "/"),
+            EventIdFormat::UrlSafeBase64 => hash,
+        };
+
+        EventId::try_from(&*format!("${}", hash))
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid PDU."))
+    }
+
+    /// Returns the content keys that survive a redaction.
+    pub fn redaction_allowed_keys(&self, kind: &EventType) -> &'static [&'static str] {
+        match kind {
+            EventType::RoomMember => &["membership"],
+            EventType::RoomCreate => &["creator"],
+            EventType::RoomJoinRules => &["join_rule"],
+            EventType::RoomPowerLevels => &[
+                "ban",
+                "events",
+                "events_default",
+                "kick",
+                "redact",
+                "state_default",
+                "users",
+                "users_default",
+            ],
+            EventType::RoomHistoryVisibility => &["history_visibility"],
+            EventType::RoomAliases if self.special_case_aliases => &["aliases"],
+            _ => &[],
+        }
+    }
+
+    /// Reads a power level from an m.room.power_levels event.
+    pub fn power_level(&self, value: &serde_json::Value) -> Option<i64> {
+        if self.integer_power_levels {
+            value.as_i64()
+        } else {
+            crate::stateres::int_value(value)
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use crate::Error;
-use ruma::identifiers::{DeviceId, UserId};
+use ruma::identifiers::{DeviceId, ServerName, UserId};
 use std::{convert::TryInto, ops::Deref};
 
 #[cfg(feature = "conduit_bin")]
@@ -12,6 +12,7 @@ use {
         },
         http::Status,
         outcome::Outcome::*,
+        request::{self, FromRequest},
         response::{self, Responder},
         tokio::io::AsyncReadExt,
         Request, State,
@@ -26,6 +27,8 @@ pub struct Ruma<T> {
     pub body: T,
     pub sender_id: Option<UserId>,
     pub device_id: Option<Box<DeviceId>>,
+    /// The server that sent a federation request, verified with its X-Matrix signature
+    pub origin: Option<Box<ServerName>>,
     pub json_body: Option<Box<serde_json::value::RawValue>>, // This is None when body is not a valid string
 }
 
@@ -53,24 +56,44 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                 .await
                 .expect("database was loaded");
 
-            let (user_id, device_id) = if T::METADATA.requires_authentication {
-                // Get token from header or query value
-                let token = match request
-                    .headers()
-                    .get_one("Authorization")
-                    .map(|s| s[7..].to_owned()) // Split off "Bearer "
-                    .or_else(|| request.get_query_value("access_token").and_then(|r| r.ok()))
-                {
-                    // TODO: M_MISSING_TOKEN
+            let limit = db.globals.max_request_size();
+            let mut handle = data.open().take(limit.into());
+            let mut body = Vec::new();
+            handle.read_to_end(&mut body).await.unwrap();
+
+            // Every federation request has to be signed by the server that sent it
+            let origin = if request.uri().path().starts_with("/_matrix/federation/") {
+                let x_matrix = match XMatrix::parse(request) {
+                    Some(x_matrix) => x_matrix,
                     None => return Failure((Status::Unauthorized, ())),
-                    Some(token) => token,
                 };
+                let content = if body.is_empty() {
+                    None
+                } else {
+                    match serde_json::from_slice(&body) {
+                        Ok(content) => Some(content),
+                        Err(_) => return Failure((Status::BadRequest, ())),
+                    }
+                };
+
+                match crate::server_server::verify_server_request(&db, &x_matrix, content.as_ref())
+                    .await
+                {
+                    Ok(origin) => Some(origin),
+                    Err(e) => {
+                        warn!("Rejected federation request: {}", e);
+                        return Failure((Status::Unauthorized, ()));
+                    }
+                }
+            } else {
+                None
+            };
 
-                // Check if token is valid
-                match db.users.find_from_token(&token).unwrap() {
-                    // TODO: M_UNKNOWN_TOKEN
+            let (user_id, device_id) = if T::METADATA.requires_authentication && origin.is_none() {
+                match authenticate(request, &db) {
+                    // TODO: M_MISSING_TOKEN and M_UNKNOWN_TOKEN
                     None => return Failure((Status::Unauthorized, ())),
-                    Some((user_id, device_id)) => (Some(user_id), Some(device_id.into())),
+                    Some((user_id, device_id)) => (Some(user_id), Some(device_id)),
                 }
             } else {
                 (None, None)
@@ -83,11 +106,6 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                 http_request = http_request.header(header.name.as_str(), &*header.value);
             }
 
-            let limit = db.globals.max_request_size();
-            let mut handle = data.open().take(limit.into());
-            let mut body = Vec::new();
-            handle.read_to_end(&mut body).await.unwrap();
-
             let http_request = http_request.body(body.clone()).unwrap();
             log::info!("{:?}", http_request);
 
@@ -96,6 +114,7 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
                     body: t,
                     sender_id: user_id,
                     device_id,
+                    origin,
                     // TODO: Can we avoid parsing it again? (We only need this for append_pdu)
                     json_body: utils::string_from_bytes(&body)
                         .ok()
@@ -110,6 +129,145 @@ impl<'a, T: IncomingRequest> FromTransformedData<'a> for Ruma<T> {
     }
 }
 
+/// Finds the user and device of the access token in the request.
+#[cfg(feature = "conduit_bin")]
+fn authenticate(
+    request: &Request<'_>,
+    db: &crate::Database<'_>,
+) -> Option<(UserId, Box<DeviceId>)> {
+    // Get token from header or query value
+    let token = request
+        .headers()
+        .get_one("Authorization")
+        .map(|s| s[7..].to_owned()) // Split off "Bearer "
+        .or_else(|| request.get_query_value("access_token").and_then(|r| r.ok()))?;
+
+    // Check if token is valid
+    db.users
+        .find_from_token(&token)
+        .unwrap()
+        .map(|(user_id, device_id)| (user_id, device_id.into()))
+}
+
+/// The X-Matrix authorization header of a request from another server. It has to be verified
+/// with `server_server::verify_server_request` together with the request body.
+pub struct XMatrix {
+    pub origin: Box<ServerName>,
+    pub key: String,
+    pub sig: String,
+    pub method: String,
+    pub uri: String,
+}
+
+impl XMatrix {
+    #[cfg(feature = "conduit_bin")]
+    fn parse(request: &Request<'_>) -> Option<Self> {
+        Self::from_header(
+            request.headers().get_one("Authorization")?,
+            request.method().to_string(),
+            request.uri().to_string(),
+        )
+    }
+
+    /// Parses `X-Matrix origin=example.org,key="ed25519:key1",sig="..."`.
+    fn from_header(header: &str, method: String, uri: String) -> Option<Self> {
+        let params = header.strip_prefix("X-Matrix ")?;
+
+        let (mut origin, mut key, mut sig) = (None, None, None);
+        for param in params.split(',') {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next()?.trim();
+            let value = parts.next()?.trim().trim_matches('"').to_owned();
+            match name {
+                "origin" => origin = Some(value),
+                "key" => key = Some(value),
+                "sig" => sig = Some(value),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            origin: origin?.try_into().ok()?,
+            key: key?,
+            sig: sig?,
+            method,
+            uri,
+        })
+    }
+}
+
+#[cfg(feature = "conduit_bin")]
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for XMatrix {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match XMatrix::parse(request) {
+            Some(x_matrix) => Success(x_matrix),
+            None => Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Request guard for federation routes without a ruma request type and without a body.
+pub struct AuthenticatedServer {
+    pub origin: Box<ServerName>,
+}
+
+#[cfg(feature = "conduit_bin")]
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedServer {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let db = request
+            .guard::<State<'_, crate::Database<'_>>>()
+            .await
+            .expect("database was loaded");
+
+        let x_matrix = match XMatrix::parse(request) {
+            Some(x_matrix) => x_matrix,
+            None => return Failure((Status::Unauthorized, ())),
+        };
+
+        match crate::server_server::verify_server_request(&db, &x_matrix, None).await {
+            Ok(origin) => Success(AuthenticatedServer { origin }),
+            Err(e) => {
+                warn!("Rejected federation request: {}", e);
+                Failure((Status::Unauthorized, ()))
+            }
+        }
+    }
+}
+
+/// Request guard for routes without a ruma request type that need an access token.
+pub struct AuthenticatedUser {
+    pub sender_id: UserId,
+    pub device_id: Box<DeviceId>,
+}
+
+#[cfg(feature = "conduit_bin")]
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let db = request
+            .guard::<State<'_, crate::Database<'_>>>()
+            .await
+            .expect("database was loaded");
+
+        match authenticate(request, &db) {
+            // TODO: M_MISSING_TOKEN and M_UNKNOWN_TOKEN
+            None => Failure((Status::Unauthorized, ())),
+            Some((sender_id, device_id)) => Success(AuthenticatedUser {
+                sender_id,
+                device_id,
+            }),
+        }
+    }
+}
+
 impl<T> Deref for Ruma<T> {
     type Target = T;
 
@@ -169,3 +327,50 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::XMatrix;
+
+    fn parse(header: &str) -> Option<XMatrix> {
+        XMatrix::from_header(
+            header,
+            "GET".to_owned(),
+            "/_matrix/federation/v1/version".to_owned(),
+        )
+    }
+
+    #[test]
+    fn parses_quoted_and_unquoted_params() {
+        let x_matrix =
+            parse(r#"X-Matrix origin=example.org,key="ed25519:key1",sig="ABCDEF""#).unwrap();
+
+        assert_eq!(x_matrix.origin.as_str(), "example.org");
+        assert_eq!(x_matrix.key, "ed25519:key1");
+        assert_eq!(x_matrix.sig, "ABCDEF");
+        assert_eq!(x_matrix.method, "GET");
+        assert_eq!(x_matrix.uri, "/_matrix/federation/v1/version");
+    }
+
+    #[test]
+    fn ignores_order_spaces_and_unknown_params() {
+        let x_matrix = parse(concat!(
+            r#"X-Matrix sig="ABC=", key = "ed25519:key1", "#,
+            r#"destination="other.org", origin="example.org:8448""#,
+        ))
+        .unwrap();
+
+        assert_eq!(x_matrix.origin.as_str(), "example.org:8448");
+        assert_eq!(x_matrix.key, "ed25519:key1");
+        assert_eq!(x_matrix.sig, "ABC=");
+    }
+
+    #[test]
+    fn rejects_incomplete_headers() {
+        assert!(parse(r#"Bearer origin=example.org,key="ed25519:key1",sig="ABC""#).is_none());
+        assert!(parse(r#"X-Matrix origin=example.org,key="ed25519:key1""#).is_none());
+        assert!(parse(r#"X-Matrix key="ed25519:key1",sig="ABC""#).is_none());
+        assert!(parse(r#"X-Matrix origin=example.org,key,sig="ABC""#).is_none());
+        assert!(parse(r#"X-Matrix origin=,key="ed25519:key1",sig="ABC""#).is_none());
+    }
+}
@@ -1,6 +1,10 @@
-use crate::{client_server, ConduitResult, Database, Error, Result, Ruma};
+use crate::{
+    client_server, utils, AuthenticatedServer, ConduitResult, Database, Error, Result, Ruma,
+    XMatrix,
+};
 use http::header::{HeaderValue, AUTHORIZATION};
 use rocket::{get, post, put, response::content::Json, State};
+use ruma::api::client::error::ErrorKind;
 use ruma::api::federation::{
     directory::get_public_rooms,
     discovery::{
@@ -9,6 +13,12 @@ use ruma::api::federation::{
     transactions::send_transaction_message,
 };
 use ruma::api::{client, OutgoingRequest};
+use ruma::{
+    events::room::member,
+    signatures::{PublicKeyMap, Verified},
+    EventId, RoomId, ServerName, UserId,
+};
+use serde::Deserialize;
 use serde_json::json;
 use std::{
     collections::BTreeMap,
@@ -41,6 +51,19 @@ pub async fn send_request<T: OutgoingRequest>(
     destination: String,
     request: T,
 ) -> Result<T::IncomingResponse>
+where
+    T: Debug,
+{
+    send_limited_request(db, destination, request, None).await
+}
+
+/// Like `send_request`, but fails if the response body is larger than `max_size` bytes.
+pub async fn send_limited_request<T: OutgoingRequest>(
+    db: &crate::Database<'static>,
+    destination: String,
+    request: T,
+    max_size: Option<u64>,
+) -> Result<T::IncomingResponse>
 where
     T: Debug,
 {
@@ -130,21 +153,123 @@ where
                 }
             }
 
-            let body = reqwest_response
-                .bytes()
-                .await
-                .unwrap()
-                .into_iter()
-                .collect();
-            Ok(
-                T::IncomingResponse::try_from(http_response.body(body).unwrap())
-                    .expect("TODO: error handle other server errors"),
-            )
+            if let (Some(max_size), Some(length)) = (max_size, reqwest_response.content_length()) {
+                if length > max_size {
+                    return Err(Error::BadServerResponse("Server response is too large."));
+                }
+            }
+
+            // Read the body in chunks to stop early when the content length was missing or wrong
+            let mut body = Vec::new();
+            while let Some(chunk) = reqwest_response.chunk().await? {
+                if max_size.map_or(false, |max_size| {
+                    (body.len() + chunk.len()) as u64 > max_size
+                }) {
+                    return Err(Error::BadServerResponse("Server response is too large."));
+                }
+                body.extend_from_slice(&chunk);
+            }
+
+            T::IncomingResponse::try_from(http_response.body(body).unwrap()).map_err(|_| {
+                Error::BadServerResponse("Server returned an error or an invalid response.")
+            })
         }
         Err(e) => Err(e.into()),
     }
 }
 
+/// Returns the verify keys (key id -> base64 public key) of another server. They are fetched from
+/// the server if they are not cached or expired.
+pub async fn signing_keys(
+    db: &crate::Database<'_>,
+    origin: &ServerName,
+) -> Result<BTreeMap<String, String>> {
+    if let Some(keys) = db.globals.signing_keys_for(origin)? {
+        return Ok(keys);
+    }
+
+    let destination = "https://".to_owned()
+        + &request_well_known(db, origin.as_str())
+            .await
+            .unwrap_or(origin.as_str().to_owned() + ":8448");
+
+    let response = serde_json::from_str::<serde_json::Value>(
+        &db.globals
+            .reqwest_client()
+            .get(&format!("{}/_matrix/key/v2/server", destination))
+            .send()
+            .await?
+            .text()
+            .await?,
+    )
+    .map_err(|_| Error::BadServerResponse("Invalid server keys."))?;
+
+    if response.get("server_name").and_then(|s| s.as_str()) != Some(origin.as_str()) {
+        return Err(Error::BadServerResponse(
+            "Server keys are for a different server.",
+        ));
+    }
+
+    let keys = response
+        .get("verify_keys")
+        .and_then(|keys| keys.as_object())
+        .into_iter()
+        .flatten()
+        .filter_map(|(key_id, key)| Some((key_id.clone(), key.get("key")?.as_str()?.to_owned())))
+        .collect::<BTreeMap<_, _>>();
+
+    // The server has to sign its keys with themselves
+    let mut public_key_map = PublicKeyMap::new();
+    public_key_map.insert(origin.as_str().to_owned(), keys.clone());
+    ruma::signatures::verify_json(&public_key_map, &response)
+        .map_err(|_| Error::BadServerResponse("Server keys are not signed correctly."))?;
+
+    // Keys are cached for at most a week
+    let valid_until_ts = response
+        .get("valid_until_ts")
+        .and_then(|ts| ts.as_u64())
+        .unwrap_or(0)
+        .min(utils::millis_since_unix_epoch() + 7 * 24 * 60 * 60 * 1000);
+    db.globals
+        .add_signing_keys(origin, keys.clone(), valid_until_ts)?;
+
+    Ok(keys)
+}
+
+/// Checks the X-Matrix signature of a request from another server and returns that server.
+pub async fn verify_server_request(
+    db: &crate::Database<'_>,
+    x_matrix: &XMatrix,
+    content: Option<&serde_json::Value>,
+) -> Result<Box<ServerName>> {
+    let keys = signing_keys(db, &x_matrix.origin).await?;
+
+    let mut key_signature = serde_json::Map::new();
+    key_signature.insert(x_matrix.key.clone(), x_matrix.sig.clone().into());
+    let mut signatures = serde_json::Map::new();
+    signatures.insert(x_matrix.origin.as_str().to_owned(), key_signature.into());
+
+    let mut request_map = serde_json::Map::new();
+    request_map.insert("method".to_owned(), x_matrix.method.clone().into());
+    request_map.insert("uri".to_owned(), x_matrix.uri.clone().into());
+    request_map.insert("origin".to_owned(), x_matrix.origin.as_str().into());
+    request_map.insert(
+        "destination".to_owned(),
+        db.globals.server_name().as_str().into(),
+    );
+    if let Some(content) = content {
+        request_map.insert("content".to_owned(), content.clone());
+    }
+    request_map.insert("signatures".to_owned(), signatures.into());
+
+    let mut public_key_map = PublicKeyMap::new();
+    public_key_map.insert(x_matrix.origin.as_str().to_owned(), keys);
+    ruma::signatures::verify_json(&public_key_map, &request_map.into())
+        .map_err(|_| Error::BadRequest(ErrorKind::Forbidden, "Invalid X-Matrix signature."))?;
+
+    Ok(x_matrix.origin.clone())
+}
+
 #[cfg_attr(feature = "conduit_bin", get("/.well-known/matrix/server"))]
 pub fn well_known_server() -> Json<String> {
     rocket::response::content::Json(json!({ "m.server": "pc.koesters.xyz:59003"}).to_string())
@@ -215,6 +340,7 @@ pub async fn get_public_rooms_route(
             },
         sender_id,
         device_id,
+        origin,
         json_body,
     } = body;
 
@@ -235,6 +361,7 @@ pub async fn get_public_rooms_route(
             },
             sender_id,
             device_id,
+            origin,
             json_body,
         },
     )
@@ -268,13 +395,179 @@ pub async fn get_public_rooms_route(
     feature = "conduit_bin",
     put("/_matrix/federation/v1/send/<_>", data = "<body>")
 )]
-pub fn send_transaction_message_route(
-    _db: State<'_, Database<'_>>,
+pub async fn send_transaction_message_route(
+    db: State<'_, Database<'_>>,
     body: Ruma<send_transaction_message::v1::Request>,
 ) -> ConduitResult<send_transaction_message::v1::Response> {
-    dbg!(&*body);
-    Ok(send_transaction_message::v1::Response {
-        pdus: BTreeMap::new(),
+    let origin = body
+        .origin
+        .as_ref()
+        .expect("federation requests are authenticated");
+
+    if body.body.origin.as_str() != origin.as_str() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Transaction origin does not match the signing server.",
+        ));
     }
-    .into())
+
+    let mut public_key_map = PublicKeyMap::new();
+    public_key_map.insert(origin.as_str().to_owned(), signing_keys(&db, origin).await?);
+
+    let mut pdus = BTreeMap::new();
+
+    for pdu in &body.pdus {
+        let mut pdu_json = serde_json::to_value(pdu).expect("pdus can be serialized");
+
+        let event_id = match db.rooms.remote_event_id(&pdu_json) {
+            Ok(event_id) => event_id,
+            Err(_) => continue,
+        };
+
+        // Servers can only send events of their own users
+        match pdu_json
+            .get("sender")
+            .and_then(|sender| sender.as_str())
+            .and_then(|sender| UserId::try_from(sender).ok())
+        {
+            Some(sender) if sender.server_name().as_str() == origin.as_str() => {}
+            _ => {
+                pdus.insert(
+                    event_id,
+                    Err("Sender does not belong to the origin server.".to_owned()),
+                );
+                continue;
+            }
+        }
+
+        match ruma::signatures::verify_event(&public_key_map, &pdu_json) {
+            Ok(Verified::All) => {}
+            // The signatures only cover the redacted event, so it is still valid, but its content
+            // can't be trusted
+            Ok(Verified::Signatures) => {
+                if let Err(e) = db.rooms.redact_remote_pdu(&mut pdu_json) {
+                    pdus.insert(event_id, Err(e.to_string()));
+                    continue;
+                }
+            }
+            Err(_) => {
+                pdus.insert(event_id, Err("Invalid signature.".to_owned()));
+                continue;
+            }
+        }
+
+        let result = db
+            .rooms
+            .append_remote_pdu(pdu_json, &db.globals, &db.account_data)
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+
+        pdus.insert(event_id, result);
+    }
+
+    Ok(send_transaction_message::v1::Response { pdus }.into())
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    get("/_matrix/federation/v1/event/<event_id>")
+)]
+pub fn get_event_route(
+    db: State<'_, Database<'_>>,
+    server: AuthenticatedServer,
+    event_id: String,
+) -> Result<Json<String>> {
+    let event_id = EventId::try_from(event_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid event id."))?;
+
+    // Rejected and soft failed events might be part of auth chains, so we return them too
+    let pdu_json = db
+        .rooms
+        .get_pdu_json_with_outliers(&event_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+    let pdu = db
+        .rooms
+        .get_pdu_with_outliers(&event_id)?
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+
+    if !db.rooms.server_can_see_event(&server.origin, &pdu)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Server is not allowed to see this event.",
+        ));
+    }
+
+    Ok(Json(
+        json!({
+            "origin": db.globals.server_name(),
+            "origin_server_ts": utils::millis_since_unix_epoch(),
+            "pdus": [pdu_json],
+        })
+        .to_string(),
+    ))
+}
+
+/// The invite event another server asks us to sign for one of our users.
+#[derive(Deserialize)]
+struct ThirdPartyInviteExchange {
+    room_id: RoomId,
+    sender: UserId,
+    state_key: UserId,
+    content: member::MemberEventContent,
+}
+
+#[cfg_attr(
+    feature = "conduit_bin",
+    put(
+        "/_matrix/federation/v1/exchange_third_party_invite/<room_id>",
+        data = "<body>"
+    )
+)]
+pub async fn exchange_third_party_invite_route(
+    db: State<'_, Database<'_>>,
+    x_matrix: XMatrix,
+    room_id: String,
+    body: String,
+) -> Result<Json<String>> {
+    let content = serde_json::from_str::<serde_json::Value>(&body)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid JSON body."))?;
+    verify_server_request(&db, &x_matrix, Some(&content))
+        .await
+        .map_err(|_| Error::BadRequest(ErrorKind::Forbidden, "Invalid X-Matrix signature."))?;
+
+    let room_id = RoomId::try_from(room_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid room id."))?;
+    let event = serde_json::from_value::<ThirdPartyInviteExchange>(content)
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Invalid third party invite event."))?;
+
+    if event.room_id != room_id {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Room id of the event does not match the path.",
+        ));
+    }
+
+    let third_party_invite = event.content.third_party_invite.ok_or(Error::BadRequest(
+        ErrorKind::InvalidParam,
+        "Event has no signed third party invite.",
+    ))?;
+
+    if third_party_invite.signed.mxid != event.state_key {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Third party invite was signed for another user.",
+        ));
+    }
+
+    // Checks that the sender is one of our users and allowed to invite
+    db.rooms.exchange_third_party_invite(
+        &room_id,
+        &event.sender,
+        serde_json::to_value(third_party_invite.signed)
+            .expect("SignedContent::to_value always works"),
+        &db.globals,
+        &db.account_data,
+    )?;
+
+    Ok(Json("{}".to_owned()))
 }
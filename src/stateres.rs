@@ -1,59 +1,360 @@
-use std::collections::HashMap;
+use crate::{PduEvent, Result};
+use ruma::{
+    events::{room::member::MembershipState, EventType},
+    EventId,
+};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+};
 
-fn stateres(state_a: HashMap<StateTuple, PduEvent>, state_b: HashMap<StateTuple, PduEvent>) {
-    let mut unconflicted = todo!("state at fork event");
+/// Maps (event type, state key) to something, usually the id of the state event.
+pub type StateMap<T> = HashMap<(EventType, String), T>;
 
-    let mut conflicted: HashMap<StateTuple, PduEvent> = state_a
+/// Resolves the state of multiple forks of a room into one state (state resolution v2).
+///
+/// - `get_pdu` loads events from the database
+/// - `auth_check` returns true if the event is allowed in the given (partially resolved) state
+pub fn resolve<F, A>(
+    state_sets: &[StateMap<EventId>],
+    get_pdu: F,
+    auth_check: A,
+) -> Result<StateMap<EventId>>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+    A: Fn(&PduEvent, &StateMap<EventId>) -> Result<bool>,
+{
+    if state_sets.len() == 1 {
+        return Ok(state_sets[0].clone());
+    }
+
+    // 1. Everything all forks agree on is unconflicted
+    let mut unconflicted = StateMap::new();
+    let mut conflicted = HashSet::new();
+
+    let keys = state_sets
         .iter()
-        .filter(|(key_a, value_a)| match state_b.remove(key_a) {
-            Some(value_b) if value_a == value_b => unconflicted.insert(key_a, value_a),
-            _ => false,
+        .flat_map(|state| state.keys())
+        .collect::<HashSet<_>>();
+
+    for key in keys {
+        let values = state_sets
+            .iter()
+            .map(|state| state.get(key))
+            .collect::<HashSet<_>>();
+
+        if values.len() == 1 {
+            if let Some(Some(event_id)) = values.into_iter().next() {
+                unconflicted.insert(key.clone(), event_id.clone());
+            }
+        } else {
+            conflicted.extend(values.into_iter().flatten().cloned());
+        }
+    }
+
+    if conflicted.is_empty() {
+        return Ok(unconflicted);
+    }
+
+    // 2. The full conflicted set also contains the auth chain difference
+    let auth_chains = state_sets
+        .iter()
+        .map(|state| auth_chain(state.values(), &get_pdu))
+        .collect::<Result<Vec<_>>>()?;
+
+    let union = auth_chains
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    conflicted.extend(
+        union
+            .into_iter()
+            .filter(|event_id| !auth_chains.iter().all(|chain| chain.contains(event_id))),
+    );
+
+    let mut events = HashMap::new();
+    for event_id in conflicted {
+        // Events we don't have can't be part of the resolved state
+        if let Some(pdu) = get_pdu(&event_id)? {
+            events.insert(event_id, pdu);
+        }
+    }
+
+    // 3. Power events are sorted by their auth dependencies and applied first
+    let (control_events, other_events): (Vec<_>, Vec<_>) = events
+        .keys()
+        .cloned()
+        .partition(|event_id| is_power_event(&events[event_id]));
+
+    let sorted_control_events = reverse_topological_power_sort(&control_events, &events, &get_pdu)?;
+
+    let mut resolved = iterative_auth_checks(
+        &sorted_control_events,
+        unconflicted.clone(),
+        &events,
+        &auth_check,
+    )?;
+
+    // 4. All other events are sorted by the mainline of the resolved power levels
+    let mainline = mainline(
+        resolved.get(&(EventType::RoomPowerLevels, "".to_owned())),
+        &get_pdu,
+    )?;
+
+    let mut sorted_other_events = other_events
+        .into_iter()
+        .map(|event_id| {
+            let pdu = &events[&event_id];
+            Ok((
+                mainline_position(pdu, &mainline, &get_pdu)?,
+                u64::from(pdu.origin_server_ts),
+                event_id.to_string(),
+                event_id,
+            ))
         })
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
+    sorted_other_events.sort_by(|a, b| (a.0, a.1, &a.2).cmp(&(b.0, b.1, &b.2)));
 
-    // We removed unconflicted from state_b, now we can easily insert all events that are only in fork b
-    conflicted.extend(state_b);
+    let sorted_other_events = sorted_other_events
+        .into_iter()
+        .map(|(_, _, _, event_id)| event_id)
+        .collect::<Vec<_>>();
 
-    let partial_state = unconflicted.clone();
+    resolved = iterative_auth_checks(&sorted_other_events, resolved, &events, &auth_check)?;
 
-    let full_conflicted = conflicted.clone(); // TODO: auth events
+    // 5. Unconflicted state always wins
+    resolved.extend(unconflicted);
 
-    let output_rev = Vec::new();
-    let event_map = HashMap::new();
-    let incoming_edges = HashMap::new();
+    Ok(resolved)
+}
+
+/// Returns all events that are (recursively) referenced in the auth_events of these events.
+fn auth_chain<'a, F>(
+    event_ids: impl Iterator<Item = &'a EventId>,
+    get_pdu: &F,
+) -> Result<HashSet<EventId>>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+{
+    let mut chain = HashSet::new();
+    let mut todo = event_ids.cloned().collect::<Vec<_>>();
 
-    for event in full_conflicted {
-        event_map.insert(event.event_id, event);
-        incoming_edges.insert(event.event_id, 0);
+    while let Some(event_id) = todo.pop() {
+        if let Some(pdu) = get_pdu(&event_id)? {
+            for auth_event in pdu.auth_events {
+                if chain.insert(auth_event.clone()) {
+                    todo.push(auth_event);
+                }
+            }
+        }
     }
 
-    for e in conflicted_control_events {
-        for a in e.auth_events {
-            incoming_edges[a.event_id] += 1;
+    Ok(chain)
+}
+
+/// Power events can change who is allowed to send other events.
+fn is_power_event(pdu: &PduEvent) -> bool {
+    match pdu.kind {
+        EventType::RoomCreate | EventType::RoomPowerLevels | EventType::RoomJoinRules => {
+            pdu.state_key.as_deref() == Some("")
+        }
+        EventType::RoomMember => {
+            pdu.state_key.as_deref() != Some(pdu.sender.as_str())
+                && matches!(
+                    pdu.content
+                        .get("membership")
+                        .and_then(|membership| serde_json::from_value(membership.clone()).ok()),
+                    Some(MembershipState::Leave) | Some(MembershipState::Ban)
+                )
         }
+        _ => false,
     }
+}
+
+/// Sorts events so that auth events come before the events they authorize. Ties are broken by
+/// the power level of the sender, the timestamp and the event id.
+fn reverse_topological_power_sort<F>(
+    event_ids: &[EventId],
+    events: &HashMap<EventId, PduEvent>,
+    get_pdu: &F,
+) -> Result<Vec<EventId>>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+{
+    let set = event_ids.iter().collect::<HashSet<_>>();
 
-    while incoming_edges.len() > 0 {
-        let mut count_0 = incoming_edges
+    let mut dependencies = HashMap::new();
+    let mut dependents = HashMap::<&EventId, Vec<&EventId>>::new();
+    for event_id in event_ids {
+        let auth_events = events[event_id]
+            .auth_events
             .iter()
-            .filter(|(_, c)| c == 0)
+            .filter(|auth_event| set.contains(auth_event))
             .collect::<Vec<_>>();
 
-        count_0.sort_by(|(x, _), (y, _)| {
-            x.power_level
-                .cmp(&a.power_level)
-                .then_with(|| x.origin_server.ts.cmp(&y.origin_server_ts))
-                .then_with(|| x.event_id.cmp(&y.event_id))
-        });
+        dependencies.insert(event_id, auth_events.len());
+        for auth_event in auth_events {
+            dependents.entry(auth_event).or_default().push(event_id);
+        }
+    }
 
-        for (id, count) in count_0 {
-            output_rev.push(event_map[id]);
+    let sort_key = |event_id: &EventId| {
+        let pdu = &events[event_id];
+        Ok::<_, crate::Error>((
+            Reverse(sender_power_level(pdu, get_pdu)?),
+            u64::from(pdu.origin_server_ts),
+            event_id.to_string(),
+        ))
+    };
 
-            for auth_event in event_map[id].auth_events {
-                incoming_edges[auth_event.event_id] -= 1;
+    let mut ready = BTreeSet::new();
+    let mut keys = HashMap::new();
+    for event_id in event_ids {
+        let key = sort_key(event_id)?;
+        keys.insert(key.clone(), event_id);
+        if dependencies[event_id] == 0 {
+            ready.insert(key);
+        }
+    }
+
+    let mut sorted = Vec::new();
+    while let Some(key) = ready.iter().next().cloned() {
+        ready.remove(&key);
+        let event_id = keys[&key];
+        sorted.push(event_id.clone());
+
+        for dependent in dependents.get(event_id).into_iter().flatten() {
+            let count = dependencies
+                .get_mut(dependent)
+                .expect("all dependents are in the set");
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(sort_key(dependent)?);
+            }
+        }
+    }
+
+    Ok(sorted)
+}
+
+/// Returns the power level the sender had when sending this event.
+fn sender_power_level<F>(pdu: &PduEvent, get_pdu: &F) -> Result<i64>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+{
+    let mut create_event = None;
+
+    for auth_event in &pdu.auth_events {
+        if let Some(auth_pdu) = get_pdu(auth_event)? {
+            match auth_pdu.kind {
+                EventType::RoomPowerLevels => {
+                    return Ok(auth_pdu
+                        .content
+                        .get("users")
+                        .and_then(|users| users.get(pdu.sender.as_str()))
+                        .or_else(|| auth_pdu.content.get("users_default"))
+                        .and_then(int_value)
+                        .unwrap_or(0));
+                }
+                EventType::RoomCreate => create_event = Some(auth_pdu),
+                _ => {}
+            }
+        }
+    }
+
+    // Without power levels, the creator has all the power
+    Ok(match create_event {
+        Some(create_event) if create_event.sender == pdu.sender => 100,
+        _ => 0,
+    })
+}
+
+/// Power levels may be sent as strings in old rooms.
+pub fn int_value(value: &serde_json::Value) -> Option<i64> {
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+}
+
+/// Applies the events one after another, skipping events that are not allowed.
+fn iterative_auth_checks<A>(
+    event_ids: &[EventId],
+    mut state: StateMap<EventId>,
+    events: &HashMap<EventId, PduEvent>,
+    auth_check: &A,
+) -> Result<StateMap<EventId>>
+where
+    A: Fn(&PduEvent, &StateMap<EventId>) -> Result<bool>,
+{
+    for event_id in event_ids {
+        let pdu = &events[event_id];
+        if let Some(state_key) = &pdu.state_key {
+            if auth_check(pdu, &state)? {
+                state.insert((pdu.kind.clone(), state_key.clone()), event_id.clone());
+            }
+        }
+    }
+
+    Ok(state)
+}
+
+/// Returns the chain of power level events that led to this power level event, oldest first.
+fn mainline<F>(power_levels: Option<&EventId>, get_pdu: &F) -> Result<Vec<EventId>>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+{
+    let mut mainline = Vec::new();
+    let mut current = power_levels.cloned();
+
+    while let Some(event_id) = current {
+        current = None;
+        if let Some(pdu) = get_pdu(&event_id)? {
+            for auth_event in &pdu.auth_events {
+                if get_pdu(auth_event)?.map_or(false, |p| p.kind == EventType::RoomPowerLevels) {
+                    current = Some(auth_event.clone());
+                }
+            }
+        }
+        mainline.push(event_id);
+    }
+
+    mainline.reverse();
+    Ok(mainline)
+}
+
+/// Returns the position of the closest mainline event in the auth chain of this event. Events
+/// that are not connected to the mainline come first.
+fn mainline_position<F>(pdu: &PduEvent, mainline: &[EventId], get_pdu: &F) -> Result<usize>
+where
+    F: Fn(&EventId) -> Result<Option<PduEvent>>,
+{
+    let mut current = pdu.clone();
+    let mut visited = HashSet::new();
+
+    loop {
+        if let Some(position) = mainline.iter().position(|e| e == &current.event_id) {
+            return Ok(position + 1);
+        }
+
+        if !visited.insert(current.event_id.clone()) {
+            return Ok(0);
+        }
+
+        let mut next = None;
+        for auth_event in &current.auth_events {
+            if let Some(auth_pdu) = get_pdu(auth_event)? {
+                if auth_pdu.kind == EventType::RoomPowerLevels {
+                    next = Some(auth_pdu);
+                    break;
+                }
             }
+        }
 
-            incoming_edges.remove(id);
+        match next {
+            Some(next) => current = next,
+            None => return Ok(0),
         }
     }
 }